@@ -0,0 +1,286 @@
+//! Async variant of the ISP commands, enabled by the `async` feature
+//!
+//! [`AsyncDevice`] runs each command of a blocking [`IspCommand`] device as one
+//! task on tokio's blocking thread pool, so many devices can be driven
+//! concurrently from one runtime. Progress events are forwarded to the calling
+//! task.
+//!
+//! Dropping a command, e.g. when a `tokio::time::timeout` expires, aborts the
+//! transfer at the next packet the device answers: it's notified with an
+//! abort packet and released for the next command. A device that stopped
+//! answering keeps the command running until the transport gives up, for
+//! [`HpmDevice`] after [`READ_TIMEOUT`](crate::hid::READ_TIMEOUT) with
+//! [`Error::TransferError`], and later commands wait for it.
+
+use std::ops::ControlFlow;
+use std::sync::mpsc as std_mpsc;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use tokio::sync::mpsc;
+use tokio::task;
+
+use crate::hid::HpmDevice;
+use crate::isp_command::{Error, IspCommand, MemoryId, RuntimeEnvironment};
+use crate::progress::{NoProgress, Progress, ProgressEvent};
+
+/// Forwards the progress of a blocking command to the calling task and waits
+/// for its answer, breaks once the caller dropped the command
+struct Forward {
+    events: mpsc::UnboundedSender<ProgressEvent>,
+    answers: std_mpsc::Receiver<ControlFlow<()>>,
+}
+
+impl Progress for Forward {
+    fn update(&mut self, event: &ProgressEvent) -> ControlFlow<()> {
+        if self.events.send(*event).is_err() {
+            return ControlFlow::Break(());
+        }
+        self.answers.recv().unwrap_or(ControlFlow::Break(()))
+    }
+}
+
+/// Blocking device driven from tokio's blocking thread pool
+pub struct AsyncDevice<D> {
+    device: Arc<Mutex<D>>,
+}
+
+/// [`HpmDevice`] driven from tokio's blocking thread pool, take its
+/// [`family`](HpmDevice::family) before wrapping it
+pub type AsyncHpmDevice = AsyncDevice<HpmDevice>;
+
+impl<D> Clone for AsyncDevice<D> {
+    fn clone(&self) -> Self {
+        Self {
+            device: self.device.clone(),
+        }
+    }
+}
+
+impl<D> From<D> for AsyncDevice<D>
+where
+    D: IspCommand + Send + 'static,
+{
+    fn from(device: D) -> Self {
+        Self::new(device)
+    }
+}
+
+impl<D> AsyncDevice<D>
+where
+    D: IspCommand + Send + 'static,
+{
+    pub fn new(device: D) -> Self {
+        Self {
+            device: Arc::new(Mutex::new(device)),
+        }
+    }
+
+    /// Run `command` on the blocking thread pool, forwarding its progress
+    async fn run<T, P, F>(&self, mut progress: P, command: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        P: Progress,
+        F: FnOnce(&D, Forward) -> Result<T, Error> + Send + 'static,
+    {
+        let (events, mut receiver) = mpsc::unbounded_channel();
+        let (answer, answers) = std_mpsc::channel();
+        let forward = Forward { events, answers };
+        let device = self.device.clone();
+        let task = task::spawn_blocking(move || {
+            // The device keeps no state a panicking command could leave half
+            // updated, so a poisoned lock is taken over
+            let device = device.lock().unwrap_or_else(PoisonError::into_inner);
+            command(&device, forward)
+        });
+
+        while let Some(event) = receiver.recv().await {
+            // Fails only once the command is done
+            let _ = answer.send(progress.update(&event));
+        }
+        task.await.map_err(|_| Error::TransferError)?
+    }
+
+    /// See [`IspCommand::query_runtime_environment`]
+    pub async fn query_runtime_environment(
+        &self,
+        id: RuntimeEnvironment,
+    ) -> Result<Vec<u32>, Error> {
+        self.run(NoProgress, move |device, _| {
+            device.query_runtime_environment(id)
+        })
+        .await
+    }
+
+    /// See [`IspCommand::configure_memory`]
+    pub async fn configure_memory(&self, memory_id: MemoryId, cfg_addr: u32) -> Result<(), Error> {
+        self.run(NoProgress, move |device, _| {
+            device.configure_memory(memory_id, cfg_addr)
+        })
+        .await
+    }
+
+    /// See [`IspCommand::write_memory`]
+    pub async fn write_memory<P>(
+        &self,
+        memory_id: MemoryId,
+        offset: u32,
+        data: &[u8],
+        progress: P,
+    ) -> Result<(), Error>
+    where
+        P: Progress,
+    {
+        let data = data.to_vec();
+        self.run(progress, move |device, forward| {
+            device.write_memory(memory_id, offset, &data, forward)
+        })
+        .await
+    }
+
+    /// See [`IspCommand::read_memory`]
+    pub async fn read_memory<P>(
+        &self,
+        memory_id: MemoryId,
+        offset: u32,
+        data: &mut [u8],
+        progress: P,
+    ) -> Result<(), Error>
+    where
+        P: Progress,
+    {
+        let length = data.len();
+        let buffer = self
+            .run(progress, move |device, forward| {
+                let mut buffer = vec![0u8; length];
+                device.read_memory(memory_id, offset, &mut buffer, forward)?;
+                Ok(buffer)
+            })
+            .await?;
+        data.copy_from_slice(&buffer);
+        Ok(())
+    }
+
+    /// See [`IspCommand::verify_memory`]
+    pub async fn verify_memory<P>(
+        &self,
+        memory_id: MemoryId,
+        offset: u32,
+        data: &[u8],
+        progress: P,
+    ) -> Result<(), Error>
+    where
+        P: Progress,
+    {
+        let data = data.to_vec();
+        self.run(progress, move |device, forward| {
+            device.verify_memory(memory_id, offset, &data, forward)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use zerocopy::FromZeroes;
+
+    use super::*;
+    use crate::isp_command::{Interface, Packet};
+
+    /// Answers everything with success, the first write takes a while
+    #[derive(Default)]
+    struct MockDevice {
+        writes: AtomicUsize,
+        aborted: AtomicBool,
+    }
+
+    impl Interface for MockDevice {
+        fn write(&self, _: &Packet, _: u16) -> Result<(), Error> {
+            if self.writes.fetch_add(1, Ordering::SeqCst) == 0 {
+                thread::sleep(Duration::from_millis(20));
+            }
+            Ok(())
+        }
+
+        fn read(&self, packet: &mut Packet) -> Result<u16, Error> {
+            *packet = Packet::new_zeroed();
+            Ok(mem::size_of::<u32>() as u16)
+        }
+
+        fn abort(&self) -> Result<(), Error> {
+            self.aborted.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    impl IspCommand for MockDevice {}
+
+    #[tokio::test]
+    async fn writes_memory() {
+        let device = AsyncDevice::new(MockDevice::default());
+        let mut events = 0;
+
+        device
+            .write_memory(MemoryId::ILM, 0, &[0xA5; 1200], |_: &ProgressEvent| {
+                events += 1;
+                ControlFlow::Continue(())
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(events, 3);
+        let device = device.device.lock().unwrap();
+        assert_eq!(device.writes.load(Ordering::SeqCst), 3);
+        assert!(!device.aborted.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn aborts_write_on_break() {
+        let device = AsyncDevice::new(MockDevice::default());
+
+        let result = device
+            .write_memory(MemoryId::ILM, 0, &[0xA5; 1200], |_: &ProgressEvent| {
+                ControlFlow::Break(())
+            })
+            .await;
+
+        assert!(matches!(result, Err(Error::Aborted)));
+        assert!(device.device.lock().unwrap().aborted.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn aborts_write_when_dropped() {
+        let device = AsyncDevice::new(MockDevice::default());
+
+        tokio::select! {
+            biased;
+            _ = device.write_memory(MemoryId::ILM, 0, &[0xA5; 1200], NoProgress) => {
+                panic!("write finished before it was dropped")
+            }
+            _ = async {} => {}
+        }
+        // Waits for the device, released by the aborted write
+        device
+            .configure_memory(MemoryId::XPI0, 0x200)
+            .await
+            .unwrap();
+
+        let device = device.device.lock().unwrap();
+        assert!(device.aborted.load(Ordering::SeqCst));
+        assert_eq!(device.writes.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn commands_are_send() {
+        let device = AsyncDevice::new(MockDevice::default());
+
+        tokio::spawn(async move { device.configure_memory(MemoryId::XPI0, 0x200).await })
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
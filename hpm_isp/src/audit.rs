@@ -0,0 +1,336 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::hex::to_hex;
+use crate::output::error_code;
+
+/// Append-only log of flash operations, CSV for `.csv` files and JSON lines
+/// otherwise
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LogFormat {
+    Csv,
+    JsonLines,
+}
+
+impl LogFormat {
+    fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("csv") => LogFormat::Csv,
+            _ => LogFormat::JsonLines,
+        }
+    }
+}
+
+/// One flash operation in the audit log
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct AuditRecord {
+    pub(crate) timestamp: String,
+    pub(crate) host: String,
+    pub(crate) command: String,
+    pub(crate) family: Option<String>,
+    pub(crate) device_serial: Option<String>,
+    pub(crate) memory: String,
+    pub(crate) offset: u32,
+    pub(crate) bytes: usize,
+    /// SHA-256 of the data written or read
+    pub(crate) sha256: Option<String>,
+    /// SHA-256 of the input image when serial patches change what is written
+    #[serde(default)]
+    pub(crate) image_sha256: Option<String>,
+    pub(crate) verified: bool,
+    pub(crate) serial: Option<u64>,
+    /// `ok` or `error`
+    pub(crate) result: String,
+    pub(crate) error_code: Option<String>,
+    pub(crate) error: Option<String>,
+}
+
+impl AuditRecord {
+    pub(crate) fn new(command: &str, memory: String, offset: u32) -> Self {
+        Self {
+            timestamp: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            host: hostname::get()
+                .map(|host| host.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            command: command.to_string(),
+            memory,
+            offset,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn set_error(&mut self, error: &(dyn Error + 'static)) {
+        self.result = "error".to_string();
+        self.error_code = Some(error_code(error).to_string());
+        self.error = Some(error.to_string());
+    }
+
+    /// Append the record to the log file
+    pub(crate) fn append_to(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let new = fs::metadata(path).map_or(true, |metadata| metadata.len() == 0);
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        match LogFormat::from_path(path) {
+            LogFormat::Csv => {
+                let mut writer = csv::WriterBuilder::new().has_headers(new).from_writer(file);
+                writer.serialize(self)?;
+                writer.flush()?;
+            }
+            LogFormat::JsonLines => {
+                writeln!(file, "{}", serde_json::to_string(self)?)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn read_records(path: &Path) -> Result<Vec<AuditRecord>, Box<dyn Error>> {
+    match LogFormat::from_path(path) {
+        LogFormat::Csv => Ok(csv::Reader::from_path(path)?
+            .deserialize()
+            .collect::<Result<_, _>>()?),
+        LogFormat::JsonLines => {
+            let mut records = Vec::new();
+            for line in BufReader::new(fs::File::open(path)?).lines() {
+                let line = line?;
+                if !line.trim().is_empty() {
+                    records.push(serde_json::from_str(&line)?);
+                }
+            }
+            Ok(records)
+        }
+    }
+}
+
+/// Summarise yield and failures of an audit log
+pub(crate) fn report(path: &Path) -> Result<ReportResult, Box<dyn Error>> {
+    let records = read_records(path)?;
+
+    let mut failures = BTreeMap::<String, usize>::new();
+    let mut families = BTreeMap::<String, FamilySummary>::new();
+    let mut passed = 0;
+    for record in &records {
+        let ok = record.result == "ok";
+        if ok {
+            passed += 1;
+        } else {
+            let code = record.error_code.as_deref().unwrap_or("error");
+            *failures.entry(code.to_string()).or_default() += 1;
+        }
+
+        let family = record.family.as_deref().unwrap_or("unknown");
+        let summary = families
+            .entry(family.to_string())
+            .or_insert_with(|| FamilySummary {
+                family: family.to_string(),
+                total: 0,
+                passed: 0,
+            });
+        summary.total += 1;
+        summary.passed += ok as usize;
+    }
+
+    let mut failures: Vec<_> = failures
+        .into_iter()
+        .map(|(code, count)| FailureSummary { code, count })
+        .collect();
+    failures.sort_by_key(|failure| Reverse(failure.count));
+
+    Ok(ReportResult {
+        command: "report",
+        path: path.to_path_buf(),
+        total: records.len(),
+        passed,
+        failed: records.len() - passed,
+        yield_percent: if records.is_empty() {
+            0.0
+        } else {
+            passed as f64 * 100.0 / records.len() as f64
+        },
+        first: records.first().map(|record| record.timestamp.clone()),
+        last: records.last().map(|record| record.timestamp.clone()),
+        families: families.into_values().collect(),
+        failures,
+    })
+}
+
+#[derive(Serialize)]
+pub(crate) struct FamilySummary {
+    family: String,
+    total: usize,
+    passed: usize,
+}
+
+#[derive(Serialize)]
+pub(crate) struct FailureSummary {
+    code: String,
+    count: usize,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ReportResult {
+    command: &'static str,
+    path: PathBuf,
+    total: usize,
+    passed: usize,
+    failed: usize,
+    yield_percent: f64,
+    first: Option<String>,
+    last: Option<String>,
+    families: Vec<FamilySummary>,
+    failures: Vec<FailureSummary>,
+}
+
+impl fmt::Display for ReportResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} operations, {} passed, {} failed, yield {:.1}%",
+            self.total, self.passed, self.failed, self.yield_percent
+        )?;
+        if let (Some(first), Some(last)) = (&self.first, &self.last) {
+            write!(f, "\nFrom {first} to {last}")?;
+        }
+        for family in &self.families {
+            write!(
+                f,
+                "\n{}: {}/{} passed",
+                family.family, family.passed, family.total
+            )?;
+        }
+        if !self.failures.is_empty() {
+            write!(f, "\nFailures:")?;
+            for failure in &self.failures {
+                write!(f, "\n  {}: {}", failure.code, failure.count)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reader computing the SHA-256 of the data read through it
+pub(crate) struct HashReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R> HashReader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    pub(crate) fn finish(self) -> String {
+        to_hex(&self.hasher.finalize())
+    }
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Writer computing the SHA-256 of the data written through it
+pub(crate) struct HashWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W> HashWriter<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    pub(crate) fn finish(self) -> (W, String) {
+        (self.inner, to_hex(&self.hasher.finalize()))
+    }
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::output::CodedError;
+
+    fn sha256_hex(data: &[u8]) -> String {
+        to_hex(&Sha256::digest(data))
+    }
+
+    fn record(ok: bool, family: &str) -> AuditRecord {
+        let mut record = AuditRecord::new("flash write", "XPI0".to_string(), 0x400);
+        record.family = Some(family.to_string());
+        record.bytes = 16;
+        record.sha256 = Some(sha256_hex(b"data"));
+        if ok {
+            record.result = "ok".to_string();
+        } else {
+            record.set_error(&CodedError::new("device_not_found", "can't open"));
+        }
+        record
+    }
+
+    #[test]
+    fn reports_yield_from_logs() {
+        let dir = tempdir().unwrap();
+        for ext in ["csv", "jsonl"] {
+            let path = dir.path().join(format!("audit.{ext}"));
+            record(true, "HPM6300").append_to(&path).unwrap();
+            record(false, "HPM6300").append_to(&path).unwrap();
+            record(true, "HPM5300").append_to(&path).unwrap();
+            record(true, "HPM5300").append_to(&path).unwrap();
+
+            let report = report(&path).unwrap();
+
+            assert_eq!(report.total, 4);
+            assert_eq!(report.passed, 3);
+            assert_eq!(report.failed, 1);
+            assert_eq!(report.yield_percent, 75.0);
+            assert_eq!(report.failures[0].code, "device_not_found");
+            assert_eq!(report.families[0].family, "HPM5300");
+            assert_eq!(report.families[0].passed, 2);
+            assert_eq!(report.families[1].total, 2);
+        }
+    }
+
+    #[test]
+    fn hashes_streamed_data() {
+        let mut reader = HashReader::new(&b"data"[..]);
+        io::copy(&mut reader, &mut io::sink()).unwrap();
+        assert_eq!(reader.finish(), sha256_hex(b"data"));
+
+        let mut writer = HashWriter::new(Vec::new());
+        writer.write_all(b"data").unwrap();
+        let (data, hash) = writer.finish();
+        assert_eq!(data, b"data");
+        assert_eq!(hash, sha256_hex(b"data"));
+    }
+}
@@ -0,0 +1,207 @@
+//! Boot image layout in XPI NOR flash
+//!
+//! A boot image holds the XPI NOR configuration option at 0x400, the boot
+//! header at 0x1000 and the application, by default at 0x3000. The boot header
+//! and firmware info table are laid out as in the HPM SDK and `hpm-rt`, and
+//! parsed to check images built by them.
+
+use std::mem;
+
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+/// Offset of the XPI NOR configuration option in flash
+pub const NOR_CFG_OPTION_OFFSET: usize = 0x400;
+/// Offset of the boot header in flash
+pub const BOOT_HEADER_OFFSET: usize = 0x1000;
+/// Default offset of the application in flash
+pub const APP_OFFSET: usize = 0x3000;
+
+/// Tag in the upper half of the first word of the XPI NOR configuration option
+pub const NOR_CFG_OPTION_TAG: u16 = 0xFCF9;
+pub const BOOT_HEADER_TAG: u8 = 0xBF;
+pub const BOOT_HEADER_VERSION: u8 = 0x10;
+
+/// Firmware flags: hash type in bits 11:8
+const FW_FLAGS_HASH_SHIFT: u32 = 8;
+const FW_FLAGS_HASH_MASK: u32 = 0xF << FW_FLAGS_HASH_SHIFT;
+const HASH_TYPE_SHA256: u32 = 1;
+
+#[derive(Debug, Error)]
+pub enum ImageError {
+    #[error("no boot header at {0:#x}")]
+    MissingBootHeader(usize),
+    #[error("boot header is truncated")]
+    TruncatedBootHeader,
+    #[error("firmware {0} lies outside of the image")]
+    TruncatedFirmware(usize),
+    #[error("hash of firmware {0} doesn't match")]
+    HashMismatch(usize),
+}
+
+#[derive(AsBytes, FromZeroes, FromBytes, Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct BootHeader {
+    pub tag: u8,
+    pub version: u8,
+    /// Length of the header including firmware info tables and signature block
+    pub length: u16,
+    pub flags: u32,
+    pub sw_version: u16,
+    pub fuse_version: u8,
+    pub fw_count: u8,
+    pub dc_block_offset: u16,
+    pub sig_block_offset: u16,
+}
+
+#[derive(AsBytes, FromZeroes, FromBytes, Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct FwInfo {
+    /// Offset of the firmware from the start of the boot header
+    pub offset: u32,
+    pub size: u32,
+    pub flags: u32,
+    reserved0: u32,
+    pub load_addr: u32,
+    reserved1: u32,
+    pub entry_point: u32,
+    reserved2: u32,
+    pub hash: [u8; 64],
+    pub iv: [u8; 32],
+}
+
+impl FwInfo {
+    fn has_sha256(&self) -> bool {
+        self.flags & FW_FLAGS_HASH_MASK == HASH_TYPE_SHA256 << FW_FLAGS_HASH_SHIFT
+    }
+}
+
+/// Boot header and firmware info tables parsed from a flash image
+#[derive(Debug, Clone)]
+pub struct ParsedBootImage {
+    pub header: BootHeader,
+    pub fw_info: Vec<FwInfo>,
+}
+
+impl ParsedBootImage {
+    /// Parse the boot header of a flash image starting at XPI offset 0
+    pub fn parse(image: &[u8]) -> Result<Self, ImageError> {
+        let header_bytes = image
+            .get(BOOT_HEADER_OFFSET..)
+            .ok_or(ImageError::MissingBootHeader(BOOT_HEADER_OFFSET))?;
+        let header = BootHeader::read_from_prefix(header_bytes)
+            .ok_or(ImageError::MissingBootHeader(BOOT_HEADER_OFFSET))?;
+        if header.tag != BOOT_HEADER_TAG {
+            return Err(ImageError::MissingBootHeader(BOOT_HEADER_OFFSET));
+        }
+
+        let fw_info = (0..header.fw_count as usize)
+            .map(|i| {
+                let offset = mem::size_of::<BootHeader>() + i * mem::size_of::<FwInfo>();
+                header_bytes
+                    .get(offset..)
+                    .and_then(FwInfo::read_from_prefix)
+                    .ok_or(ImageError::TruncatedBootHeader)
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { header, fw_info })
+    }
+
+    /// Data of firmware `index` in the flash image
+    pub fn firmware<'a>(&self, image: &'a [u8], index: usize) -> Result<&'a [u8], ImageError> {
+        let fw_info = &self.fw_info[index];
+        let start = BOOT_HEADER_OFFSET + fw_info.offset as usize;
+        image
+            .get(start..start + fw_info.size as usize)
+            .ok_or(ImageError::TruncatedFirmware(index))
+    }
+
+    /// Check the SHA-256 hash of every firmware which carries one
+    pub fn verify_hashes(&self, image: &[u8]) -> Result<(), ImageError> {
+        for (index, fw_info) in self.fw_info.iter().enumerate() {
+            if fw_info.has_sha256() {
+                self.verify_firmware(index, self.firmware(image, index)?)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the SHA-256 hash of firmware `index` against its data, if it
+    /// carries one
+    pub fn verify_firmware(&self, index: usize, firmware: &[u8]) -> Result<(), ImageError> {
+        let fw_info = &self.fw_info[index];
+        if fw_info.has_sha256() && fw_info.hash[..32] != Sha256::digest(firmware)[..] {
+            return Err(ImageError::HashMismatch(index));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Boot image of `app` at 0x3000 with a SHA-256 hash, as the HPM SDK
+    /// builds it
+    fn boot_image(app: &[u8]) -> Vec<u8> {
+        let header = BootHeader {
+            tag: BOOT_HEADER_TAG,
+            version: BOOT_HEADER_VERSION,
+            length: (mem::size_of::<BootHeader>() + mem::size_of::<FwInfo>()) as u16,
+            flags: 0,
+            sw_version: 0,
+            fuse_version: 0,
+            fw_count: 1,
+            dc_block_offset: 0,
+            sig_block_offset: 0,
+        };
+        let mut fw_info = FwInfo::new_zeroed();
+        fw_info.offset = (APP_OFFSET - BOOT_HEADER_OFFSET) as u32;
+        fw_info.size = app.len() as u32;
+        fw_info.flags = HASH_TYPE_SHA256 << FW_FLAGS_HASH_SHIFT;
+        fw_info.load_addr = 0x8000_3000;
+        fw_info.entry_point = 0x8000_3000;
+        fw_info.hash[..32].copy_from_slice(&Sha256::digest(app));
+
+        let mut image = vec![0xFF; APP_OFFSET + app.len()];
+        let fw_info_offset = BOOT_HEADER_OFFSET + mem::size_of::<BootHeader>();
+        image[BOOT_HEADER_OFFSET..fw_info_offset].copy_from_slice(header.as_bytes());
+        image[fw_info_offset..fw_info_offset + mem::size_of::<FwInfo>()]
+            .copy_from_slice(fw_info.as_bytes());
+        image[APP_OFFSET..].copy_from_slice(app);
+        image
+    }
+
+    #[test]
+    fn parses_boot_image() {
+        let app = [0x13u8; 100];
+        let image = boot_image(&app);
+
+        let parsed = ParsedBootImage::parse(&image).unwrap();
+        let fw_info = parsed.fw_info[0];
+        assert_eq!({ fw_info.offset }, 0x2000);
+        assert_eq!({ fw_info.load_addr }, 0x8000_3000);
+        assert_eq!(parsed.firmware(&image, 0).unwrap(), &app);
+        parsed.verify_hashes(&image).unwrap();
+    }
+
+    #[test]
+    fn detects_modified_firmware() {
+        let mut image = boot_image(&[0x13; 100]);
+        image[APP_OFFSET + 10] ^= 1;
+
+        let parsed = ParsedBootImage::parse(&image).unwrap();
+        assert!(matches!(
+            parsed.verify_hashes(&image),
+            Err(ImageError::HashMismatch(0))
+        ));
+    }
+
+    #[test]
+    fn rejects_image_without_boot_header() {
+        assert!(ParsedBootImage::parse(&[0xFF; 0x2000]).is_err());
+        assert!(ParsedBootImage::parse(&[0xFF; 0x100]).is_err());
+    }
+}
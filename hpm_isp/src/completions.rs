@@ -0,0 +1,157 @@
+use std::fs;
+use std::io;
+
+use clap::{ArgEnum, Command};
+use clap_complete::{generate, Shell};
+
+use hpm_isp::hid::HpmDevice;
+
+const BIN_NAME: &str = "hpm_isp";
+
+/// Values completed at runtime by calling `hpm_isp complete-values <VALUES>`
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum DynamicValues {
+    /// Serial numbers of the attached devices
+    Devices,
+    /// Memory config files in the working directory
+    Configs,
+}
+
+pub(crate) fn dynamic_values(values: DynamicValues) -> Vec<String> {
+    match values {
+        DynamicValues::Devices => HpmDevice::list()
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|device| device.serial_number)
+            .collect(),
+        DynamicValues::Configs => {
+            let mut configs: Vec<_> = fs::read_dir(".")
+                .into_iter()
+                .flatten()
+                .flatten()
+                .map(|entry| entry.path())
+                .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+                .filter(|path| {
+                    fs::read_to_string(path)
+                        .is_ok_and(|content| content.contains("[memory_config]"))
+                })
+                .filter_map(|path| Some(path.file_name()?.to_str()?.to_string()))
+                .collect();
+            configs.sort();
+            configs
+        }
+    }
+}
+
+/// Print the completion script, hooking dynamic values into the shells
+/// which support it
+pub(crate) fn print_completions(shell: Shell, command: &mut Command) {
+    let mut script = Vec::new();
+    generate(shell, command, BIN_NAME, &mut script);
+    let script = String::from_utf8(script).unwrap();
+
+    let script = match shell {
+        Shell::Bash => format!("{script}{BASH_DYNAMIC}"),
+        Shell::Fish => format!("{script}{FISH_DYNAMIC}"),
+        Shell::Zsh => zsh_dynamic(&script),
+        _ => script,
+    };
+    print!("{script}");
+    let _ = io::Write::flush(&mut io::stdout());
+}
+
+const BASH_DYNAMIC: &str = r#"
+_hpm_isp_dynamic() {
+    local cur="${COMP_WORDS[COMP_CWORD]}"
+    local prev="${COMP_WORDS[COMP_CWORD-1]}"
+    case "${prev}" in
+        --device)
+            COMPREPLY=($(compgen -W "$(hpm_isp complete-values devices 2>/dev/null)" -- "${cur}"))
+            return 0
+            ;;
+        --config|-c)
+            COMPREPLY=($(compgen -W "$(hpm_isp complete-values configs 2>/dev/null)" -- "${cur}"))
+            if [[ ${#COMPREPLY[@]} -gt 0 ]]; then
+                return 0
+            fi
+            ;;
+    esac
+    _hpm_isp "$@"
+}
+
+complete -F _hpm_isp_dynamic -o bashdefault -o default hpm_isp
+"#;
+
+const FISH_DYNAMIC: &str = r#"
+complete -c hpm_isp -l device -x -a "(hpm_isp complete-values devices 2>/dev/null)"
+complete -c hpm_isp -s c -l config -r -a "(hpm_isp complete-values configs 2>/dev/null)"
+"#;
+
+const ZSH_DYNAMIC: &str = r#"
+_hpm_isp_devices() {
+    local -a devices
+    devices=(${(f)"$(hpm_isp complete-values devices 2>/dev/null)"})
+    _describe 'device' devices
+}
+
+_hpm_isp_configs() {
+    local -a configs
+    configs=(${(f)"$(hpm_isp complete-values configs 2>/dev/null)"})
+    _describe 'config' configs
+    _files
+}
+"#;
+
+/// Replace the static actions of `--device` and `--config` with functions
+/// calling back into `hpm_isp`
+fn zsh_dynamic(script: &str) -> String {
+    let script = script
+        .lines()
+        .map(|line| {
+            if line.contains("'--device=[") {
+                replace_zsh_action(line, "_hpm_isp_devices")
+            } else if line.contains("'--config=[") || line.contains("'-c+[") {
+                replace_zsh_action(line, "_hpm_isp_configs")
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    // Define the functions before `_hpm_isp` is called at the end
+    match script.find("\n_hpm_isp \"$@\"") {
+        Some(index) => format!("{}{ZSH_DYNAMIC}{}\n", &script[..index], &script[index..]),
+        None => format!("{script}\n{ZSH_DYNAMIC}"),
+    }
+}
+
+/// Turn `'--opt=[help]:VALUE:_files'` into `'--opt=[help]:VALUE:action'`
+fn replace_zsh_action(line: &str, action: &str) -> String {
+    match line.rfind(':') {
+        Some(index) => {
+            let end = line[index..].find('\'').map_or(line.len(), |i| index + i);
+            format!("{}:{action}{}", &line[..index], &line[end..])
+        }
+        None => line.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_zsh_actions() {
+        assert_eq!(
+            replace_zsh_action("'--device=[USB serial]:SERIAL: ' \\", "_hpm_isp_devices"),
+            "'--device=[USB serial]:SERIAL:_hpm_isp_devices' \\"
+        );
+        assert_eq!(
+            replace_zsh_action(
+                "'-c+[Path of memory config file]:CONFIG:_files' \\",
+                "_hpm_isp_configs"
+            ),
+            "'-c+[Path of memory config file]:CONFIG:_hpm_isp_configs' \\"
+        );
+    }
+}
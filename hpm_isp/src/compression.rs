@@ -0,0 +1,253 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+
+use clap::ArgEnum;
+use flate2::{read::MultiGzDecoder, write::GzEncoder};
+use xz2::{read::XzDecoder, write::XzEncoder};
+
+use hpm_isp::image_file::FileFormat;
+
+use crate::output::CodedError;
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Compression {
+    None,
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+impl Compression {
+    /// Guess compression from the file extension
+    pub(crate) fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("gz") => Compression::Gzip,
+            Some("zst") => Compression::Zstd,
+            Some("xz") => Compression::Xz,
+            _ => Compression::None,
+        }
+    }
+
+    fn decoder<'a, R>(&self, reader: R) -> io::Result<Box<dyn Read + 'a>>
+    where
+        R: Read + 'a,
+    {
+        Ok(match self {
+            Compression::None => Box::new(reader),
+            Compression::Gzip => Box::new(MultiGzDecoder::new(reader)),
+            Compression::Zstd => Box::new(zstd::Decoder::new(reader)?),
+            Compression::Xz => Box::new(XzDecoder::new_multi_decoder(reader)),
+        })
+    }
+}
+
+/// `-` stands for stdin or stdout
+pub(crate) fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+/// Input image, decompressed on the fly
+pub(crate) struct Input {
+    pub(crate) reader: Box<dyn Read>,
+    /// Length of the decompressed data, unknown for stdin
+    pub(crate) length: Option<usize>,
+}
+
+impl Input {
+    /// Compressed files are decompressed once in advance to work out the
+    /// length of the data.
+    pub(crate) fn open(
+        path: &Path,
+        compression: Option<Compression>,
+    ) -> Result<Self, Box<dyn Error>> {
+        if is_stdio(path) {
+            let compression = compression.unwrap_or(Compression::None);
+            return Ok(Self {
+                reader: compression.decoder(io::stdin().lock())?,
+                length: None,
+            });
+        }
+
+        let compression = compression.unwrap_or_else(|| Compression::from_path(path));
+        let length = match compression {
+            Compression::None => File::open(path)?.metadata()?.len(),
+            _ => {
+                let file = BufReader::new(File::open(path)?);
+                io::copy(&mut compression.decoder(file)?, &mut io::sink())?
+            }
+        };
+        let file = BufReader::new(File::open(path)?);
+
+        Ok(Self {
+            reader: compression.decoder(file)?,
+            length: Some(length as usize),
+        })
+    }
+
+    /// Refuse Intel HEX and ELF files, which carry their addresses and can't
+    /// be written as is
+    pub(crate) fn require_bin(self, path: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut reader = BufReader::new(self.reader);
+        let format = FileFormat::detect(reader.fill_buf()?);
+        if format != FileFormat::Bin {
+            return Err(CodedError::new(
+                "invalid_argument",
+                format!(
+                    "{}: {} files carry their addresses, turn them into a raw binary with `image merge --merged FILE.bin` and write it at the address printed",
+                    path.display(),
+                    format.as_str()
+                ),
+            )
+            .into());
+        }
+        Ok(Self {
+            reader: Box::new(reader),
+            length: self.length,
+        })
+    }
+}
+
+/// Output image, compressing data on the fly
+pub(crate) enum Output {
+    Plain(Box<dyn Write>),
+    Gzip(GzEncoder<Box<dyn Write>>),
+    Zstd(zstd::Encoder<'static, Box<dyn Write>>),
+    Xz(XzEncoder<Box<dyn Write>>),
+}
+
+impl Output {
+    pub(crate) fn create(
+        path: &Path,
+        compression: Option<Compression>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let (writer, compression): (Box<dyn Write>, _) = if is_stdio(path) {
+            (
+                Box::new(io::stdout().lock()),
+                compression.unwrap_or(Compression::None),
+            )
+        } else {
+            (
+                Box::new(File::create(path)?),
+                compression.unwrap_or_else(|| Compression::from_path(path)),
+            )
+        };
+        Ok(Self::new(writer, compression)?)
+    }
+
+    fn new(writer: Box<dyn Write>, compression: Compression) -> io::Result<Self> {
+        Ok(match compression {
+            Compression::None => Output::Plain(writer),
+            Compression::Gzip => Output::Gzip(GzEncoder::new(writer, Default::default())),
+            Compression::Zstd => Output::Zstd(zstd::Encoder::new(writer, 0)?),
+            Compression::Xz => Output::Xz(XzEncoder::new(writer, 6)),
+        })
+    }
+
+    /// Write the end of the compressed stream and flush it
+    pub(crate) fn finish(self) -> io::Result<()> {
+        let mut writer = match self {
+            Output::Plain(writer) => writer,
+            Output::Gzip(encoder) => encoder.finish()?,
+            Output::Zstd(encoder) => encoder.finish()?,
+            Output::Xz(encoder) => encoder.finish()?,
+        };
+        writer.flush()
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::Plain(writer) => writer.write(buf),
+            Output::Gzip(encoder) => encoder.write(buf),
+            Output::Zstd(encoder) => encoder.write(buf),
+            Output::Xz(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Plain(writer) => writer.flush(),
+            Output::Gzip(encoder) => encoder.flush(),
+            Output::Zstd(encoder) => encoder.flush(),
+            Output::Xz(encoder) => encoder.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn detects_compression_from_extension() {
+        assert_eq!(
+            Compression::from_path(Path::new("flash.bin.gz")),
+            Compression::Gzip
+        );
+        assert_eq!(
+            Compression::from_path(Path::new("flash.zst")),
+            Compression::Zstd
+        );
+        assert_eq!(
+            Compression::from_path(Path::new("flash.bin.xz")),
+            Compression::Xz
+        );
+        assert_eq!(
+            Compression::from_path(Path::new("flash.bin")),
+            Compression::None
+        );
+    }
+
+    #[test]
+    fn round_trips_compressed_images() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        let dir = tempdir().unwrap();
+
+        for ext in ["bin", "gz", "zst", "xz"] {
+            let path = dir.path().join(format!("flash.{ext}"));
+            let mut output = Output::create(&path, None).unwrap();
+            output.write_all(&data).unwrap();
+            output.finish().unwrap();
+
+            let mut input = Input::open(&path, None).unwrap();
+            let mut decompressed = Vec::new();
+            input.reader.read_to_end(&mut decompressed).unwrap();
+
+            assert_eq!(input.length, Some(data.len()));
+            assert_eq!(decompressed, data);
+        }
+    }
+
+    #[test]
+    fn refuses_hex_and_elf_files() {
+        let dir = tempdir().unwrap();
+        let hex = dir.path().join("app.hex");
+        std::fs::write(&hex, ":0400000013050000E4\n:00000001FF\n").unwrap();
+        let bin = dir.path().join("app.bin");
+        std::fs::write(&bin, [0x13, 0x05, 0, 0]).unwrap();
+
+        let error = Input::open(&hex, None)
+            .unwrap()
+            .require_bin(&hex)
+            .err()
+            .unwrap();
+        assert!(error
+            .to_string()
+            .contains("hex files carry their addresses"));
+
+        let mut data = Vec::new();
+        Input::open(&bin, None)
+            .unwrap()
+            .require_bin(&bin)
+            .unwrap()
+            .reader
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data, [0x13, 0x05, 0, 0]);
+    }
+}
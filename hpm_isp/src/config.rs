@@ -1,9 +1,27 @@
+use std::collections::BTreeMap;
+use std::env;
 use std::error::Error;
+use std::fmt;
 use std::fs;
+use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
 
-use hpm_isp::memory_config::MemoryConfig;
+use hpm_isp::hid::Family;
+use hpm_isp::isp_command::MemoryId;
+use hpm_isp::memory_config::{
+    board_preset, BoardPreset, FlashType, MemoryConfig, PinGroup, PortConnection,
+    QuadIOEnableSequence,
+};
+use serde::de::{value, DeserializeOwned, IntoDeserializer};
 use serde::{Deserialize, Serialize};
+use toml_edit::{table, DocumentMut};
+
+use crate::output::CodedError;
+
+/// Prefix of the environment variables overriding config values
+const ENV_PREFIX: &str = "HPM_ISP_";
+/// Environment variable selecting a profile if `--profile` isn't given
+const PROFILE_ENV: &str = "HPM_ISP_PROFILE";
 
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
@@ -16,60 +34,508 @@ impl Config {
         Self { memory_config }
     }
 
-    fn from_file<P>(path: P) -> Result<Self, Box<dyn Error>>
+    pub(crate) fn to_toml_string(&self) -> Result<String, toml::ser::Error> {
+        toml::to_string_pretty(self)
+    }
+
+    /// Set the `[memory_config]` values of a config file, keeping its other
+    /// tables and comments
+    pub(crate) fn update_file(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let mut document = match fs::read_to_string(path) {
+            Ok(text) => text.parse::<DocumentMut>()?,
+            Err(e) if e.kind() == ErrorKind::NotFound => DocumentMut::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let update = self.to_toml_string()?.parse::<DocumentMut>()?;
+
+        let section = document
+            .entry("memory_config")
+            .or_insert_with(table)
+            .as_table_like_mut()
+            .ok_or_else(|| format!("memory_config of {} is not a table", path.display()))?;
+        for (key, value) in update["memory_config"]
+            .as_table_like()
+            .into_iter()
+            .flat_map(|t| t.iter())
+        {
+            section.insert(key, value.clone());
+        }
+        fs::write(path, document.to_string())?;
+        Ok(())
+    }
+}
+
+/// Values set by one config file, the unset ones fall through to lower layers
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigLayer {
+    memory_config: MemoryConfigLayer,
+    profiles: BTreeMap<String, ProfileLayer>,
+}
+
+impl ConfigLayer {
+    fn from_file(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let config = fs::read_to_string(path)
+            .map_err(|e| CodedError::new("invalid_config", format!("{}: {e}", path.display())))?;
+        Ok(Self::from_toml_str(&config)
+            .map_err(|e| CodedError::new("invalid_config", format!("{}: {e}", path.display())))?)
+    }
+
+    fn from_toml_str(config: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(config)
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct MemoryConfigLayer {
+    flash_type: Option<FlashType>,
+    port_connection: Option<PortConnection>,
+    pin_group: Option<PinGroup>,
+    quad_io_enable_sequence: Option<QuadIOEnableSequence>,
+}
+
+impl From<MemoryConfig> for MemoryConfigLayer {
+    fn from(config: MemoryConfig) -> Self {
+        Self {
+            flash_type: Some(config.flash_type),
+            port_connection: Some(config.port_connection),
+            pin_group: Some(config.pin_group),
+            quad_io_enable_sequence: Some(config.quad_io_enable_sequence),
+        }
+    }
+}
+
+/// Board profile, `[profiles.<name>]`, overriding the values of the file it's
+/// defined in when selected with `--profile`
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ProfileLayer {
+    memory_config: MemoryConfigLayer,
+    /// XPI<ID> the board boots from
+    xpi: Option<u8>,
+    /// Family the board is expected to have
+    family: Option<Family>,
+}
+
+/// Config layers, from lowest to highest precedence
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Layer {
+    #[default]
+    Default,
+    /// `hpm_isp/config.toml` in the user config directory
+    User,
+    /// Project file found in the working directory or one of its parents
+    Project,
+    /// `HPM_ISP_*` environment variables
+    Env,
+    /// `--board` preset and file passed with `-c`
+    Cli,
+}
+
+impl Layer {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Layer::Default => "default",
+            Layer::User => "user",
+            Layer::Project => "project",
+            Layer::Env => "env",
+            Layer::Cli => "cli",
+        }
+    }
+}
+
+/// Where a config value came from
+#[derive(Clone, Debug, Default, Serialize)]
+pub(crate) struct Source {
+    layer: Layer,
+    /// Config file or environment variable
+    origin: Option<String>,
+}
+
+impl Source {
+    fn file(layer: Layer, path: &Path) -> Self {
+        Self {
+            layer,
+            origin: Some(path.display().to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.origin {
+            Some(origin) => write!(f, "{} ({origin})", self.layer.as_str()),
+            None => write!(f, "{}", self.layer.as_str()),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Resolved<T> {
+    value: T,
+    source: Source,
+}
+
+impl<T: Serialize> Resolved<T> {
+    fn set(&mut self, value: Option<T>, source: &Source) {
+        if let Some(value) = value {
+            self.value = value;
+            self.source = source.clone();
+        }
+    }
+
+    /// Read `HPM_ISP_<KEY>`, e.g. `HPM_ISP_PIN_GROUP=group2`
+    fn set_from_env<F>(&mut self, key: &str, var: &F) -> Result<(), CodedError>
     where
-        P: AsRef<Path>,
+        T: DeserializeOwned,
+        F: Fn(&str) -> Option<String>,
     {
-        println!("Reading memory config from: {}", path.as_ref().display());
+        let name = format!("{ENV_PREFIX}{}", key.to_uppercase());
+        if let Some(value) = var(&name) {
+            let value = T::deserialize(IntoDeserializer::<value::Error>::into_deserializer(
+                value.as_str(),
+            ))
+            .map_err(|e| CodedError::new("invalid_config", format!("{name}: {e}")))?;
+            self.value = value;
+            self.source = Source {
+                layer: Layer::Env,
+                origin: Some(name),
+            };
+        }
+        Ok(())
+    }
 
-        let config = fs::read_to_string(path)?;
-        Ok(Self::from_toml_str(&config)?)
+    fn entry(&self, key: &'static str) -> ResolvedValue {
+        ResolvedValue {
+            key,
+            value: serde_json::to_value(&self.value).unwrap_or_default(),
+            source: self.source.clone(),
+        }
     }
+}
 
-    pub(crate) fn to_toml_string(&self) -> Result<String, toml::ser::Error> {
-        toml::to_string_pretty(self)
+/// Config merged from the defaults, the user and project files, the
+/// environment and the command line, in that order
+#[derive(Debug, Default)]
+pub(crate) struct ResolvedConfig {
+    /// Config files read, from lowest to highest precedence
+    pub(crate) files: Vec<(Layer, PathBuf)>,
+    /// Selected profile
+    pub(crate) profile: Option<String>,
+    /// Whether a config file defines the selected profile
+    profile_found: bool,
+    flash_type: Resolved<FlashType>,
+    port_connection: Resolved<PortConnection>,
+    pin_group: Resolved<PinGroup>,
+    quad_io_enable_sequence: Resolved<QuadIOEnableSequence>,
+    xpi: Resolved<Option<u8>>,
+    family: Resolved<Option<Family>>,
+}
+
+impl ResolvedConfig {
+    /// Merge all layers, `project_file` is searched from the working directory
+    /// up to the root
+    pub(crate) fn load(
+        cli_config: Option<&Path>,
+        profile: Option<&str>,
+        board: Option<&str>,
+        project_file: &str,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut config = Self {
+            profile: profile
+                .map(str::to_string)
+                .or_else(|| env::var(PROFILE_ENV).ok()),
+            ..Default::default()
+        };
+        if let Some(path) = user_config_path().filter(|path| path.is_file()) {
+            config.apply_file(Layer::User, &path)?;
+        }
+        if let Some(path) = find_project_config(&env::current_dir()?, project_file) {
+            config.apply_file(Layer::Project, &path)?;
+        }
+        config.apply_env(|name| env::var(name).ok())?;
+        if let Some(name) = board {
+            let preset = board_preset(name).ok_or_else(|| {
+                CodedError::new(
+                    "invalid_config",
+                    format!("unknown board `{name}`, see `boards`"),
+                )
+            })?;
+            config.apply_board(preset);
+        }
+        if let Some(path) = cli_config {
+            config.apply_file(Layer::Cli, path)?;
+        }
+        if let (Some(profile), false) = (&config.profile, config.profile_found) {
+            return Err(CodedError::new(
+                "invalid_config",
+                format!("profile `{profile}` isn't defined in any config file"),
+            )
+            .into());
+        }
+        Ok(config)
     }
 
-    fn into_memory_config(self) -> MemoryConfig {
-        self.memory_config
+    fn apply_file(&mut self, layer: Layer, path: &Path) -> Result<(), Box<dyn Error>> {
+        let mut config = ConfigLayer::from_file(path)?;
+        self.apply(config.memory_config, &Source::file(layer, path));
+        if let Some(name) = &self.profile {
+            if let Some(profile) = config.profiles.remove(name) {
+                let source = Source {
+                    layer,
+                    origin: Some(format!("{} [profiles.{name}]", path.display())),
+                };
+                self.apply_profile(profile, &source)?;
+            }
+        }
+        self.files.push((layer, path.to_path_buf()));
+        Ok(())
     }
 
-    fn from_toml_str(config: &str) -> Result<Self, toml::de::Error> {
-        toml::from_str(config)
+    fn apply_profile(&mut self, profile: ProfileLayer, source: &Source) -> Result<(), CodedError> {
+        let invalid = |message: String| {
+            CodedError::new(
+                "invalid_config",
+                format!(
+                    "{}: {message}",
+                    source.origin.as_deref().unwrap_or_default()
+                ),
+            )
+        };
+        if profile.xpi.is_some_and(|xpi| xpi > 1) {
+            return Err(invalid("xpi must be 0 or 1".to_string()));
+        }
+
+        self.apply(profile.memory_config, source);
+        self.xpi.set(profile.xpi.map(Some), source);
+        self.family.set(profile.family.map(Some), source);
+        self.profile_found = true;
+        Ok(())
+    }
+
+    fn apply_env<F>(&mut self, var: F) -> Result<(), CodedError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        self.flash_type.set_from_env("flash_type", &var)?;
+        self.port_connection.set_from_env("port_connection", &var)?;
+        self.pin_group.set_from_env("pin_group", &var)?;
+        self.quad_io_enable_sequence
+            .set_from_env("quad_io_enable_sequence", &var)?;
+        Ok(())
+    }
+
+    fn apply_board(&mut self, preset: &BoardPreset) {
+        let source = Source {
+            layer: Layer::Cli,
+            origin: Some(format!("--board {}", preset.name)),
+        };
+        self.apply(preset.memory_config.into(), &source);
+        let xpi = match preset.xpi {
+            MemoryId::XPI1 => 1,
+            _ => 0,
+        };
+        self.xpi.set(Some(Some(xpi)), &source);
+        self.family.set(Some(Some(preset.family)), &source);
+    }
+
+    fn apply(&mut self, layer: MemoryConfigLayer, source: &Source) {
+        self.flash_type.set(layer.flash_type, source);
+        self.port_connection.set(layer.port_connection, source);
+        self.pin_group.set(layer.pin_group, source);
+        self.quad_io_enable_sequence
+            .set(layer.quad_io_enable_sequence, source);
+    }
+
+    pub(crate) fn memory_config(&self) -> MemoryConfig {
+        MemoryConfig::new()
+            .flash_type(self.flash_type.value)
+            .port_connection(self.port_connection.value)
+            .pin_group(self.pin_group.value)
+            .quad_io_enable_sequence(self.quad_io_enable_sequence.value)
+    }
+
+    /// XPI the selected profile boots from
+    pub(crate) fn xpi(&self) -> Option<MemoryId> {
+        self.xpi.value.map(|xpi| match xpi {
+            0 => MemoryId::XPI0,
+            _ => MemoryId::XPI1,
+        })
+    }
+
+    /// Family the selected profile or board preset is for
+    pub(crate) fn family(&self) -> Option<Family> {
+        self.family.value
+    }
+
+    /// Refuse a device of another family than the profile or board preset
+    /// expects
+    pub(crate) fn check_family(&self, family: Family) -> Result<(), CodedError> {
+        match self.family.value {
+            Some(expected) if expected != family => Err(CodedError::new(
+                "family_mismatch",
+                format!(
+                    "{} expects {expected}, found {family}",
+                    self.family.source.origin.as_deref().unwrap_or_default()
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Every value with the layer it came from
+    pub(crate) fn values(&self) -> Vec<ResolvedValue> {
+        let mut values = vec![
+            self.xpi.entry("xpi"),
+            self.family.entry("family"),
+            self.flash_type.entry("memory_config.flash_type"),
+            self.port_connection.entry("memory_config.port_connection"),
+            self.pin_group.entry("memory_config.pin_group"),
+            self.quad_io_enable_sequence
+                .entry("memory_config.quad_io_enable_sequence"),
+        ];
+        // Profile values are unset without a profile
+        values.retain(|value| !value.value.is_null());
+        values
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct ResolvedValue {
+    pub(crate) key: &'static str,
+    pub(crate) value: serde_json::Value,
+    pub(crate) source: Source,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ShowResult {
+    command: &'static str,
+    profile: Option<String>,
+    files: Vec<ConfigFile>,
+    values: Vec<ResolvedValue>,
+    /// Print where each value came from
+    #[serde(skip)]
+    resolved: bool,
+}
+
+#[derive(Serialize)]
+struct ConfigFile {
+    layer: Layer,
+    path: PathBuf,
+}
+
+impl ShowResult {
+    pub(crate) fn new(config: &ResolvedConfig, resolved: bool) -> Self {
+        Self {
+            command: "config show",
+            profile: config.profile.clone(),
+            files: config
+                .files
+                .iter()
+                .map(|(layer, path)| ConfigFile {
+                    layer: *layer,
+                    path: path.clone(),
+                })
+                .collect(),
+            values: config.values(),
+            resolved,
+        }
     }
 }
 
-pub(crate) fn read_memory_config_or_default(
-    config: Option<PathBuf>,
-    default_config_file: &str,
-) -> Result<Vec<u8>, Box<dyn Error>> {
-    if let Some(config_path) = config {
-        return read_memory_config(config_path);
+impl ShowResult {
+    /// Print `key = value` in TOML, which matches JSON for these values
+    fn fmt_value(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        key: &str,
+        value: &ResolvedValue,
+    ) -> fmt::Result {
+        let line = format!("{key} = {}", value.value);
+        if self.resolved {
+            write!(f, "{line:<48} # {}", value.source)
+        } else {
+            write!(f, "{line}")
+        }
     }
+}
 
-    if Path::new(default_config_file).exists() {
-        return read_memory_config(default_config_file);
+impl fmt::Display for ShowResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.resolved {
+            for file in &self.files {
+                writeln!(f, "# {}: {}", file.layer.as_str(), file.path.display())?;
+            }
+        }
+        if let Some(profile) = &self.profile {
+            writeln!(f, "# profile: {profile}")?;
+        }
+        // Top-level values first, then the [memory_config] table
+        let (memory_config, top): (Vec<_>, Vec<_>) = self
+            .values
+            .iter()
+            .partition(|value| value.key.starts_with("memory_config."));
+        for value in top {
+            self.fmt_value(f, value.key, value)?;
+            writeln!(f)?;
+        }
+        write!(f, "[memory_config]")?;
+        for value in memory_config {
+            writeln!(f)?;
+            self.fmt_value(f, value.key.trim_start_matches("memory_config."), value)?;
+        }
+        Ok(())
     }
+}
+
+/// Values of a memory config in field order, e.g. `sfdp_sdr, port_a_cs0, group1, none`
+pub(crate) fn describe_memory_config(memory_config: &MemoryConfig) -> String {
+    let values = serde_json::to_value(memory_config).unwrap_or_default();
+    [
+        "flash_type",
+        "port_connection",
+        "pin_group",
+        "quad_io_enable_sequence",
+    ]
+    .iter()
+    .filter_map(|key| values[key].as_str())
+    .collect::<Vec<_>>()
+    .join(", ")
+}
 
-    Ok(MemoryConfig::default().to_bootrom_config())
+/// `hpm_isp/config.toml` in `$XDG_CONFIG_HOME`, `~/.config` or `%APPDATA%`
+pub(crate) fn user_config_path() -> Option<PathBuf> {
+    let dir = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            if cfg!(windows) {
+                env::var_os("APPDATA").map(PathBuf::from)
+            } else {
+                env::var_os("HOME").map(|home| PathBuf::from(home).join(".config"))
+            }
+        })?;
+    Some(dir.join("hpm_isp").join("config.toml"))
 }
 
-fn read_memory_config<P>(path: P) -> Result<Vec<u8>, Box<dyn Error>>
-where
-    P: AsRef<Path>,
-{
-    Ok(Config::from_file(path)?
-        .into_memory_config()
-        .to_bootrom_config())
+/// Search `file_name` in `dir` and its parents
+fn find_project_config(dir: &Path, file_name: &str) -> Option<PathBuf> {
+    dir.ancestors()
+        .map(|dir| dir.join(file_name))
+        .find(|path| path.is_file())
 }
 
 #[cfg(test)]
 mod tests {
+    use tempfile::tempdir;
+
     use super::*;
 
     #[test]
     fn parses_memory_config_section() {
-        let config = Config::from_toml_str(
+        let config = ConfigLayer::from_toml_str(
             r#"
 [memory_config]
 flash_type = "read_1_4_4"
@@ -80,7 +546,10 @@ quad_io_enable_sequence = "status2_bit1_programmed_by_0x31"
         )
         .unwrap();
 
-        assert_eq!(config.into_memory_config().to_bootrom_config().len(), 12);
+        let mut resolved = ResolvedConfig::default();
+        resolved.apply(config.memory_config, &Source::default());
+        assert_eq!(resolved.memory_config().to_bootrom_config().len(), 12);
+        assert_eq!(resolved.flash_type.value, FlashType::Read144);
     }
 
     #[test]
@@ -92,4 +561,156 @@ quad_io_enable_sequence = "status2_bit1_programmed_by_0x31"
         assert!(config.contains("[memory_config]"));
         assert!(config.contains("flash_type = \"sfdp_sdr\""));
     }
+
+    #[test]
+    fn updates_memory_config_of_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("hpm_isp.toml");
+        fs::write(
+            &path,
+            "# board config\n[memory_config]\nflash_type = \"read_1_4_4\"\n\n[profiles.evk]\nxpi = 1\n",
+        )
+        .unwrap();
+
+        Config::new(MemoryConfig::new().pin_group(PinGroup::Group2))
+            .update_file(&path)
+            .unwrap();
+
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(text.starts_with("# board config\n"));
+        let config = ConfigLayer::from_toml_str(&text).unwrap();
+        assert_eq!(config.memory_config.flash_type, Some(FlashType::SfdpSdr));
+        assert_eq!(config.memory_config.pin_group, Some(PinGroup::Group2));
+        assert_eq!(config.profiles["evk"].xpi, Some(1));
+    }
+
+    #[test]
+    fn merges_layers_in_order() {
+        let mut resolved = ResolvedConfig::default();
+        let user = ConfigLayer::from_toml_str(
+            "[memory_config]\nflash_type = \"read_1_4_4\"\npin_group = \"group2\"",
+        )
+        .unwrap();
+        resolved.apply(
+            user.memory_config,
+            &Source::file(Layer::User, Path::new("user.toml")),
+        );
+        let project =
+            ConfigLayer::from_toml_str("[memory_config]\nport_connection = \"port_b_cs0\"")
+                .unwrap();
+        resolved.apply(
+            project.memory_config,
+            &Source::file(Layer::Project, Path::new("hpm_isp.toml")),
+        );
+        resolved
+            .apply_env(|name| (name == "HPM_ISP_PIN_GROUP").then(|| "group1".to_string()))
+            .unwrap();
+
+        let values = resolved.values();
+        assert_eq!(values[0].value, "read_1_4_4");
+        assert_eq!(values.len(), 4);
+        assert_eq!(values[0].source.to_string(), "user (user.toml)");
+        assert_eq!(values[1].value, "port_b_cs0");
+        assert_eq!(values[1].source.layer, Layer::Project);
+        assert_eq!(values[2].value, "group1");
+        assert_eq!(values[2].source.to_string(), "env (HPM_ISP_PIN_GROUP)");
+        assert_eq!(values[3].value, "none");
+        assert_eq!(values[3].source.to_string(), "default");
+    }
+
+    #[test]
+    fn applies_selected_profile() {
+        let config = ConfigLayer::from_toml_str(
+            r#"
+[memory_config]
+flash_type = "read_1_4_4"
+
+[profiles.evk]
+xpi = 1
+family = "HPM5300"
+
+[profiles.evk.memory_config]
+port_connection = "port_b_cs0"
+
+[profiles.other]
+family = "HPM6700"
+"#,
+        )
+        .unwrap();
+        let path = Path::new("hpm_isp.toml");
+        let mut resolved = ResolvedConfig {
+            profile: Some("evk".to_string()),
+            ..Default::default()
+        };
+        let mut profiles = config.profiles;
+        resolved.apply(config.memory_config, &Source::file(Layer::Project, path));
+        let source = Source {
+            layer: Layer::Project,
+            origin: Some("hpm_isp.toml [profiles.evk]".to_string()),
+        };
+        resolved
+            .apply_profile(profiles.remove("evk").unwrap(), &source)
+            .unwrap();
+
+        assert_eq!(resolved.xpi(), Some(MemoryId::XPI1));
+        assert!(resolved.check_family(Family::HPM5300).is_ok());
+        let error = resolved.check_family(Family::HPM6300).unwrap_err();
+        assert_eq!(error.code, "family_mismatch");
+        assert_eq!(resolved.flash_type.value, FlashType::Read144);
+        assert_eq!(resolved.port_connection.value, PortConnection::PortBCs0);
+        assert_eq!(
+            resolved.port_connection.source.to_string(),
+            "project (hpm_isp.toml [profiles.evk])"
+        );
+        assert_eq!(
+            profiles.remove("other").unwrap().family,
+            Some(Family::HPM6700_6400)
+        );
+
+        let invalid = ProfileLayer {
+            xpi: Some(2),
+            ..Default::default()
+        };
+        assert!(resolved.apply_profile(invalid, &source).is_err());
+    }
+
+    #[test]
+    fn applies_board_preset() {
+        let mut resolved = ResolvedConfig::default();
+        resolved.apply_board(board_preset("hpm5300evk").unwrap());
+
+        assert_eq!(resolved.pin_group.value, PinGroup::Group2);
+        assert_eq!(resolved.xpi(), Some(MemoryId::XPI0));
+        assert_eq!(
+            resolved
+                .check_family(Family::HPM6700_6400)
+                .unwrap_err()
+                .to_string(),
+            "--board hpm5300evk expects HPM5300, found HPM6700/6400"
+        );
+        assert_eq!(
+            resolved.pin_group.source.to_string(),
+            "cli (--board hpm5300evk)"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_env_values() {
+        let mut resolved = ResolvedConfig::default();
+        let error = resolved
+            .apply_env(|name| (name == "HPM_ISP_FLASH_TYPE").then(|| "nand".to_string()))
+            .unwrap_err();
+        assert!(error.to_string().contains("HPM_ISP_FLASH_TYPE"));
+    }
+
+    #[test]
+    fn finds_project_config_in_parents() {
+        let root = tempdir().unwrap();
+        let nested = root.path().join("a").join("b");
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(root.path().join("hpm_isp.toml"), "").unwrap();
+
+        let found = find_project_config(&nested, "hpm_isp.toml");
+        assert_eq!(found, Some(root.path().join("hpm_isp.toml")));
+    }
 }
@@ -0,0 +1,33 @@
+//! Hex strings of hashes, keys and per-unit data
+
+/// Lowercase hex digits of `bytes`
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Bytes of a string of hex digits, `None` for an odd number of digits or
+/// any other character
+pub(crate) fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_hex() {
+        assert_eq!(to_hex(&[0x00, 0xA5, 0xFF]), "00a5ff");
+        assert_eq!(decode_hex("00A5ff"), Some(vec![0x00, 0xA5, 0xFF]));
+        assert_eq!(decode_hex(""), Some(vec![]));
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("zz"), None);
+        assert_eq!(decode_hex("+1"), None);
+    }
+}
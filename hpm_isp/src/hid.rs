@@ -1,11 +1,17 @@
 use std::fmt::Display;
+use std::time::Duration;
 
 use hidapi::{HidApi, HidDevice, HidError};
 use num_enum::FromPrimitive;
+use serde::{Deserialize, Serialize};
 use strum::{EnumIter, IntoEnumIterator};
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
-use crate::isp_command::{Error, Interface, IspCommand, Packet};
+use crate::isp_command::{Error, Interface, IspCommand, MemoryId, Packet};
+
+/// Longest wait for a packet of the device, which answers each packet after
+/// programming at most one flash sector
+pub const READ_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(AsBytes, FromZeroes, FromBytes)]
 #[repr(C, packed)]
@@ -62,9 +68,11 @@ impl HidAcknowledgement {
     }
 }
 
-#[derive(EnumIter, Clone, Copy)]
+/// Chip family, named as printed in config files, e.g. `family = "HPM5300"`
+#[derive(EnumIter, Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
 #[repr(u16)]
 pub enum Family {
+    #[serde(rename = "HPM6700/6400", alias = "HPM6700", alias = "HPM6400")]
     HPM6700_6400 = 0x0001,
     HPM6300 = 0x0002,
     HPM6200 = 0x0003,
@@ -81,6 +89,25 @@ impl Family {
     pub fn vid(&self) -> u16 {
         *self as u16
     }
+
+    /// Size of a memory of the family's first core, none for memories the
+    /// family doesn't have
+    ///
+    /// XPI flashes are sized by the board, so they get their whole window.
+    pub fn memory_size(&self, memory_id: MemoryId) -> Option<u32> {
+        match (self, memory_id) {
+            (
+                Family::HPM6700_6400 | Family::HPM6800 | Family::HPM6E00,
+                MemoryId::ILM | MemoryId::DLM,
+            ) => Some(0x4_0000),
+            (_, MemoryId::ILM | MemoryId::DLM) => Some(0x2_0000),
+            (Family::HPM6700_6400, MemoryId::XRAM) => Some(0x10_0000),
+            (Family::HPM6300 | Family::HPM6800 | Family::HPM6E00, MemoryId::XRAM) => Some(0x8_0000),
+            (Family::HPM6200, MemoryId::XRAM) => Some(0x4_0000),
+            (Family::HPM5300, MemoryId::XRAM) => None,
+            (_, MemoryId::XPI0 | MemoryId::XPI1) => Some(memory_id.window_size()),
+        }
+    }
 }
 
 impl Display for Family {
@@ -102,7 +129,32 @@ pub struct HpmDevice {
     family: Family,
 }
 
+/// An attached HPMicro device in ISP mode
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub family: Family,
+    pub serial_number: Option<String>,
+    pub path: String,
+}
+
 impl HpmDevice {
+    pub fn list() -> Result<Vec<DeviceInfo>, Box<dyn std::error::Error>> {
+        let api = HidApi::new()?;
+        let devices = api
+            .device_list()
+            .filter(|info| info.vendor_id() == Family::pid())
+            .filter_map(|info| {
+                let family = Family::iter().find(|chip| chip.vid() == info.product_id())?;
+                Some(DeviceInfo {
+                    family,
+                    serial_number: info.serial_number().map(str::to_string),
+                    path: info.path().to_string_lossy().into_owned(),
+                })
+            })
+            .collect();
+        Ok(devices)
+    }
+
     pub fn open() -> Result<Self, Box<dyn std::error::Error>> {
         let api = HidApi::new().unwrap();
         // Connect to device using its VID and PID
@@ -116,9 +168,54 @@ impl HpmDevice {
         Ok(Self { device, family })
     }
 
+    /// Open the device with the given USB serial number
+    pub fn open_serial(serial_number: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let api = HidApi::new()?;
+        let (device, family) = Family::iter()
+            .find_map(|chip| {
+                api.open_serial(Family::pid(), chip.vid(), serial_number)
+                    .ok()
+                    .map(|device| (device, chip))
+            })
+            .ok_or_else(|| format!("Can't find HPMicro device {serial_number}"))?;
+        Ok(Self { device, family })
+    }
+
     pub fn family(&self) -> Family {
         self.family
     }
+
+    /// Read a report, a device not answering within [`READ_TIMEOUT`] fails
+    /// the transfer
+    fn read_report(&self, buffer: &mut [u8]) -> Result<(), Error> {
+        match self
+            .device
+            .read_timeout(buffer, READ_TIMEOUT.as_millis() as i32)?
+        {
+            0 => Err(Error::TransferError),
+            _ => Ok(()),
+        }
+    }
+
+    /// USB identity of the device
+    pub fn usb_info(&self) -> UsbInfo {
+        UsbInfo {
+            vendor_id: Family::pid(),
+            product_id: self.family.vid(),
+            manufacturer: self.device.get_manufacturer_string().ok().flatten(),
+            product: self.device.get_product_string().ok().flatten(),
+            serial_number: self.device.get_serial_number_string().ok().flatten(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UsbInfo {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
 }
 
 impl Interface for HpmDevice {
@@ -132,7 +229,7 @@ impl Interface for HpmDevice {
 
         // Device ACK/NAK/Abort stage
         let mut buffer: [u8; 516] = [0u8; 516];
-        self.device.read(&mut buffer)?;
+        self.read_report(&mut buffer)?;
         let ack_packet: HidAcknowledgement =
             HidAcknowledgement::read_from_prefix(&buffer[..]).unwrap();
 
@@ -146,7 +243,7 @@ impl Interface for HpmDevice {
         let mut buffer = [0u8; 516];
 
         // Device response stage
-        self.device.read(&mut buffer)?;
+        self.read_report(&mut buffer)?;
         let response_packet: HidPayloadPacket =
             HidPayloadPacket::read_from_prefix(&buffer[..]).unwrap();
 
@@ -157,6 +254,12 @@ impl Interface for HpmDevice {
         *packet = Packet::read_from_prefix(&response_packet.payload[..]).unwrap();
         Ok(response_packet.length - 4)
     }
+
+    fn abort(&self) -> Result<(), Error> {
+        let abort_packet = HidAcknowledgement::new(PacketType::Abort);
+        self.device.write(abort_packet.as_bytes())?;
+        Ok(())
+    }
 }
 
 impl IspCommand for HpmDevice {}
@@ -0,0 +1,400 @@
+//! Firmware files: raw binaries, Intel HEX and ELF
+//!
+//! Every format is read into segments of data at absolute addresses. ELF
+//! files are placed by the physical address of their loadable segments, which
+//! is where initialised data lives in flash, not where it runs.
+
+use object::elf::{FileHeader32, ELFOSABI_NONE, EM_RISCV, ET_EXEC, PF_R, PF_W, PF_X, PT_LOAD};
+use object::read::elf::{FileHeader, ProgramHeader};
+use object::write::elf::Writer;
+use object::{write, Endianness};
+use thiserror::Error;
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+/// Data bytes per Intel HEX record, as written by the GNU tools
+const HEX_RECORD_LENGTH: usize = 16;
+
+#[derive(Debug, Error)]
+pub enum FileError {
+    #[error("invalid Intel HEX file: {0}")]
+    Hex(#[from] ihex::ReaderError),
+    #[error("invalid ELF file: {0}")]
+    Elf(#[from] object::read::Error),
+    #[error("ELF segment {0} lies outside of the file")]
+    TruncatedElfSegment(usize),
+    #[error("data at {0:#x} runs past the end of the address space")]
+    AddressOverflow(u32),
+}
+
+/// Format of a firmware file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileFormat {
+    /// Raw binary, placed at an address given by the user
+    Bin,
+    /// Intel HEX, with 32-bit linear or 20-bit segment addresses
+    Hex,
+    /// 32-bit ELF
+    Elf,
+}
+
+impl FileFormat {
+    /// Guess the format from the contents
+    pub fn detect(data: &[u8]) -> Self {
+        let text = data.trim_ascii_start();
+        if data.starts_with(ELF_MAGIC) {
+            FileFormat::Elf
+        } else if text.starts_with(b":") && text.is_ascii() {
+            FileFormat::Hex
+        } else {
+            FileFormat::Bin
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FileFormat::Bin => "bin",
+            FileFormat::Hex => "hex",
+            FileFormat::Elf => "elf",
+        }
+    }
+}
+
+/// Data at an absolute address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    /// Address following the last byte, which may be 4 GiB
+    pub fn end(&self) -> u64 {
+        u64::from(self.address) + self.data.len() as u64
+    }
+}
+
+/// Firmware file read into segments
+#[derive(Debug, Clone)]
+pub struct ImageFile {
+    pub format: FileFormat,
+    /// Segments sorted by address
+    pub segments: Vec<Segment>,
+    pub entry_point: Option<u32>,
+}
+
+impl ImageFile {
+    /// Read a file of any format, raw binaries are placed at `address`
+    pub fn parse(data: &[u8], address: u32) -> Result<Self, FileError> {
+        let mut file = match FileFormat::detect(data) {
+            FileFormat::Bin => Self {
+                format: FileFormat::Bin,
+                segments: vec![Segment {
+                    address,
+                    data: data.to_vec(),
+                }],
+                entry_point: None,
+            },
+            FileFormat::Hex => Self::parse_hex(&String::from_utf8_lossy(data))?,
+            FileFormat::Elf => Self::parse_elf(data)?,
+        };
+        if let Some(segment) = file.segments.iter().find(|s| s.end() > 1 << 32) {
+            return Err(FileError::AddressOverflow(segment.address));
+        }
+        file.segments.retain(|segment| !segment.data.is_empty());
+        file.segments.sort_by_key(|segment| segment.address);
+        Ok(file)
+    }
+
+    fn parse_hex(text: &str) -> Result<Self, FileError> {
+        let mut segments: Vec<Segment> = Vec::new();
+        let mut entry_point = None;
+        let mut base = 0u32;
+        for record in ihex::Reader::new(text) {
+            match record? {
+                ihex::Record::Data { offset, value } => {
+                    let address = base.wrapping_add(u32::from(offset));
+                    match segments.last_mut() {
+                        // Records usually follow each other, keep them in one segment
+                        Some(last) if last.end() == u64::from(address) => {
+                            last.data.extend_from_slice(&value)
+                        }
+                        _ => segments.push(Segment {
+                            address,
+                            data: value,
+                        }),
+                    }
+                }
+                ihex::Record::ExtendedSegmentAddress(segment) => base = u32::from(segment) << 4,
+                ihex::Record::ExtendedLinearAddress(upper) => base = u32::from(upper) << 16,
+                ihex::Record::StartSegmentAddress { cs, ip } => {
+                    entry_point = Some((u32::from(cs) << 4) + u32::from(ip))
+                }
+                ihex::Record::StartLinearAddress(address) => entry_point = Some(address),
+                ihex::Record::EndOfFile => break,
+            }
+        }
+        Ok(Self {
+            format: FileFormat::Hex,
+            segments,
+            entry_point,
+        })
+    }
+
+    fn parse_elf(data: &[u8]) -> Result<Self, FileError> {
+        let header = FileHeader32::<Endianness>::parse(data)?;
+        let endian = header.endian()?;
+        let mut segments = Vec::new();
+        for (index, program_header) in header.program_headers(endian, data)?.iter().enumerate() {
+            if program_header.p_type(endian) != PT_LOAD {
+                continue;
+            }
+            let bytes = program_header
+                .data(endian, data)
+                .map_err(|()| FileError::TruncatedElfSegment(index))?;
+            segments.push(Segment {
+                address: program_header.p_paddr(endian),
+                data: bytes.to_vec(),
+            });
+        }
+        Ok(Self {
+            format: FileFormat::Elf,
+            segments,
+            entry_point: Some(header.e_entry(endian)),
+        })
+    }
+
+    /// Intel HEX with 32-bit linear addresses
+    pub fn to_hex(&self) -> Result<String, ihex::WriterError> {
+        let mut records = Vec::new();
+        let mut upper = None;
+        for segment in &self.segments {
+            let mut offset = 0;
+            while offset < segment.data.len() {
+                let address = segment.address + offset as u32;
+                if upper != Some(address >> 16) {
+                    upper = Some(address >> 16);
+                    records.push(ihex::Record::ExtendedLinearAddress((address >> 16) as u16));
+                }
+                // Records may not cross a 64 KiB boundary
+                let length = HEX_RECORD_LENGTH
+                    .min(segment.data.len() - offset)
+                    .min(0x1_0000 - (address & 0xFFFF) as usize);
+                records.push(ihex::Record::Data {
+                    offset: address as u16,
+                    value: segment.data[offset..offset + length].to_vec(),
+                });
+                offset += length;
+            }
+        }
+        if let Some(entry_point) = self.entry_point {
+            records.push(ihex::Record::StartLinearAddress(entry_point));
+        }
+        records.push(ihex::Record::EndOfFile);
+        ihex::create_object_file_representation(&records)
+    }
+
+    /// 32-bit RISC-V executable with a loadable segment for each segment
+    pub fn to_elf(&self) -> Result<Vec<u8>, write::Error> {
+        let mut buffer = Vec::new();
+        let mut writer = Writer::new(Endianness::Little, false, &mut buffer);
+        writer.reserve_file_header();
+        writer.reserve_program_headers(self.segments.len() as u32);
+        let offsets: Vec<usize> = self
+            .segments
+            .iter()
+            .map(|segment| writer.reserve(segment.data.len(), 1))
+            .collect();
+
+        writer.write_file_header(&write::elf::FileHeader {
+            os_abi: ELFOSABI_NONE,
+            abi_version: 0,
+            e_type: ET_EXEC,
+            e_machine: EM_RISCV,
+            e_entry: u64::from(self.entry_point.unwrap_or_default()),
+            e_flags: 0,
+        })?;
+        writer.write_align_program_headers();
+        for (segment, offset) in self.segments.iter().zip(&offsets) {
+            writer.write_program_header(&write::elf::ProgramHeader {
+                p_type: PT_LOAD,
+                p_flags: PF_R | PF_W | PF_X,
+                p_offset: *offset as u64,
+                p_vaddr: u64::from(segment.address),
+                p_paddr: u64::from(segment.address),
+                p_filesz: segment.data.len() as u64,
+                p_memsz: segment.data.len() as u64,
+                p_align: 1,
+            });
+        }
+        for segment in &self.segments {
+            writer.write(&segment.data);
+        }
+        Ok(buffer)
+    }
+}
+
+/// Join segments which follow each other, the segments must be sorted and
+/// must not overlap
+pub fn coalesce(segments: Vec<Segment>) -> Vec<Segment> {
+    let mut joined: Vec<Segment> = Vec::with_capacity(segments.len());
+    for segment in segments {
+        match joined.last_mut() {
+            Some(last) if last.end() == u64::from(segment.address) => {
+                last.data.extend_from_slice(&segment.data)
+            }
+            _ => joined.push(segment),
+        }
+    }
+    joined
+}
+
+/// Data from `start` to the end of the last segment, with the gaps filled
+/// with `pad`
+///
+/// Segments before `start` are cut off.
+pub fn flatten(segments: &[Segment], start: u32, pad: u8) -> Vec<u8> {
+    let end = segments
+        .iter()
+        .map(Segment::end)
+        .max()
+        .unwrap_or_default()
+        .max(u64::from(start));
+    let mut data = vec![pad; (end - u64::from(start)) as usize];
+    for segment in segments {
+        let skip = start.saturating_sub(segment.address) as usize;
+        if skip >= segment.data.len() {
+            continue;
+        }
+        let offset = segment.address.saturating_sub(start) as usize;
+        data[offset..offset + segment.data.len() - skip].copy_from_slice(&segment.data[skip..]);
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_format() {
+        assert_eq!(FileFormat::detect(b"\x7fELF\x01\x01"), FileFormat::Elf);
+        assert_eq!(FileFormat::detect(b"\r\n:00000001FF\r\n"), FileFormat::Hex);
+        assert_eq!(FileFormat::detect(b":\xff\x00"), FileFormat::Bin);
+        assert_eq!(FileFormat::detect(b""), FileFormat::Bin);
+    }
+
+    #[test]
+    fn reads_intel_hex() {
+        let text = ":0200000480007A\n\
+                    :04040000112233444E\n\
+                    :0204040055663B\n\
+                    :02100000AABB89\n\
+                    :040000058000300047\n\
+                    :00000001FF\n";
+
+        let file = ImageFile::parse(text.as_bytes(), 0).unwrap();
+
+        assert_eq!(file.format, FileFormat::Hex);
+        assert_eq!(file.entry_point, Some(0x8000_3000));
+        assert_eq!(
+            file.segments,
+            [
+                Segment {
+                    address: 0x8000_0400,
+                    data: vec![0x11, 0x22, 0x33, 0x44, 0x55, 0x66],
+                },
+                Segment {
+                    address: 0x8000_1000,
+                    data: vec![0xAA, 0xBB],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn places_binary_and_flattens() {
+        let file = ImageFile::parse(&[1, 2, 3], 0x8000_0002).unwrap();
+        let segments = [
+            file.segments[0].clone(),
+            Segment {
+                address: 0x8000_0008,
+                data: vec![9],
+            },
+        ];
+
+        assert_eq!(
+            flatten(&segments, 0x8000_0000, 0xFF),
+            [0xFF, 0xFF, 1, 2, 3, 0xFF, 0xFF, 0xFF, 9]
+        );
+        assert_eq!(flatten(&segments, 0x8000_0003, 0), [2, 3, 0, 0, 0, 9]);
+    }
+
+    #[test]
+    fn writes_hex_and_elf() {
+        let file = ImageFile {
+            format: FileFormat::Bin,
+            segments: vec![
+                Segment {
+                    address: 0x8000_FFF8,
+                    data: (0..40).collect(),
+                },
+                Segment {
+                    address: 0x8010_0000,
+                    data: vec![0xA5; 3],
+                },
+            ],
+            entry_point: Some(0x8000_3000),
+        };
+
+        let hex = file.to_hex().unwrap();
+        assert!(hex.starts_with(":020000048000"));
+        assert!(hex.ends_with(":00000001FF\n"));
+        for data in [hex.into_bytes(), file.to_elf().unwrap()] {
+            let parsed = ImageFile::parse(&data, 0).unwrap();
+            assert_eq!(parsed.segments, file.segments);
+            assert_eq!(parsed.entry_point, file.entry_point);
+        }
+    }
+
+    #[test]
+    fn coalesces_adjacent_segments() {
+        let segments = vec![
+            Segment {
+                address: 0,
+                data: vec![1],
+            },
+            Segment {
+                address: 1,
+                data: vec![2],
+            },
+            Segment {
+                address: 3,
+                data: vec![3],
+            },
+        ];
+
+        assert_eq!(
+            coalesce(segments),
+            [
+                Segment {
+                    address: 0,
+                    data: vec![1, 2],
+                },
+                Segment {
+                    address: 3,
+                    data: vec![3],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_data_past_address_space() {
+        let result = ImageFile::parse(&[0; 4], 0xFFFF_FFFE);
+
+        assert!(matches!(
+            result,
+            Err(FileError::AddressOverflow(0xFFFF_FFFE))
+        ));
+    }
+}
@@ -0,0 +1,156 @@
+use std::fmt;
+
+use serde::Serialize;
+use strum::IntoEnumIterator;
+
+use hpm_isp::hid::HpmDevice;
+use hpm_isp::isp_command::{MemoryId, RuntimeEnvironment, Status};
+use hpm_isp::session::Session;
+
+#[derive(Serialize)]
+pub(crate) struct InfoResult {
+    command: &'static str,
+    family: String,
+    usb: UsbEntry,
+    runtime_environment: Vec<RuntimeEnvironmentEntry>,
+    /// Address windows of the ISP protocol, the same for every device and
+    /// not queried from it
+    isp_windows: Vec<MemoryEntry>,
+}
+
+#[derive(Serialize)]
+struct UsbEntry {
+    vendor_id: u16,
+    product_id: u16,
+    manufacturer: Option<String>,
+    product: Option<String>,
+    serial_number: Option<String>,
+}
+
+#[derive(Serialize)]
+struct RuntimeEnvironmentEntry {
+    name: &'static str,
+    /// Raw words of the response
+    words: Option<Vec<u32>>,
+    /// Decoded value, only the last boot status has a documented layout
+    description: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct MemoryEntry {
+    memory: &'static str,
+    id: u32,
+    base_address: u32,
+    window_size: u32,
+}
+
+/// Query everything the BootROM and USB stack tell about the device
+///
+/// Items the BootROM refuses are reported with their error, so one failing
+/// query doesn't hide the others.
+pub(crate) fn query_info(session: &Session<HpmDevice>) -> InfoResult {
+    let device = session.device();
+    let usb = device.usb_info();
+    let runtime_environment = session
+        .info()
+        .runtime_environment
+        .into_iter()
+        .map(|(id, words)| match words {
+            Ok(words) => RuntimeEnvironmentEntry {
+                name: id.as_str(),
+                description: describe(id, &words),
+                words: Some(words),
+                error: None,
+            },
+            Err(e) => RuntimeEnvironmentEntry {
+                name: id.as_str(),
+                words: None,
+                description: None,
+                error: Some(e.to_string()),
+            },
+        })
+        .collect();
+    let isp_windows = MemoryId::iter()
+        .map(|memory_id| MemoryEntry {
+            memory: memory_id.as_str(),
+            id: memory_id as u32,
+            base_address: memory_id.base_address(),
+            window_size: memory_id.window_size(),
+        })
+        .collect();
+
+    InfoResult {
+        command: "info",
+        family: device.family().to_string(),
+        usb: UsbEntry {
+            vendor_id: usb.vendor_id,
+            product_id: usb.product_id,
+            manufacturer: usb.manufacturer,
+            product: usb.product,
+            serial_number: usb.serial_number,
+        },
+        runtime_environment,
+        isp_windows,
+    }
+}
+
+/// Decode the words of a runtime environment item
+///
+/// The BootROM doesn't document the words of the ROM parameters, the active
+/// peripheral and the memory attributes, which take no memory ID, so they are
+/// only printed raw.
+fn describe(id: RuntimeEnvironment, words: &[u32]) -> Option<String> {
+    match (id, words.first()) {
+        (RuntimeEnvironment::LastBootStatus, Some(&status)) => Some(Status(status).to_string()),
+        _ => None,
+    }
+}
+
+impl fmt::Display for InfoResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unknown = |s: &Option<String>| s.clone().unwrap_or_else(|| "unknown".to_string());
+
+        writeln!(f, "Family: {}", self.family)?;
+        writeln!(f, "USB:")?;
+        writeln!(
+            f,
+            "  VID:PID: {:04x}:{:04x}",
+            self.usb.vendor_id, self.usb.product_id
+        )?;
+        writeln!(f, "  Manufacturer: {}", unknown(&self.usb.manufacturer))?;
+        writeln!(f, "  Product: {}", unknown(&self.usb.product))?;
+        writeln!(f, "  Serial number: {}", unknown(&self.usb.serial_number))?;
+
+        writeln!(f, "Runtime environment:")?;
+        for entry in &self.runtime_environment {
+            write!(f, "  {}: ", entry.name)?;
+            match (&entry.words, &entry.error) {
+                (Some(words), _) => {
+                    let words: Vec<String> = words.iter().map(|w| format!("{w:#010x}")).collect();
+                    write!(f, "[{}]", words.join(", "))?;
+                    match &entry.description {
+                        Some(description) => write!(f, " {description}")?,
+                        None => write!(f, " (raw)")?,
+                    }
+                    writeln!(f)?;
+                }
+                (None, Some(error)) => writeln!(f, "error: {error}")?,
+                (None, None) => writeln!(f)?,
+            }
+        }
+
+        write!(f, "ISP memory windows (fixed by the protocol):")?;
+        for entry in &self.isp_windows {
+            write!(
+                f,
+                "\n  {:<5} id {:#07x}: {:#010x}..{:#010x}",
+                entry.memory,
+                entry.id,
+                entry.base_address,
+                entry.base_address as u64 + entry.window_size as u64
+            )?;
+        }
+        Ok(())
+    }
+}
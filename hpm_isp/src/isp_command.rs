@@ -6,20 +6,25 @@ use std::io::{Read, Write};
 use std::path::Path;
 use std::{cmp, error, fmt, io, mem};
 
+use strum::{EnumIter, IntoEnumIterator};
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
+use crate::progress::{NoProgress, Phase, PhaseTracker, Progress};
+
+const VERIFY_CHUNK_SIZE: usize = 0x1_0000;
+
 #[derive(AsBytes, FromZeroes, FromBytes)]
 #[repr(C, packed)]
 pub struct Packet {
-    cmd: u8,
-    arg_num: u8,
-    cmd_type: u8,
+    pub(crate) cmd: u8,
+    pub(crate) arg_num: u8,
+    pub(crate) cmd_type: u8,
     reserved: u8,
-    payload: [u8; 508],
+    pub(crate) payload: [u8; 508],
 }
 
 #[repr(u8)]
-enum Commands {
+pub(crate) enum Commands {
     /// Query runtime environment
     QueryRuntimeEnv = 0x01,
     /// Configure runtime environment
@@ -33,7 +38,7 @@ enum Commands {
 }
 
 #[repr(u8)]
-enum CommandType {
+pub(crate) enum CommandType {
     CommandData = 0x00,
     DataOnly = 0x01,
     ResponseOnly = 0x02,
@@ -65,7 +70,7 @@ impl From<QueryRuntimeEnvironment> for Packet {
     }
 }
 
-#[derive(AsBytes)]
+#[derive(AsBytes, EnumIter, Clone, Copy, Debug, PartialEq, Eq)]
 #[repr(u32)]
 pub enum RuntimeEnvironment {
     RomParameter = 0x00,
@@ -74,20 +79,57 @@ pub enum RuntimeEnvironment {
     MemoryAttribute = 0x04,
 }
 
-struct RomParameter {
-    /// TODO: 完善具体字段
-    none: u8,
+impl RuntimeEnvironment {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RuntimeEnvironment::RomParameter => "rom_parameter",
+            RuntimeEnvironment::ActivePeripheralInfo => "active_peripheral_info",
+            RuntimeEnvironment::LastBootStatus => "last_boot_status",
+            RuntimeEnvironment::MemoryAttribute => "memory_attribute",
+        }
+    }
+}
+
+/// Status code in the BootROM format, `group * 1000 + code`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Status(pub u32);
+
+impl Status {
+    pub fn group(&self) -> u32 {
+        self.0 / 1000
+    }
+
+    pub fn code(&self) -> u32 {
+        self.0 % 1000
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.group(), self.code()) {
+            (0, 0) => write!(f, "success"),
+            (0, 1) => write!(f, "fail"),
+            (0, 2) => write!(f, "invalid argument"),
+            (0, 3) => write!(f, "timeout"),
+            (group, code) => write!(f, "group {group}, code {code}"),
+        }?;
+        write!(f, " ({})", self.0)
+    }
 }
 
 #[derive(AsBytes)]
 #[repr(C, packed)]
-struct ConfigureMemory {
+pub(crate) struct ConfigureMemory {
     memory_id: u32,
     cfg_addr: u32,
 }
 
 impl ConfigureMemory {
-    fn new(cfg_addr: u32, memory_id: MemoryId) -> Self {
+    pub(crate) fn new(cfg_addr: u32, memory_id: MemoryId) -> Self {
         ConfigureMemory {
             memory_id: memory_id as u32,
             cfg_addr,
@@ -111,14 +153,14 @@ impl From<ConfigureMemory> for Packet {
 
 #[derive(AsBytes)]
 #[repr(C, packed)]
-struct WriteMemory {
+pub(crate) struct WriteMemory {
     start: u32,
     length: u32,
     memory_id: u32,
 }
 
 impl WriteMemory {
-    fn new(start: u32, length: u32, memory_id: MemoryId) -> Self {
+    pub(crate) fn new(start: u32, length: u32, memory_id: MemoryId) -> Self {
         WriteMemory {
             start,
             length,
@@ -143,14 +185,14 @@ impl From<WriteMemory> for Packet {
 
 #[derive(AsBytes)]
 #[repr(C, packed)]
-struct ReadMemory {
+pub(crate) struct ReadMemory {
     start: u32,
     length: u32,
     memory_id: u32,
 }
 
 impl ReadMemory {
-    fn new(start: u32, length: u32, memory_id: MemoryId) -> Self {
+    pub(crate) fn new(start: u32, length: u32, memory_id: MemoryId) -> Self {
         ReadMemory {
             start,
             length,
@@ -173,7 +215,11 @@ impl From<ReadMemory> for Packet {
     }
 }
 
-#[derive(Copy, Clone)]
+/// Memories accessible through the BootROM ISP protocol
+///
+/// OTP is not one of them: the protocol has no command to read or program
+/// fuses, which is left to firmware running on the chip.
+#[derive(EnumIter, Copy, Clone, Debug, PartialEq, Eq)]
 #[repr(u32)]
 pub enum MemoryId {
     ILM = 0x00,
@@ -184,6 +230,15 @@ pub enum MemoryId {
 }
 
 impl MemoryId {
+    /// Memory whose window holds an absolute address
+    pub fn from_address(address: u32) -> Option<MemoryId> {
+        MemoryId::iter().find(|memory_id| {
+            address
+                .checked_sub(memory_id.base_address())
+                .is_some_and(|offset| offset < memory_id.window_size())
+        })
+    }
+
     pub fn base_address(&self) -> u32 {
         match self {
             MemoryId::ILM => 0x0000_0000,
@@ -193,12 +248,62 @@ impl MemoryId {
             MemoryId::XPI1 => 0x9000_0000,
         }
     }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MemoryId::ILM => "ILM",
+            MemoryId::DLM => "DLM",
+            MemoryId::XRAM => "XRAM",
+            MemoryId::XPI0 => "XPI0",
+            MemoryId::XPI1 => "XPI1",
+        }
+    }
+
+    /// Size of the address window mapped to this memory
+    pub fn window_size(&self) -> u32 {
+        match self {
+            MemoryId::ILM => 0x0008_0000,
+            MemoryId::DLM => 0x0008_0000,
+            MemoryId::XRAM => 0x0010_0000,
+            MemoryId::XPI0 => 0x1000_0000,
+            MemoryId::XPI1 => 0x1000_0000,
+        }
+    }
+
+    /// Convert an absolute address inside the window of this memory to an
+    /// offset, values below the window size are taken as an offset already
+    ///
+    /// Fails with [`Error::OutOfRange`] for any other value, e.g. an address
+    /// of another memory.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// assert_eq!(MemoryId::XPI0.to_offset(0x8000_0400)?, 0x400);
+    /// assert_eq!(MemoryId::XPI0.to_offset(0x400)?, 0x400);
+    /// assert!(MemoryId::XPI0.to_offset(0x9000_0400).is_err());
+    /// ```
+    pub fn to_offset(&self, address: u32) -> Result<u32, Error> {
+        if address < self.window_size() {
+            return Ok(address);
+        }
+        match address.checked_sub(self.base_address()) {
+            Some(offset) if offset < self.window_size() => Ok(offset),
+            _ => Err(Error::OutOfRange(address)),
+        }
+    }
+}
+
+impl fmt::Display for MemoryId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
 }
 
 #[derive(FromZeroes, FromBytes)]
 #[repr(C, packed)]
-struct GenericCommandResponse {
-    status: u32,
+pub(crate) struct GenericCommandResponse {
+    pub(crate) status: u32,
 }
 
 impl From<GenericCommandResponse> for Result<(), Error> {
@@ -214,28 +319,52 @@ impl From<GenericCommandResponse> for Result<(), Error> {
 pub trait Interface {
     fn write(&self, packet: &Packet, length: u16) -> Result<(), Error>;
     fn read(&self, packet: &mut Packet) -> Result<u16, Error>;
+    /// Abort the running transfer
+    ///
+    /// Transports which can't notify the device keep the default, which does
+    /// nothing.
+    fn abort(&self) -> Result<(), Error> {
+        Ok(())
+    }
 }
 
-fn write_from_reader<D, R, F>(
+fn abort_transfer<D>(device: &D) -> Error
+where
+    D: Interface + ?Sized,
+{
+    match device.abort() {
+        Ok(()) => Error::Aborted,
+        Err(e) => e,
+    }
+}
+
+/// Absolute address and length of `length` bytes at `offset` of the memory,
+/// which have to stay inside its window
+fn window_range(memory_id: MemoryId, offset: u32, length: usize) -> Result<(u32, u32), Error> {
+    if u64::from(offset) + length as u64 > u64::from(memory_id.window_size()) {
+        return Err(Error::OutOfRange(offset));
+    }
+    // The window ends at the latest at 4 GiB, so neither overflows
+    Ok((memory_id.base_address() + offset, length as u32))
+}
+
+fn write_stream<D, R, P>(
     device: &D,
     memory_id: MemoryId,
     offset: u32,
     total_length: usize,
     mut reader: R,
-    update_progress: F,
+    mut progress: P,
 ) -> Result<(), Error>
 where
     D: Interface + ?Sized,
     R: Read,
-    F: Fn(usize, usize),
+    P: Progress,
 {
+    let tracker = PhaseTracker::new(Phase::Write, total_length);
     let command_length = mem::size_of::<WriteMemory>();
-    let mut packet: Packet = WriteMemory::new(
-        offset + memory_id.base_address(),
-        total_length as u32,
-        memory_id,
-    )
-    .into();
+    let (address, length) = window_range(memory_id, offset, total_length)?;
+    let mut packet: Packet = WriteMemory::new(address, length, memory_id).into();
 
     if total_length == 0 {
         device.write(&packet, command_length as u16)?;
@@ -253,7 +382,9 @@ where
         reader.read_exact(&mut packet.payload[payload_offset..payload_offset + write_length])?;
         device.write(&packet, (payload_offset + write_length) as u16)?;
         bytes_written += write_length;
-        update_progress(bytes_written, total_length);
+        if tracker.report(&mut progress, bytes_written).is_break() {
+            return Err(abort_transfer(device));
+        }
 
         packet.arg_num = 0;
         packet.cmd_type = CommandType::DataOnly as u8;
@@ -266,25 +397,22 @@ where
     resp.into()
 }
 
-fn read_to_writer<D, W, F>(
+fn read_stream<D, W, P>(
     device: &D,
     memory_id: MemoryId,
     offset: u32,
     total_length: usize,
     mut writer: W,
-    update_progress: F,
+    mut progress: P,
 ) -> Result<(), Error>
 where
     D: Interface + ?Sized,
     W: Write,
-    F: Fn(usize, usize),
+    P: Progress,
 {
-    let mut packet: Packet = ReadMemory::new(
-        offset + memory_id.base_address(),
-        total_length as u32,
-        memory_id,
-    )
-    .into();
+    let tracker = PhaseTracker::new(Phase::Read, total_length);
+    let (address, length) = window_range(memory_id, offset, total_length)?;
+    let mut packet: Packet = ReadMemory::new(address, length, memory_id).into();
     let mut bytes_read = 0;
 
     device.write(&packet, mem::size_of::<ReadMemory>() as u16)?;
@@ -302,20 +430,35 @@ where
 
         writer.write_all(&packet.payload[..length])?;
         bytes_read += length;
-        update_progress(bytes_read, total_length);
+        if tracker.report(&mut progress, bytes_read).is_break() {
+            return Err(abort_transfer(device));
+        }
     }
 
     Ok(())
 }
 
 pub trait IspCommand: Interface {
-    fn query_runtime_environment(&self, id: RuntimeEnvironment) -> Result<(), Error> {
+    /// Query runtime environment of the BootROM
+    ///
+    /// Returns the little-endian words following the status of the response.
+    fn query_runtime_environment(&self, id: RuntimeEnvironment) -> Result<Vec<u32>, Error> {
         let mut packet: Packet = QueryRuntimeEnvironment::new(id).into();
         self.write(&packet, mem::size_of::<QueryRuntimeEnvironment>() as u16)?;
-        self.read(&mut packet)?;
-        todo!("data matching and error handling");
-        Ok(())
+        let length = self.read(&mut packet)? as usize;
+        let status_length = mem::size_of::<GenericCommandResponse>();
+        if length < status_length || length > packet.payload.len() {
+            return Err(Error::TransferError);
+        }
+
+        let resp = GenericCommandResponse::read_from_prefix(&packet.payload[..]).unwrap();
+        Result::<(), Error>::from(resp)?;
+        Ok(packet.payload[status_length..length]
+            .chunks_exact(mem::size_of::<u32>())
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect())
     }
+
     /// Configure memory, using configuration block in RAM
     ///
     /// # Arguments
@@ -336,69 +479,153 @@ pub trait IspCommand: Interface {
         resp.into()
     }
 
-    fn write_memory<F>(
+    fn write_memory<P>(
         &self,
         memory_id: MemoryId,
         offset: u32,
         data: &[u8],
-        update_progress: F,
+        progress: P,
     ) -> Result<(), Error>
     where
-        F: Fn(usize, usize),
+        P: Progress,
     {
-        write_from_reader(self, memory_id, offset, data.len(), data, update_progress)
+        write_stream(self, memory_id, offset, data.len(), data, progress)
     }
 
-    fn read_memory<F>(
+    fn read_memory<P>(
         &self,
         memory_id: MemoryId,
         offset: u32,
         data: &mut [u8],
-        update_progress: F,
+        progress: P,
+    ) -> Result<(), Error>
+    where
+        P: Progress,
+    {
+        read_stream(self, memory_id, offset, data.len(), data, progress)
+    }
+
+    /// Write data from a reader
+    ///
+    /// Exactly `length` bytes are read when the length is known. Otherwise the
+    /// reader is read to its end before writing, since the length of a write
+    /// must be sent to the device in advance.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// device.write_from_reader(io::stdin().lock(), None, MemoryId::XPI0, 0x400, NoProgress);
+    /// ```
+    fn write_from_reader<R, P>(
+        &self,
+        mut reader: R,
+        length: Option<usize>,
+        memory_id: MemoryId,
+        offset: u32,
+        progress: P,
     ) -> Result<(), Error>
     where
-        F: Fn(usize, usize),
+        R: Read,
+        P: Progress,
     {
-        read_to_writer(self, memory_id, offset, data.len(), data, update_progress)
+        match length {
+            Some(length) => write_stream(self, memory_id, offset, length, reader, progress),
+            None => {
+                let mut data = Vec::new();
+                reader.read_to_end(&mut data)?;
+                self.write_memory(memory_id, offset, &data, progress)
+            }
+        }
     }
 
-    fn write_file<P, F>(
+    /// Read `total_length` bytes into a writer
+    fn read_to_writer<W, P>(
+        &self,
+        writer: W,
+        memory_id: MemoryId,
+        offset: u32,
+        total_length: usize,
+        progress: P,
+    ) -> Result<(), Error>
+    where
+        W: Write,
+        P: Progress,
+    {
+        read_stream(self, memory_id, offset, total_length, writer, progress)
+    }
+
+    fn write_file<P, Q>(
         &self,
         path: P,
         memory_id: MemoryId,
         offset: u32,
-        update_progress: F,
+        progress: Q,
     ) -> Result<(), Error>
     where
         P: AsRef<Path>,
-        F: Fn(usize, usize),
+        Q: Progress,
     {
         let file = File::open(path)?;
         let file_info = file.metadata()?;
-        write_from_reader(
-            self,
+        self.write_from_reader(
+            file,
+            Some(file_info.len() as usize),
             memory_id,
             offset,
-            file_info.len() as usize,
-            file,
-            update_progress,
+            progress,
         )
     }
 
-    fn read_file<P, F>(
+    fn read_file<P, Q>(
         &self,
         path: P,
         memory_id: MemoryId,
         offset: u32,
         total_length: usize,
-        update_progress: F,
+        progress: Q,
     ) -> Result<(), Error>
     where
         P: AsRef<Path>,
-        F: Fn(usize, usize),
+        Q: Progress,
     {
         let file = File::create(path)?;
-        read_to_writer(self, memory_id, offset, total_length, file, update_progress)
+        self.read_to_writer(file, memory_id, offset, total_length, progress)
+    }
+
+    /// Read back memory and compare it with `data`
+    ///
+    /// Fails with [`Error::VerifyFailed`] carrying the offset of the first
+    /// mismatching byte.
+    fn verify_memory<P>(
+        &self,
+        memory_id: MemoryId,
+        offset: u32,
+        data: &[u8],
+        mut progress: P,
+    ) -> Result<(), Error>
+    where
+        P: Progress,
+    {
+        window_range(memory_id, offset, data.len())?;
+        let tracker = PhaseTracker::new(Phase::Verify, data.len());
+        let mut buffer = vec![0u8; cmp::min(VERIFY_CHUNK_SIZE, data.len())];
+        let mut bytes_verified = 0;
+
+        for expected in data.chunks(VERIFY_CHUNK_SIZE) {
+            let actual = &mut buffer[..expected.len()];
+            let chunk_offset = offset + bytes_verified as u32;
+            self.read_memory(memory_id, chunk_offset, actual, NoProgress)?;
+            if let Some(i) = expected.iter().zip(actual.iter()).position(|(e, a)| e != a) {
+                return Err(Error::VerifyFailed(chunk_offset + i as u32));
+            }
+
+            bytes_verified += expected.len();
+            if tracker.report(&mut progress, bytes_verified).is_break() {
+                return Err(Error::Aborted);
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -407,6 +634,11 @@ pub enum Error {
     Nak,
     TransferError,
     Timeout,
+    Aborted,
+    /// Address outside of the window of the memory
+    OutOfRange(u32),
+    /// Read back data differs, at the given offset
+    VerifyFailed(u32),
     IoError(io::Error),
     Other(u32),
 }
@@ -417,16 +649,35 @@ impl Error {
             Error::Nak => "negative acknowledge",
             Error::TransferError => "transfer error",
             Error::Timeout => "timeout",
+            Error::Aborted => "aborted by host",
+            Error::OutOfRange(_) => "address out of range",
+            Error::VerifyFailed(_) => "verify failed",
             Error::IoError(_) => "io error",
             Error::Other(_) => "other error",
         }
     }
+
+    /// Stable identifier of the error kind, for machine-readable output
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Nak => "nak",
+            Error::TransferError => "transfer_error",
+            Error::Timeout => "timeout",
+            Error::Aborted => "aborted",
+            Error::OutOfRange(_) => "out_of_range",
+            Error::VerifyFailed(_) => "verify_failed",
+            Error::IoError(_) => "io_error",
+            Error::Other(_) => "device_status",
+        }
+    }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match &self {
             Error::IoError(e) => write!(f, "{}: {}", self.as_str(), e),
+            Error::VerifyFailed(offset) => write!(f, "{} at offset {:#x}", self.as_str(), offset),
+            Error::OutOfRange(address) => write!(f, "{} at {:#010x}", self.as_str(), address),
             Error::Other(code) => write!(f, "{}: {}", self.as_str(), code),
             _ => write!(f, "{}", self.as_str()),
         }
@@ -444,3 +695,206 @@ impl From<io::Error> for Error {
         Error::IoError(e)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::collections::VecDeque;
+    use std::ops::ControlFlow;
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::progress::ProgressEvent;
+
+    #[derive(Default)]
+    struct MockDevice {
+        writes: RefCell<Vec<Vec<u8>>>,
+        responses: RefCell<VecDeque<Vec<u8>>>,
+        aborted: Cell<bool>,
+    }
+
+    impl Interface for MockDevice {
+        fn write(&self, packet: &Packet, length: u16) -> Result<(), Error> {
+            self.writes
+                .borrow_mut()
+                .push(packet.payload[..length as usize].to_vec());
+            Ok(())
+        }
+
+        fn read(&self, packet: &mut Packet) -> Result<u16, Error> {
+            *packet = Packet::new_zeroed();
+            match self.responses.borrow_mut().pop_front() {
+                Some(response) => {
+                    packet.payload[..response.len()].copy_from_slice(&response);
+                    Ok(response.len() as u16)
+                }
+                None => Ok(mem::size_of::<GenericCommandResponse>() as u16),
+            }
+        }
+
+        fn abort(&self) -> Result<(), Error> {
+            self.aborted.set(true);
+            Ok(())
+        }
+    }
+
+    impl IspCommand for MockDevice {}
+
+    #[test]
+    fn converts_absolute_addresses_to_offsets() {
+        assert_eq!(MemoryId::XPI0.to_offset(0x8000_0400).unwrap(), 0x400);
+        assert_eq!(MemoryId::XPI0.to_offset(0x400).unwrap(), 0x400);
+        assert_eq!(MemoryId::XPI1.to_offset(0x9001_0000).unwrap(), 0x1_0000);
+        assert_eq!(MemoryId::DLM.to_offset(0x0008_0010).unwrap(), 0x10);
+    }
+
+    #[test]
+    fn rejects_addresses_outside_of_window() {
+        for (memory_id, address) in [
+            (MemoryId::XPI0, 0x9000_0400),
+            (MemoryId::XPI1, 0x8000_0400),
+            (MemoryId::DLM, 0x0010_0000),
+        ] {
+            assert!(matches!(
+                memory_id.to_offset(address),
+                Err(Error::OutOfRange(a)) if a == address
+            ));
+        }
+
+        let device = MockDevice::default();
+        let result = device.write_memory(MemoryId::XPI0, 0x9000_0400, &[0; 4], NoProgress);
+        assert!(matches!(result, Err(Error::OutOfRange(0x9000_0400))));
+
+        // Running past the end of the window would reach the next memory
+        let result = device.write_memory(MemoryId::XPI0, 0x0FFF_FFFE, &[0; 4], NoProgress);
+        assert!(matches!(result, Err(Error::OutOfRange(0x0FFF_FFFE))));
+        let result = device.read_memory(MemoryId::ILM, 0x8_0000, &mut [0; 4], NoProgress);
+        assert!(matches!(result, Err(Error::OutOfRange(0x8_0000))));
+        let result = device.verify_memory(MemoryId::DLM, 0x7_FFFF, &[0; 2], NoProgress);
+        assert!(matches!(result, Err(Error::OutOfRange(0x7_FFFF))));
+        assert!(device.writes.borrow().is_empty());
+        // The last bytes of the window are sent, the mock has no answer
+        let _ = device.write_memory(MemoryId::XPI0, 0x0FFF_FFFC, &[0; 4], NoProgress);
+        assert!(!device.writes.borrow().is_empty());
+    }
+
+    #[test]
+    fn queries_runtime_environment() {
+        let device = MockDevice::default();
+        let response = [0u32, 0x1234, 0xCAFE_0001]
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect();
+        device.responses.borrow_mut().push_back(response);
+
+        let words = device
+            .query_runtime_environment(RuntimeEnvironment::LastBootStatus)
+            .unwrap();
+
+        assert_eq!(words, vec![0x1234, 0xCAFE_0001]);
+        assert_eq!(device.writes.borrow()[0], vec![0x03, 0, 0, 0]);
+    }
+
+    #[test]
+    fn rejects_failed_runtime_environment_query() {
+        let device = MockDevice::default();
+        device
+            .responses
+            .borrow_mut()
+            .push_back(2u32.to_le_bytes().to_vec());
+
+        let result = device.query_runtime_environment(RuntimeEnvironment::RomParameter);
+
+        assert!(matches!(result, Err(Error::Other(2))));
+    }
+
+    #[test]
+    fn describes_status() {
+        assert_eq!(Status(0).to_string(), "success (0)");
+        assert_eq!(Status(2).to_string(), "invalid argument (2)");
+        assert_eq!(Status(5003).to_string(), "group 5, code 3 (5003)");
+    }
+
+    #[test]
+    fn reports_write_progress() {
+        let device = MockDevice::default();
+        let mut events = Vec::new();
+
+        device
+            .write_memory(MemoryId::ILM, 0, &[0xA5; 1200], |event: &ProgressEvent| {
+                events.push(*event);
+                ControlFlow::Continue(())
+            })
+            .unwrap();
+
+        assert_eq!(device.writes.borrow().len(), 3);
+        assert_eq!(events.len(), 3);
+        assert!(events.iter().all(|event| event.phase == Phase::Write));
+        assert_eq!(events.last().unwrap().bytes, 1200);
+        assert_eq!(events.last().unwrap().total, 1200);
+        assert!(!device.aborted.get());
+    }
+
+    #[test]
+    fn writes_reader_of_unknown_length() {
+        let device = MockDevice::default();
+        let mut last_event = None;
+
+        device
+            .write_from_reader(
+                &[0xA5; 1200][..],
+                None,
+                MemoryId::ILM,
+                0,
+                |event: &ProgressEvent| {
+                    last_event = Some(*event);
+                    ControlFlow::Continue(())
+                },
+            )
+            .unwrap();
+
+        let written: Vec<u8> = device.writes.borrow().concat();
+        assert_eq!(written.len(), mem::size_of::<WriteMemory>() + 1200);
+        assert_eq!(&written[4..8], &1200u32.to_le_bytes());
+        assert_eq!(last_event.unwrap().total, 1200);
+    }
+
+    #[test]
+    fn verifies_memory() {
+        let device = MockDevice::default();
+        let mut data = [0u8; 64];
+
+        device
+            .verify_memory(MemoryId::XPI0, 0x400, &data, NoProgress)
+            .unwrap();
+
+        data[9] = 0xA5;
+        let result = device.verify_memory(MemoryId::XPI0, 0x400, &data, NoProgress);
+        assert!(matches!(result, Err(Error::VerifyFailed(0x409))));
+    }
+
+    #[test]
+    fn aborts_write_on_break() {
+        let device = MockDevice::default();
+
+        let result = device.write_memory(MemoryId::ILM, 0, &[0xA5; 1200], |_: &ProgressEvent| {
+            ControlFlow::Break(())
+        });
+
+        assert!(matches!(result, Err(Error::Aborted)));
+        assert!(device.aborted.get());
+        assert_eq!(device.writes.borrow().len(), 1);
+    }
+
+    #[test]
+    fn aborts_write_when_receiver_is_dropped() {
+        let device = MockDevice::default();
+        let (tx, rx) = mpsc::channel();
+        drop(rx);
+
+        let result = device.write_memory(MemoryId::ILM, 0, &[0xA5; 1200], tx);
+
+        assert!(matches!(result, Err(Error::Aborted)));
+        assert!(device.aborted.get());
+    }
+}
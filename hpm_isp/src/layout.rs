@@ -0,0 +1,852 @@
+//! Layout of firmware files in the memory map
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use clap::ArgEnum;
+use serde::Serialize;
+
+use hpm_isp::boot_image::{
+    BootHeader, FwInfo, ParsedBootImage, APP_OFFSET, BOOT_HEADER_OFFSET, NOR_CFG_OPTION_OFFSET,
+    NOR_CFG_OPTION_TAG,
+};
+use hpm_isp::hid::Family;
+use hpm_isp::image_file::{coalesce, flatten, FileFormat, ImageFile, Segment};
+use hpm_isp::isp_command::MemoryId;
+use hpm_isp::memory_config::MemoryConfig;
+
+use crate::output::CodedError;
+use crate::parse::parse_number;
+
+/// Firmware file given on the command line
+pub(crate) struct ImageInput {
+    pub(crate) path: PathBuf,
+    /// Offset or absolute address of a raw binary
+    pub(crate) address: Option<u32>,
+}
+
+/// Parse an input given as `PATH` or `PATH@OFFSET`, e.g. `app.bin@0x3000`
+pub(crate) fn parse_input(s: &str) -> Result<ImageInput, String> {
+    match s.rsplit_once('@') {
+        Some((path, address)) if !path.is_empty() => Ok(ImageInput {
+            path: PathBuf::from(path),
+            address: Some(parse_number(address)?),
+        }),
+        _ => Ok(ImageInput {
+            path: PathBuf::from(s),
+            address: None,
+        }),
+    }
+}
+
+/// Parse a pad byte, e.g. `0xff`
+pub(crate) fn parse_byte(s: &str) -> Result<u8, String> {
+    let value = parse_number(s)?;
+    u8::try_from(value).map_err(|_| format!("{value:#x} doesn't fit in a byte"))
+}
+
+/// Read an input, raw binaries are placed in `xpi` at their offset, 0 by
+/// default
+pub(crate) fn load_input(input: &ImageInput, xpi: MemoryId) -> Result<ImageFile, Box<dyn Error>> {
+    let data = fs::read(&input.path)?;
+    let offset = xpi.to_offset(input.address.unwrap_or(0))?;
+    let address = xpi.base_address().checked_add(offset).ok_or_else(|| {
+        CodedError::new(
+            "out_of_range",
+            format!("{offset:#x} is outside of the {xpi} address space"),
+        )
+    })?;
+    let file =
+        ImageFile::parse(&data, address).map_err(|e| format!("{}: {e}", input.path.display()))?;
+    if file.format != FileFormat::Bin && input.address.is_some() {
+        return Err(CodedError::new(
+            "invalid_argument",
+            format!(
+                "{}: {} files carry their addresses, only raw binaries take @OFFSET",
+                input.path.display(),
+                file.format.as_str()
+            ),
+        )
+        .into());
+    }
+    Ok(file)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum WarningKind {
+    /// Two segments write the same addresses
+    Overlap,
+    /// Data outside of the memory map or the flash
+    OutOfRange,
+    /// Unwritten bytes between two segments of a memory
+    Gap,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct LayoutWarning {
+    pub(crate) kind: WarningKind,
+    pub(crate) message: String,
+}
+
+/// Check segments of several files for overlaps, gaps and data outside of
+/// the memory map of `family`, or beyond `flash_size` in the XPI flashes
+///
+/// Without a family, the memories are as large as their ISP windows, see
+/// `info`.
+pub(crate) fn check_layout(
+    files: &[(&Path, &[Segment])],
+    family: Option<Family>,
+    flash_size: Option<u32>,
+) -> Vec<LayoutWarning> {
+    let mut warnings = Vec::new();
+    let mut segments: Vec<(&Path, &Segment)> = files
+        .iter()
+        .flat_map(|(path, segments)| segments.iter().map(move |segment| (*path, segment)))
+        .collect();
+    segments.sort_by_key(|(_, segment)| segment.address);
+
+    for (path, segment) in &segments {
+        let memory_id = MemoryId::from_address(segment.address);
+        let limit = memory_id.map(|memory_id| match (memory_id, family) {
+            (MemoryId::XPI0 | MemoryId::XPI1, _) => {
+                Some(flash_size.unwrap_or(memory_id.window_size()))
+            }
+            (_, Some(family)) => family.memory_size(memory_id),
+            (_, None) => Some(memory_id.window_size()),
+        });
+        let message = match (memory_id, limit) {
+            (Some(memory_id), Some(None)) => format!(
+                "{}: {:#x} bytes at {:#010x} are in {memory_id}, which {} doesn't have",
+                path.display(),
+                segment.data.len(),
+                segment.address,
+                family.map(|family| family.to_string()).unwrap_or_default()
+            ),
+            (Some(memory_id), Some(Some(limit)))
+                if segment.end() > u64::from(memory_id.base_address()) + u64::from(limit) =>
+            {
+                format!(
+                    "{}: {:#x} bytes at {:#010x} run past the end of {memory_id} at {:#010x}",
+                    path.display(),
+                    segment.data.len(),
+                    segment.address,
+                    u64::from(memory_id.base_address()) + u64::from(limit),
+                )
+            }
+            (Some(_), _) => continue,
+            (None, _) => format!(
+                "{}: {:#x} bytes at {:#010x} are outside of the memory map",
+                path.display(),
+                segment.data.len(),
+                segment.address
+            ),
+        };
+        warnings.push(LayoutWarning {
+            kind: WarningKind::OutOfRange,
+            message,
+        });
+    }
+
+    // The segment reaching furthest so far, overlaps and gaps are against it
+    let mut furthest: Option<(&Path, &Segment)> = None;
+    for &(path, segment) in &segments {
+        if let Some((last_path, last)) = furthest {
+            let start = u64::from(segment.address);
+            if start < last.end() {
+                warnings.push(LayoutWarning {
+                    kind: WarningKind::Overlap,
+                    message: format!(
+                        "{} and {} overlap at {:#010x}..{:#010x}",
+                        last_path.display(),
+                        path.display(),
+                        segment.address,
+                        last.end().min(segment.end())
+                    ),
+                });
+            } else if start > last.end()
+                && MemoryId::from_address(last.address) == MemoryId::from_address(segment.address)
+            {
+                warnings.push(LayoutWarning {
+                    kind: WarningKind::Gap,
+                    message: format!(
+                        "{:#x} bytes unwritten at {:#010x}..{:#010x}",
+                        start - last.end(),
+                        last.end(),
+                        segment.address
+                    ),
+                });
+            }
+            if segment.end() <= last.end() {
+                continue;
+            }
+        }
+        furthest = Some((path, segment));
+    }
+    warnings
+}
+
+#[derive(Serialize)]
+pub(crate) struct SegmentEntry {
+    address: u32,
+    size: usize,
+    memory: Option<&'static str>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct FirmwareEntry {
+    /// Offset in the XPI flash
+    offset: u32,
+    size: u32,
+    load_addr: u32,
+    entry_point: u32,
+}
+
+#[derive(Serialize)]
+pub(crate) struct BootHeaderEntry {
+    hashes_verified: bool,
+    firmware: Vec<FirmwareEntry>,
+}
+
+/// Boot area of a file with data in the first 12 KiB of an XPI flash
+#[derive(Serialize)]
+pub(crate) struct BootEntry {
+    memory: &'static str,
+    /// Whether a valid XPI NOR configuration option is at 0x400
+    nor_cfg_option: bool,
+    boot_header: Option<BootHeaderEntry>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct FileEntry {
+    path: PathBuf,
+    format: &'static str,
+    entry_point: Option<u32>,
+    segments: Vec<SegmentEntry>,
+    boot: Option<BootEntry>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ImageInfoResult {
+    command: &'static str,
+    files: Vec<FileEntry>,
+    warnings: Vec<LayoutWarning>,
+}
+
+/// `length` bytes at `address`, with the bytes no segment covers set to `pad`
+fn read_range(segments: &[Segment], address: u64, length: usize, pad: u8) -> Vec<u8> {
+    let end = address + length as u64;
+    let mut data = vec![pad; length];
+    for segment in segments {
+        let start = u64::from(segment.address).max(address);
+        let stop = segment.end().min(end);
+        if start < stop {
+            let from = (start - u64::from(segment.address)) as usize;
+            let to = (start - address) as usize;
+            let length = (stop - start) as usize;
+            data[to..to + length].copy_from_slice(&segment.data[from..from + length]);
+        }
+    }
+    data
+}
+
+/// Look for the XPI NOR configuration option and the boot header in the XPI
+/// whose boot area the file writes
+///
+/// Only the boot area, the firmware info tables and the firmware are read, so
+/// data far into the flash doesn't cost a copy of the whole flash.
+fn boot_entry(file: &ImageFile) -> Option<BootEntry> {
+    [MemoryId::XPI0, MemoryId::XPI1]
+        .into_iter()
+        .find_map(|memory_id| {
+            let base = memory_id.base_address();
+            let boot_area = u64::from(base)..u64::from(base) + APP_OFFSET as u64;
+            let segments: Vec<Segment> = file
+                .segments
+                .iter()
+                .filter(|segment| MemoryId::from_address(segment.address) == Some(memory_id))
+                .cloned()
+                .collect();
+            if !segments
+                .iter()
+                .any(|segment| boot_area.contains(&u64::from(segment.address)))
+            {
+                return None;
+            }
+
+            // Boot header with the largest firmware info table it may have
+            let header_end = BOOT_HEADER_OFFSET
+                + mem::size_of::<BootHeader>()
+                + usize::from(u8::MAX) * mem::size_of::<FwInfo>();
+            let image = read_range(&segments, u64::from(base), header_end, 0xFF);
+            let tag = u16::from_le_bytes([
+                image[NOR_CFG_OPTION_OFFSET + 2],
+                image[NOR_CFG_OPTION_OFFSET + 3],
+            ]);
+            let data_end = segments.iter().map(Segment::end).max().unwrap_or_default();
+            let boot_header = ParsedBootImage::parse(&image).ok().map(|parsed| {
+                let firmware = parsed
+                    .fw_info
+                    .iter()
+                    .map(|fw_info| FirmwareEntry {
+                        offset: BOOT_HEADER_OFFSET as u32 + fw_info.offset,
+                        size: fw_info.size,
+                        load_addr: fw_info.load_addr,
+                        entry_point: fw_info.entry_point,
+                    })
+                    .collect();
+                let hashes_verified = parsed.fw_info.iter().enumerate().all(|(index, fw_info)| {
+                    let start =
+                        u64::from(base) + BOOT_HEADER_OFFSET as u64 + u64::from(fw_info.offset);
+                    // Firmware past the end of the file isn't in it
+                    start + u64::from(fw_info.size) <= data_end
+                        && parsed
+                            .verify_firmware(
+                                index,
+                                &read_range(&segments, start, fw_info.size as usize, 0xFF),
+                            )
+                            .is_ok()
+                });
+                BootHeaderEntry {
+                    hashes_verified,
+                    firmware,
+                }
+            });
+            Some(BootEntry {
+                memory: memory_id.as_str(),
+                nor_cfg_option: tag == NOR_CFG_OPTION_TAG,
+                boot_header,
+            })
+        })
+}
+
+/// Describe the files and check their layout against each other
+pub(crate) fn image_info(
+    inputs: &[ImageInput],
+    xpi: MemoryId,
+    family: Option<Family>,
+    flash_size: Option<u32>,
+) -> Result<ImageInfoResult, Box<dyn Error>> {
+    let files = inputs
+        .iter()
+        .map(|input| load_input(input, xpi))
+        .collect::<Result<Vec<_>, _>>()?;
+    let layout: Vec<(&Path, &[Segment])> = inputs
+        .iter()
+        .zip(&files)
+        .map(|(input, file)| (input.path.as_path(), file.segments.as_slice()))
+        .collect();
+    let warnings = check_layout(&layout, family, flash_size);
+
+    let files = inputs
+        .iter()
+        .zip(&files)
+        .map(|(input, file)| {
+            let boot = boot_entry(file);
+            let entry_point = file.entry_point.or_else(|| {
+                let firmware = boot.as_ref()?.boot_header.as_ref()?.firmware.first()?;
+                Some(firmware.entry_point)
+            });
+            FileEntry {
+                path: input.path.clone(),
+                format: file.format.as_str(),
+                entry_point,
+                segments: file
+                    .segments
+                    .iter()
+                    .map(|segment| SegmentEntry {
+                        address: segment.address,
+                        size: segment.data.len(),
+                        memory: MemoryId::from_address(segment.address).map(|id| id.as_str()),
+                    })
+                    .collect(),
+                boot,
+            }
+        })
+        .collect();
+
+    Ok(ImageInfoResult {
+        command: "image info",
+        files,
+        warnings,
+    })
+}
+
+impl fmt::Display for ImageInfoResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, file) in self.files.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{} ({})", file.path.display(), file.format)?;
+            if let Some(entry_point) = file.entry_point {
+                write!(f, ", entry point {entry_point:#010x}")?;
+            }
+            for segment in &file.segments {
+                write!(
+                    f,
+                    "\n  {:#010x}..{:#010x}  {:>10} bytes  {}",
+                    segment.address,
+                    u64::from(segment.address) + segment.size as u64,
+                    segment.size,
+                    segment.memory.unwrap_or("-")
+                )?;
+            }
+            let Some(boot) = &file.boot else {
+                continue;
+            };
+            write!(
+                f,
+                "\n  {} NOR config option at 0x400: {}",
+                boot.memory,
+                if boot.nor_cfg_option {
+                    "valid"
+                } else {
+                    "missing"
+                }
+            )?;
+            match &boot.boot_header {
+                Some(header) => {
+                    write!(
+                        f,
+                        "\n  Boot header: {}",
+                        if header.hashes_verified {
+                            "firmware hashes match"
+                        } else {
+                            "firmware hashes don't match"
+                        }
+                    )?;
+                    for firmware in &header.firmware {
+                        write!(
+                            f,
+                            "\n    firmware at {:#x}, {} bytes, loaded to {:#010x}, entry point {:#010x}",
+                            firmware.offset, firmware.size, firmware.load_addr, firmware.entry_point
+                        )?;
+                    }
+                }
+                None => write!(f, "\n  Boot header: missing")?,
+            }
+        }
+        for warning in &self.warnings {
+            write!(f, "\nWarning: {}", warning.message)?;
+        }
+        Ok(())
+    }
+}
+
+/// Format of a merged image
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ImageFormat {
+    Bin,
+    Hex,
+    Elf,
+}
+
+impl ImageFormat {
+    /// Guess the format from the file extension, raw binary by default
+    pub(crate) fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("hex" | "ihex") => ImageFormat::Hex,
+            Some("elf") => ImageFormat::Elf,
+            _ => ImageFormat::Bin,
+        }
+    }
+
+    fn file_format(self) -> FileFormat {
+        match self {
+            ImageFormat::Bin => FileFormat::Bin,
+            ImageFormat::Hex => FileFormat::Hex,
+            ImageFormat::Elf => FileFormat::Elf,
+        }
+    }
+}
+
+pub(crate) struct MergeOptions {
+    /// XPI raw binaries and the memory config are placed in
+    pub(crate) xpi: MemoryId,
+    /// Byte filling the gaps of a raw binary
+    pub(crate) pad: u8,
+    /// Memory config to add as XPI NOR configuration option at 0x400
+    pub(crate) memory_config: Option<MemoryConfig>,
+    /// Family whose memory sizes the inputs have to fit in
+    pub(crate) family: Option<Family>,
+    pub(crate) format: ImageFormat,
+}
+
+/// Join the segments of several files, which must not overlap and must fit
+/// in the memory map of `family`, or in the ISP windows without one
+fn merge_segments(
+    files: &[(&Path, &[Segment])],
+    family: Option<Family>,
+) -> Result<Vec<Segment>, CodedError> {
+    let errors: Vec<String> = check_layout(files, family, None)
+        .into_iter()
+        .filter(|warning| warning.kind != WarningKind::Gap)
+        .map(|warning| warning.message)
+        .collect();
+    if !errors.is_empty() {
+        return Err(CodedError::new("invalid_layout", errors.join("\n")));
+    }
+    let mut segments: Vec<Segment> = files
+        .iter()
+        .flat_map(|(_, segments)| segments.iter().cloned())
+        .collect();
+    segments.sort_by_key(|segment| segment.address);
+    Ok(coalesce(segments))
+}
+
+#[derive(Serialize)]
+pub(crate) struct ImageMergeResult {
+    command: &'static str,
+    path: PathBuf,
+    format: &'static str,
+    /// Address a raw binary has to be written to
+    address: Option<u32>,
+    size: usize,
+    segments: Vec<SegmentEntry>,
+    entry_point: Option<u32>,
+}
+
+impl fmt::Display for ImageMergeResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Merged {} segment(s) into {} ({}, {} bytes)",
+            self.segments.len(),
+            self.path.display(),
+            self.format,
+            self.size
+        )?;
+        if let Some(address) = self.address {
+            write!(f, ", to be written at {address:#010x}")?;
+        }
+        if let Some(entry_point) = self.entry_point {
+            write!(f, ", entry point {entry_point:#010x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Merge the inputs into one image which flashes like the inputs one by one
+///
+/// Raw binaries span from the first to the last written byte of a single
+/// memory, gaps are filled with `pad`, so 0xFF leaves them as if erased.
+/// The entry point is the one of the first input which has one.
+pub(crate) fn image_merge(
+    inputs: &[ImageInput],
+    output: &Path,
+    options: &MergeOptions,
+) -> Result<ImageMergeResult, Box<dyn Error>> {
+    let files = inputs
+        .iter()
+        .map(|input| load_input(input, options.xpi))
+        .collect::<Result<Vec<_>, _>>()?;
+    let config_block = options.memory_config.map(|memory_config| {
+        [Segment {
+            address: options.xpi.base_address() + NOR_CFG_OPTION_OFFSET as u32,
+            data: memory_config.to_bootrom_config(),
+        }]
+    });
+    let mut layout: Vec<(&Path, &[Segment])> = inputs
+        .iter()
+        .zip(&files)
+        .map(|(input, file)| (input.path.as_path(), file.segments.as_slice()))
+        .collect();
+    if let Some(block) = &config_block {
+        layout.push((Path::new("memory config"), block));
+    }
+
+    let merged = ImageFile {
+        format: options.format.file_format(),
+        segments: merge_segments(&layout, options.family)?,
+        entry_point: files.iter().find_map(|file| file.entry_point),
+    };
+    let (Some(first), Some(last)) = (merged.segments.first(), merged.segments.last()) else {
+        return Err(CodedError::new("invalid_argument", "the inputs hold no data").into());
+    };
+    let (data, address) = match options.format {
+        ImageFormat::Bin => {
+            let memory_id = MemoryId::from_address(first.address);
+            if memory_id != MemoryId::from_address(last.address) {
+                return Err(CodedError::new(
+                    "invalid_layout",
+                    format!(
+                        "a raw binary can't hold data at {:#010x} and {:#010x}, merge to hex or ELF",
+                        first.address, last.address
+                    ),
+                )
+                .into());
+            }
+            (
+                flatten(&merged.segments, first.address, options.pad),
+                Some(first.address),
+            )
+        }
+        ImageFormat::Hex => (merged.to_hex()?.into_bytes(), None),
+        ImageFormat::Elf => (merged.to_elf()?, None),
+    };
+    fs::write(output, &data)?;
+
+    Ok(ImageMergeResult {
+        command: "image merge",
+        path: output.to_path_buf(),
+        format: merged.format.as_str(),
+        address,
+        size: data.len(),
+        segments: merged
+            .segments
+            .iter()
+            .map(|segment| SegmentEntry {
+                address: segment.address,
+                size: segment.data.len(),
+                memory: MemoryId::from_address(segment.address).map(|id| id.as_str()),
+            })
+            .collect(),
+        entry_point: merged.entry_point,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use hpm_isp::boot_image::{BOOT_HEADER_TAG, BOOT_HEADER_VERSION};
+    use hpm_isp::memory_config::MemoryConfig;
+    use sha2::{Digest, Sha256};
+    use tempfile::{tempdir, TempDir};
+    use zerocopy::{AsBytes, FromZeroes};
+
+    use super::*;
+
+    fn segment(address: u32, length: usize) -> Segment {
+        Segment {
+            address,
+            data: vec![0; length],
+        }
+    }
+
+    fn kinds(warnings: &[LayoutWarning]) -> Vec<WarningKind> {
+        warnings.iter().map(|warning| warning.kind).collect()
+    }
+
+    #[test]
+    fn parses_inputs() {
+        let input = parse_input("out/app.bin@0x3000").unwrap();
+        assert_eq!(input.path, PathBuf::from("out/app.bin"));
+        assert_eq!(input.address, Some(0x3000));
+
+        let input = parse_input("app.hex").unwrap();
+        assert_eq!(input.address, None);
+        assert!(parse_input("app.bin@zz").is_err());
+    }
+
+    #[test]
+    fn warns_about_overlaps_and_gaps() {
+        let boot = [segment(0x8000_0000, 0x3000)];
+        let app = [segment(0x8000_2000, 0x2000), segment(0x8000_5000, 0x100)];
+
+        let warnings = check_layout(
+            &[(Path::new("boot.bin"), &boot), (Path::new("app.hex"), &app)],
+            None,
+            None,
+        );
+
+        assert_eq!(kinds(&warnings), [WarningKind::Overlap, WarningKind::Gap]);
+        assert_eq!(
+            warnings[0].message,
+            "boot.bin and app.hex overlap at 0x80002000..0x80003000"
+        );
+        assert_eq!(
+            warnings[1].message,
+            "0x1000 bytes unwritten at 0x80004000..0x80005000"
+        );
+    }
+
+    #[test]
+    fn warns_about_data_out_of_range() {
+        let segments = [
+            segment(0x0007_FF00, 0x200),
+            segment(0x2000_0000, 4),
+            segment(0x800F_FFF0, 0x20),
+            segment(0x9000_0000, 4),
+        ];
+
+        let warnings = check_layout(&[(Path::new("app.elf"), &segments)], None, Some(0x10_0000));
+
+        assert_eq!(kinds(&warnings), [WarningKind::OutOfRange; 3]);
+        assert!(warnings[0]
+            .message
+            .contains("past the end of ILM at 0x00080000"));
+        assert!(warnings[1].message.contains("outside of the memory map"));
+        assert!(warnings[2]
+            .message
+            .contains("past the end of XPI0 at 0x80100000"));
+    }
+
+    #[test]
+    fn checks_family_memory_sizes() {
+        let segments = [segment(0x0001_FF00, 0x200), segment(0x0108_0000, 4)];
+        let files = [(Path::new("app.elf"), &segments[..])];
+
+        let warnings = check_layout(&files, Some(Family::HPM5300), None);
+        assert_eq!(kinds(&warnings), [WarningKind::OutOfRange; 2]);
+        assert!(warnings[0]
+            .message
+            .contains("past the end of ILM at 0x00020000"));
+        assert!(warnings[1]
+            .message
+            .contains("are in XRAM, which HPM5300 doesn't have"));
+
+        assert!(check_layout(&files, Some(Family::HPM6700_6400), None).is_empty());
+    }
+
+    /// Boot image of `app` at 0x3000 for XPI0, as the HPM SDK builds it
+    fn boot_image(app: &[u8]) -> Vec<u8> {
+        let header = BootHeader {
+            tag: BOOT_HEADER_TAG,
+            version: BOOT_HEADER_VERSION,
+            length: (mem::size_of::<BootHeader>() + mem::size_of::<FwInfo>()) as u16,
+            flags: 0,
+            sw_version: 0,
+            fuse_version: 0,
+            fw_count: 1,
+            dc_block_offset: 0,
+            sig_block_offset: 0,
+        };
+        let mut fw_info = FwInfo::new_zeroed();
+        fw_info.offset = (APP_OFFSET - BOOT_HEADER_OFFSET) as u32;
+        fw_info.size = app.len() as u32;
+        // SHA-256 hash
+        fw_info.flags = 1 << 8;
+        fw_info.load_addr = 0x8000_3000;
+        fw_info.entry_point = 0x8000_3000;
+        fw_info.hash[..32].copy_from_slice(&Sha256::digest(app));
+
+        let config = MemoryConfig::new().to_bootrom_config();
+        let mut image = vec![0xFF; APP_OFFSET + app.len()];
+        image[NOR_CFG_OPTION_OFFSET..NOR_CFG_OPTION_OFFSET + config.len()].copy_from_slice(&config);
+        let fw_info_offset = BOOT_HEADER_OFFSET + mem::size_of::<BootHeader>();
+        image[BOOT_HEADER_OFFSET..fw_info_offset].copy_from_slice(header.as_bytes());
+        image[fw_info_offset..fw_info_offset + mem::size_of::<FwInfo>()]
+            .copy_from_slice(fw_info.as_bytes());
+        image[APP_OFFSET..].copy_from_slice(app);
+        image
+    }
+
+    #[test]
+    fn finds_boot_header() {
+        let app = vec![0x13; 0x100];
+        let image = boot_image(&app);
+
+        let boot = boot_entry(&ImageFile::parse(&image, 0x8000_0000).unwrap()).unwrap();
+
+        assert_eq!(boot.memory, "XPI0");
+        assert!(boot.nor_cfg_option);
+        let header = boot.boot_header.unwrap();
+        assert!(header.hashes_verified);
+        assert_eq!(header.firmware[0].offset, 0x3000);
+        assert_eq!(header.firmware[0].entry_point, 0x8000_3000);
+
+        let app = ImageFile::parse(&app, 0x8000_3000).unwrap();
+        assert!(boot_entry(&app).is_none());
+
+        // Data at the end of the flash and a truncated firmware
+        let mut image = boot_image(&[0x13; 0x100]);
+        image.truncate(APP_OFFSET + 0x80);
+        let mut file = ImageFile::parse(&image, 0x8000_0000).unwrap();
+        file.segments.push(segment(0x8FFF_FF00, 0x100));
+        let header = boot_entry(&file).unwrap().boot_header.unwrap();
+        assert!(!header.hashes_verified);
+        assert_eq!(header.firmware[0].size, 0x100);
+    }
+
+    fn temp_file(dir: &TempDir, name: &str, data: &[u8]) -> PathBuf {
+        let path = dir.path().join(name);
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn merges_inputs_with_memory_config() {
+        let dir = tempdir().unwrap();
+        let boot = temp_file(&dir, "boot.bin", &[0x11; 0x10]);
+        let app = temp_file(&dir, "app.bin", &[0x22; 0x20]);
+        let merged = temp_file(&dir, "merged.bin", &[]);
+        let inputs = [
+            parse_input(&format!("{}@0x3000", app.display())).unwrap(),
+            parse_input(&format!("{}@0x1000", boot.display())).unwrap(),
+        ];
+        let options = MergeOptions {
+            xpi: MemoryId::XPI0,
+            pad: 0xFF,
+            memory_config: Some(MemoryConfig::new()),
+            family: None,
+            format: ImageFormat::Bin,
+        };
+
+        let result = image_merge(&inputs, &merged, &options).unwrap();
+
+        let data = fs::read(&merged).unwrap();
+        let config = MemoryConfig::new().to_bootrom_config();
+        assert_eq!(result.address, Some(0x8000_0400));
+        assert_eq!(data.len(), 0x3000 - 0x400 + 0x20);
+        assert_eq!(&data[..config.len()], config.as_slice());
+        assert_eq!(data[config.len()], 0xFF);
+        assert_eq!(&data[0xC00..0xC10], &[0x11; 0x10]);
+        assert_eq!(&data[0x2C00..], &[0x22; 0x20]);
+
+        let hex = temp_file(&dir, "merged.hex", &[]);
+        let options = MergeOptions {
+            format: ImageFormat::from_path(&hex),
+            ..options
+        };
+        image_merge(&inputs, &hex, &options).unwrap();
+        let file = ImageFile::parse(&fs::read(&hex).unwrap(), 0).unwrap();
+        assert_eq!(file.format, FileFormat::Hex);
+        assert_eq!(
+            flatten(&file.segments, 0x8000_0400, 0xFF),
+            data,
+            "hex and bin write the same bytes"
+        );
+    }
+
+    #[test]
+    fn refuses_to_merge_overlaps() {
+        let boot = [segment(0x8000_0000, 0x3000)];
+        let app = [segment(0x8000_2F00, 0x100)];
+        let config = [segment(0x8000_0400, 0x10)];
+
+        let error = merge_segments(
+            &[(Path::new("boot.bin"), &boot), (Path::new("app.bin"), &app)],
+            None,
+        )
+        .unwrap_err();
+        assert_eq!(error.code, "invalid_layout");
+
+        let merged = merge_segments(
+            &[
+                (Path::new("config"), &config),
+                (Path::new("app.bin"), &app),
+                (Path::new("tail"), &[segment(0x8000_3000, 4)]),
+            ],
+            None,
+        )
+        .unwrap();
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged[1].address, 0x8000_2F00);
+        assert_eq!(merged[1].data.len(), 0x104);
+    }
+
+    #[test]
+    fn merges_into_family_memory_sizes() {
+        let ilm = [segment(0x0001_FF00, 0x200)];
+        let files = [(Path::new("ilm.bin"), &ilm[..])];
+
+        assert!(merge_segments(&files, None).is_ok());
+        assert!(merge_segments(&files, Some(Family::HPM6700_6400)).is_ok());
+        let error = merge_segments(&files, Some(Family::HPM5300)).unwrap_err();
+        assert_eq!(error.code, "invalid_layout");
+        assert!(error.message.contains("run past the end of ILM"));
+    }
+}
@@ -4,6 +4,14 @@
     env!("CARGO_PKG_README")
 ))]
 
+#[cfg(feature = "async")]
+pub mod async_isp;
+pub mod boot_image;
 pub mod hid;
+pub mod image_file;
 pub mod isp_command;
 pub mod memory_config;
+pub mod progress;
+pub mod session;
+#[cfg(feature = "sim")]
+pub mod sim;
@@ -1,18 +1,56 @@
+mod audit;
+mod completions;
+mod compression;
 mod config;
+mod hex;
+mod info;
+mod layout;
+mod manpage;
+mod mem;
+mod output;
+mod parse;
+mod probe;
+mod selftest;
+mod serial;
+#[cfg(feature = "tui")]
+mod tui;
 mod wizard;
 
 use std::error::Error;
-use std::num::ParseIntError;
+use std::fmt;
+use std::fs;
+use std::io::{self, IsTerminal, Read, Write};
 use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
 
-use clap::{Parser, Subcommand};
-use config::read_memory_config_or_default;
-use indicatif::{ProgressBar, ProgressStyle};
+use audit::{report, AuditRecord, HashReader, HashWriter};
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueHint};
+use clap_complete::Shell;
+use completions::{dynamic_values, print_completions, DynamicValues};
+use compression::{is_stdio, Compression, Input, Output};
+use config::{describe_memory_config, Config, ResolvedConfig, ShowResult};
+use dialoguer::{theme::ColorfulTheme, Confirm};
+use info::query_info;
+use layout::{
+    image_info, image_merge, parse_byte, parse_input, ImageFormat, ImageInput, MergeOptions,
+};
+use manpage::render_man_page;
+use mem::{mem_fill, mem_read, mem_write, parse_width, Width};
+use output::{CodedError, OutputFormat, Reporter};
+use parse::{parse_family, parse_number, parse_region};
+use probe::{probe, ProbeOptions};
+use selftest::selftest;
+use serde::Serialize;
+use serial::{FlashWrite, SerialProvisioning};
 use wizard::config_wizard;
 
 use hpm_isp::{
-    hid,
+    hid::{self, Family},
     isp_command::{IspCommand, MemoryId},
+    memory_config::{MemoryConfig, BOARD_PRESETS},
+    progress::{NoProgress, ProgressEvent},
+    session::{FlashOptions, Session},
 };
 
 const DEFAULT_CONFIG_FILE: &str = "hpm_isp.toml";
@@ -22,57 +60,305 @@ const DEFAULT_CONFIG_FILE: &str = "hpm_isp.toml";
 struct Cli {
     #[clap(subcommand)]
     command: Commands,
+    #[clap(flatten)]
+    global: GlobalArgs,
+}
+
+/// Options of all commands
+#[derive(Args)]
+struct GlobalArgs {
+    /// Output format
+    #[clap(long, arg_enum, global = true, default_value = "text")]
+    output: OutputFormat,
+    /// USB serial number of the device to use, see `list`
+    #[clap(long, global = true, value_name = "SERIAL")]
+    device: Option<String>,
+    /// Board profile, `[profiles.<NAME>]` in the config files
+    #[clap(long, global = true, value_name = "NAME")]
+    profile: Option<String>,
+    /// Memory config of an evaluation board, see `boards`
+    #[clap(long, global = true, value_name = "BOARD")]
+    board: Option<String>,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     /// Command of xpi nor flash
     Flash {
-        /// XPI<ID> to write or read (0-1)
+        /// XPI<ID> to write or read (0-1), taken from the profile if omitted
         #[clap(parse(try_from_str = xpi_in_range))]
-        id: MemoryId,
+        id: Option<MemoryId>,
         #[clap(subcommand)]
         command: FlashCommands,
         /// Path of memory config file
-        #[clap(short, long)]
+        #[clap(short, long, value_hint = ValueHint::FilePath)]
+        config: Option<PathBuf>,
+        /// Append a record of the operation to an audit log, CSV for .csv files
+        /// and JSON lines otherwise
+        #[clap(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+        log: Option<PathBuf>,
+    },
+    /// Summarise yield and failures from an audit log
+    Report {
+        /// Audit log written by `flash --log`
+        #[clap(value_hint = ValueHint::FilePath)]
+        log: PathBuf,
+    },
+    /// List attached HPMicro devices
+    List,
+    /// List the memory config presets of the evaluation boards
+    Boards,
+    /// Find the memory configs which work with the attached board
+    ///
+    /// The ISP protocol can't read the SFDP table of the flash, so every
+    /// combination of flash type, port connection and pin group is tried.
+    Probe {
+        /// XPI<ID> to probe (0-1), the profile's XPI or 0 by default
+        #[clap(long, parse(try_from_str = xpi_in_range))]
+        xpi: Option<MemoryId>,
+        /// Offset or absolute address of the region read back
+        #[clap(long, default_value = "0", parse(try_from_str = parse_number))]
+        offset: u32,
+        /// Size of the region read back, the size of the expected data by default
+        #[clap(long, parse(try_from_str = parse_number))]
+        size: Option<u32>,
+        /// File with the data expected in the region, e.g. the flashed image
+        #[clap(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+        expect: Option<PathBuf>,
+        /// Save the best match to the memory config of the config file
+        /// without asking
+        #[clap(long)]
+        save: bool,
+    },
+    /// Show BootROM, chip and USB details of the attached device
+    Info,
+    /// Read or write a few values of ILM, DLM, XRAM or the XPI flashes
+    Mem {
+        #[clap(subcommand)]
+        command: MemCommands,
+        /// Path of memory config file, used for addresses in the XPI flashes
+        #[clap(short, long, value_hint = ValueHint::FilePath)]
+        config: Option<PathBuf>,
+    },
+    /// Write pseudo-random patterns to a region, read them back and report
+    /// bit errors, to qualify flash parts and screen boards
+    Selftest {
+        /// Region to test as START:LENGTH, e.g. 0x80100000:64K
+        #[clap(parse(try_from_str = parse_region))]
+        region: (u32, u32),
+        /// Seed of the patterns, random by default and printed to repeat a run
+        #[clap(long, parse(try_from_str = parse_number))]
+        seed: Option<u32>,
+        /// Passes to run, odd passes write the complement of the pass before
+        #[clap(long, default_value = "2")]
+        passes: u32,
+        /// Path of memory config file
+        #[clap(short, long, value_hint = ValueHint::FilePath)]
+        config: Option<PathBuf>,
+    },
+    /// Inspect and merge firmware images offline
+    Image {
+        #[clap(subcommand)]
+        command: ImageCommands,
+    },
+    /// Full-screen terminal UI to flash images and browse memory
+    #[cfg(feature = "tui")]
+    Tui {
+        /// Path of memory config file
+        #[clap(short, long, value_hint = ValueHint::FilePath)]
         config: Option<PathBuf>,
     },
     /// Command of wizard to generate memory config file
     Wizard {
         /// Path of memory config file
-        #[clap(short, long, default_value = DEFAULT_CONFIG_FILE)]
+        #[clap(short, long, default_value = DEFAULT_CONFIG_FILE, value_hint = ValueHint::FilePath)]
         path: PathBuf,
     },
+    /// Show the memory config merged from all layers
+    Config {
+        #[clap(subcommand)]
+        command: ConfigCommands,
+    },
+    /// Print the shell completion script
+    Completions {
+        #[clap(arg_enum)]
+        shell: Shell,
+    },
+    /// Print the man page in roff format
+    Man,
+    /// Print values for dynamic shell completion
+    #[clap(name = "complete-values", hide = true)]
+    Complete {
+        #[clap(arg_enum)]
+        values: DynamicValues,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigCommands {
+    /// Print the merged memory config
+    Show {
+        /// Path of memory config file
+        #[clap(short, long, value_hint = ValueHint::FilePath)]
+        config: Option<PathBuf>,
+        /// Show which layer each value came from
+        #[clap(long)]
+        resolved: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum MemCommands {
+    /// Print memory as a hex dump
+    Read {
+        /// Absolute address, e.g. 0x80000000, 0x1080000+0x40
+        #[clap(parse(try_from_str = parse_number))]
+        address: u32,
+        /// Bytes to read
+        #[clap(default_value = "64", parse(try_from_str = parse_number))]
+        length: u32,
+        /// Value width in bits: 8, 16 or 32, only groups the dump as the BootROM
+        /// reads bytes
+        #[clap(short, long, default_value = "32", parse(try_from_str = parse_width))]
+        width: Width,
+    },
+    /// Fill a region with a repeated value
+    ///
+    /// XPI flash is erased by sector before it's written, so the rest of the
+    /// 4 KiB sectors touched is read and written back.
+    Fill {
+        /// Region to fill as START:LENGTH, e.g. 0x1080000:16K
+        #[clap(parse(try_from_str = parse_region))]
+        region: (u32, u32),
+        /// Value repeated over the region, e.g. 0xdeadbeef
+        #[clap(parse(try_from_str = parse_number))]
+        pattern: u32,
+        /// Value width in bits: 8, 16 or 32, sets the size and alignment of the
+        /// values as the BootROM writes bytes
+        #[clap(short, long, default_value = "32", parse(try_from_str = parse_width))]
+        width: Width,
+    },
+    /// Write values to consecutive addresses
+    ///
+    /// XPI flash is erased by sector before it's written, so the rest of the
+    /// 4 KiB sectors touched is read and written back.
+    Write {
+        /// Absolute address, e.g. 0x80000000, 0x1080000+0x40
+        #[clap(parse(try_from_str = parse_number))]
+        address: u32,
+        /// Values to write, e.g. 0x12345678 0xdeadbeef
+        #[clap(required = true, parse(try_from_str = parse_number))]
+        values: Vec<u32>,
+        /// Value width in bits: 8, 16 or 32, sets the size and alignment of the
+        /// values as the BootROM writes bytes
+        #[clap(short, long, default_value = "32", parse(try_from_str = parse_width))]
+        width: Width,
+    },
 }
 
 #[derive(Subcommand)]
 enum FlashCommands {
     /// Write file to xpi nor flash
+    ///
+    /// The file is written as is, Intel HEX and ELF files are refused, see
+    /// `image merge` to turn them into a raw binary.
     Write {
-        /// Offset address to write
-        #[clap(parse(try_from_str = parse_hex))]
+        /// Offset or absolute address to write, e.g. 0x400, 0x80000400, 64K
+        #[clap(parse(try_from_str = parse_number))]
         offset: u32,
-        /// File to write
+        /// File to write, `-` for stdin
+        #[clap(value_hint = ValueHint::FilePath)]
         file: PathBuf,
+        /// Compression of the file, guessed from its extension by default
+        #[clap(long, arg_enum)]
+        compression: Option<Compression>,
+        /// Read back and compare after writing
+        #[clap(long)]
+        verify: bool,
+        /// Rules file to patch the serial number and per-unit data into the image
+        #[clap(long, value_name = "RULES", value_hint = ValueHint::FilePath)]
+        serial: Option<PathBuf>,
     },
     /// Read from xpi nor flash
     Read {
-        /// Offset address to read
-        #[clap(parse(try_from_str = parse_hex))]
+        /// Offset or absolute address to read, e.g. 0x0, 0x80000000+0x400
+        #[clap(parse(try_from_str = parse_number))]
         offset: u32,
-        /// Bytes to read
-        #[clap(parse(try_from_str = parse_hex))]
+        /// Bytes to read, e.g. 0x4000, 16K, 1M
+        #[clap(parse(try_from_str = parse_number))]
         size: u32,
-        /// File to save
+        /// File to save, `-` for stdout
+        #[clap(value_hint = ValueHint::FilePath)]
         file: PathBuf,
+        /// Compression of the file, guessed from its extension by default
+        #[clap(long, arg_enum)]
+        compression: Option<Compression>,
     },
 }
 
-fn parse_hex(s: &str) -> Result<u32, ParseIntError> {
-    if s.starts_with("0x") {
-        u32::from_str_radix(s.trim_start_matches("0x"), 16)
-    } else {
-        s.parse::<u32>()
+#[derive(Subcommand)]
+enum ImageCommands {
+    /// Show the segments of bin, hex, ELF and boot images and check that
+    /// they don't overlap and fit in the memory map
+    Info {
+        /// Files as PATH, or PATH@OFFSET for raw binaries, e.g. boot.hex app.bin@0x3000
+        #[clap(required = true, parse(try_from_str = parse_input), value_hint = ValueHint::FilePath)]
+        files: Vec<ImageInput>,
+        /// XPI<ID> raw binaries are written to (0-1)
+        #[clap(long, default_value = "0", parse(try_from_str = xpi_in_range))]
+        xpi: MemoryId,
+        /// Size of the XPI flash, to check that the images fit, e.g. 8M
+        #[clap(long, parse(try_from_str = parse_number))]
+        flash_size: Option<u32>,
+        /// Chip family whose memory sizes to check against, e.g. HPM5300,
+        /// taken from the profile or board preset by default
+        #[clap(long, parse(try_from_str = parse_family))]
+        family: Option<Family>,
+    },
+    /// Merge bin, hex and ELF files into one image which flashes like the
+    /// files one by one
+    Merge {
+        /// Files as PATH, or PATH@OFFSET for raw binaries, e.g. boot.hex app.bin@0x3000
+        #[clap(required = true, parse(try_from_str = parse_input), value_hint = ValueHint::FilePath)]
+        files: Vec<ImageInput>,
+        /// Merged image to save
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        merged: PathBuf,
+        /// Format of the merged image, guessed from its extension by default
+        #[clap(long, arg_enum)]
+        format: Option<ImageFormat>,
+        /// Byte filling the gaps of a raw binary, 0xff leaves them as if erased
+        #[clap(long, default_value = "0xff", parse(try_from_str = parse_byte))]
+        pad: u8,
+        /// XPI<ID> raw binaries and the memory config are written to (0-1)
+        #[clap(long, default_value = "0", parse(try_from_str = xpi_in_range))]
+        xpi: MemoryId,
+        /// Add the XPI NOR configuration option at 0x400, built from the
+        /// memory config
+        #[clap(long)]
+        memory_config: bool,
+        /// Path of memory config file
+        #[clap(short, long, value_hint = ValueHint::FilePath)]
+        config: Option<PathBuf>,
+        /// Chip family whose memory sizes to check against, e.g. HPM5300,
+        /// taken from the profile or board preset by default
+        #[clap(long, parse(try_from_str = parse_family))]
+        family: Option<Family>,
+    },
+}
+
+impl FlashCommands {
+    fn name(&self) -> &'static str {
+        match self {
+            FlashCommands::Write { .. } => "flash write",
+            FlashCommands::Read { .. } => "flash read",
+        }
+    }
+
+    fn offset(&self) -> u32 {
+        match self {
+            FlashCommands::Write { offset, .. } | FlashCommands::Read { offset, .. } => *offset,
+        }
     }
 }
 
@@ -85,86 +371,712 @@ fn xpi_in_range(s: &str) -> Result<MemoryId, String> {
     }
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
+fn main() -> ExitCode {
     let cli = Cli::parse();
+    let stdout_is_data = writes_stdout(&cli.command);
+    let reporter = Reporter::new(cli.global.output, stdout_is_data);
+
+    let result = if reporter.is_json() && stdout_is_data {
+        Err("JSON output and reading to stdout can't be used together".into())
+    } else {
+        run(cli.command, &cli.global, &reporter)
+    };
 
-    match cli.command {
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            reporter.error(e.as_ref());
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(command: Commands, global: &GlobalArgs, reporter: &Reporter) -> Result<(), Box<dyn Error>> {
+    match command {
         Commands::Flash {
-            id: memory_id,
+            id,
             command: flash_command,
             config,
+            log,
         } => {
-            let device = hid::HpmDevice::open().map_err(|_| "can't open HPMicro usb device")?;
-            let memory_config_bin = read_memory_config_or_default(config, DEFAULT_CONFIG_FILE)?;
+            let config = load_config(config, global, reporter)?;
+            let memory_id = id.or_else(|| config.xpi()).ok_or_else(|| {
+                CodedError::new(
+                    "invalid_config",
+                    "pass the XPI ID or set `xpi` in the profile",
+                )
+            })?;
+            let mut record = AuditRecord::new(
+                flash_command.name(),
+                memory_id.to_string(),
+                flash_command.offset(),
+            );
+            let result = match memory_id.to_offset(flash_command.offset()) {
+                Ok(offset) => {
+                    record.offset = offset;
+                    run_flash(
+                        memory_id,
+                        flash_command,
+                        &config,
+                        global.device.as_deref(),
+                        reporter,
+                        &mut record,
+                    )
+                }
+                Err(e) => Err(e.into()),
+            };
 
-            println!("Found chip: {}", device.family());
+            if let Some(log) = log {
+                match &result {
+                    Ok(result) => {
+                        record.result = "ok".to_string();
+                        record.bytes = result.bytes;
+                        record.sha256 = Some(result.sha256.clone());
+                        record.image_sha256 = result.image_sha256.clone();
+                        record.verified = result.verified;
+                        record.serial = result.serial;
+                    }
+                    Err(e) => record.set_error(e.as_ref()),
+                }
+                if let Err(e) = record.append_to(&log) {
+                    // Don't hide the error of the flash operation itself
+                    if result.is_ok() {
+                        return Err(e);
+                    }
+                    reporter.message(&format!("Failed to write audit log: {e}"));
+                }
+            }
 
-            // Config memory
-            device.write_memory(MemoryId::ILM, 0x200, &memory_config_bin, |_, _| {})?;
-            device.configure_memory(memory_id, MemoryId::ILM.base_address() + 0x200)?;
+            reporter.result(&result?);
+        }
+        Commands::Report { log } => {
+            reporter.result(&report(&log)?);
+        }
+        Commands::Boards => {
+            let boards = BOARD_PRESETS
+                .iter()
+                .map(|preset| BoardEntry {
+                    name: preset.name,
+                    family: preset.family.to_string(),
+                    xpi: preset.xpi.to_string(),
+                    memory_config: preset.memory_config,
+                })
+                .collect();
+            reporter.result(&BoardsResult {
+                command: "boards",
+                boards,
+            });
+        }
+        Commands::Probe {
+            xpi,
+            offset,
+            size,
+            expect,
+            save,
+        } => {
+            let xpi = match xpi {
+                Some(xpi) => xpi,
+                None => load_config(None, global, reporter)?
+                    .xpi()
+                    .unwrap_or(MemoryId::XPI0),
+            };
+            let device = open_device(global.device.as_deref())?;
+            reporter.message(&format!("Found chip: {}", device.family()));
 
-            match flash_command {
-                FlashCommands::Write { offset, file } => {
-                    write_file(file, memory_id, offset, &device)?;
+            let expected = expect.as_deref().map(fs::read).transpose()?;
+            let options = ProbeOptions {
+                memory_id: xpi,
+                offset: xpi.to_offset(offset)?,
+                size: size
+                    .or_else(|| expected.as_ref().map(|data| data.len() as u32))
+                    .unwrap_or(0x100),
+                expected,
+            };
+            let mut result = probe(&device, &options, |found| {
+                reporter.message(&format!("Works: {found}"));
+            })?;
+
+            if let Some(best) = result.best {
+                if save || confirm_save(reporter)? {
+                    Config::new(best).update_file(Path::new(DEFAULT_CONFIG_FILE))?;
+                    result.saved = Some(fs::canonicalize(DEFAULT_CONFIG_FILE)?);
+                }
+            }
+            reporter.result(&result);
+        }
+        Commands::List => {
+            let devices = hid::HpmDevice::list()?
+                .into_iter()
+                .map(|info| DeviceEntry {
+                    family: info.family.to_string(),
+                    serial_number: info.serial_number,
+                    path: info.path,
+                })
+                .collect();
+            reporter.result(&ListResult {
+                command: "list",
+                devices,
+            });
+        }
+        Commands::Info => {
+            let session = Session::new(open_device(global.device.as_deref())?);
+            reporter.result(&query_info(&session));
+        }
+        Commands::Mem { command, config } => {
+            let memory_config = load_config(config, global, reporter)?.memory_config();
+            let device = open_device(global.device.as_deref())?;
+            let family = device.family();
+            let mut session = Session::new(device);
+            match command {
+                MemCommands::Read {
+                    address,
+                    length,
+                    width,
+                } => reporter.result(&mem_read(
+                    &mut session,
+                    family,
+                    memory_config,
+                    address,
+                    length,
+                    width,
+                )?),
+                MemCommands::Fill {
+                    region,
+                    pattern,
+                    width,
+                } => {
+                    let mut progress = reporter.progress();
+                    let result = mem_fill(
+                        &mut session,
+                        family,
+                        memory_config,
+                        region,
+                        pattern,
+                        width,
+                        |event: &ProgressEvent| progress.update(event),
+                    )?;
+                    progress.finish();
+                    reporter.result(&result);
                 }
-                FlashCommands::Read { offset, size, file } => {
-                    read_file(file, memory_id, offset, size as usize, &device)?;
+                MemCommands::Write {
+                    address,
+                    values,
+                    width,
+                } => reporter.result(&mem_write(
+                    &mut session,
+                    family,
+                    memory_config,
+                    address,
+                    values,
+                    width,
+                )?),
+            }
+        }
+        Commands::Selftest {
+            region,
+            seed,
+            passes,
+            config,
+        } => {
+            let memory_config = load_config(config, global, reporter)?.memory_config();
+            let device = open_device(global.device.as_deref())?;
+            let family = device.family();
+            let mut session = Session::new(device);
+            let seed = match seed {
+                Some(seed) => seed,
+                None => {
+                    let mut seed = [0u8; 4];
+                    getrandom::getrandom(&mut seed)?;
+                    u32::from_le_bytes(seed)
                 }
+            };
+            reporter.message(&format!("Seed: {seed}"));
+
+            let mut progress = reporter.progress();
+            let result = selftest(
+                &mut session,
+                family,
+                memory_config,
+                region,
+                seed,
+                passes,
+                |event: &ProgressEvent| progress.update(event),
+            )?;
+            progress.finish();
+            reporter.result(&result);
+            if result.bit_errors() > 0 {
+                return Err(CodedError::new(
+                    "selftest_failed",
+                    format!(
+                        "{} bit error(s), rerun with --seed {seed}",
+                        result.bit_errors()
+                    ),
+                )
+                .into());
+            }
+        }
+        Commands::Image { command } => run_image(command, global, reporter)?,
+        Commands::Config {
+            command: ConfigCommands::Show { config, resolved },
+        } => {
+            let config = ResolvedConfig::load(
+                config.as_deref(),
+                global.profile.as_deref(),
+                global.board.as_deref(),
+                DEFAULT_CONFIG_FILE,
+            )?;
+            reporter.result(&ShowResult::new(&config, resolved));
+        }
+        Commands::Completions { shell } => {
+            print_completions(shell, &mut Cli::command());
+        }
+        Commands::Man => {
+            print!("{}", render_man_page(&Cli::command()));
+        }
+        Commands::Complete { values } => {
+            for value in dynamic_values(values) {
+                println!("{value}");
             }
         }
+        #[cfg(feature = "tui")]
+        Commands::Tui { config } => {
+            let config = ResolvedConfig::load(
+                config.as_deref(),
+                global.profile.as_deref(),
+                global.board.as_deref(),
+                DEFAULT_CONFIG_FILE,
+            )?;
+            tui::run_tui(&config, global.device.as_deref())?;
+        }
         Commands::Wizard { path } => {
-            config_wizard(path)?;
+            let path = config_wizard(path)?;
+            reporter.result(&WizardResult {
+                command: "wizard",
+                path,
+            });
         }
     }
 
     Ok(())
 }
 
-fn write_file<D, P>(
-    path: P,
+fn run_flash(
     memory_id: MemoryId,
-    offset: u32,
-    device: &D,
-) -> Result<(), Box<dyn Error>>
+    flash_command: FlashCommands,
+    config: &ResolvedConfig,
+    device: Option<&str>,
+    reporter: &Reporter,
+    record: &mut AuditRecord,
+) -> Result<FlashResult, Box<dyn Error>> {
+    let device = open_device(device)?;
+    let family = device.family();
+    record.family = Some(family.to_string());
+    record.device_serial = device.usb_info().serial_number;
+
+    reporter.message(&format!("Found chip: {family}"));
+    config.check_family(family)?;
+
+    let mut session = Session::new(device);
+    let options = FlashOptions::new(memory_id)
+        .offset(flash_command.offset())
+        .memory_config(config.memory_config());
+    // Config memory
+    session.configure(memory_id, config.memory_config())?;
+
+    let mut result = FlashResult {
+        command: "flash write",
+        family: family.to_string(),
+        memory: memory_id.to_string(),
+        offset: 0,
+        bytes: 0,
+        sha256: String::new(),
+        image_sha256: None,
+        duration_ms: 0,
+        verified: false,
+        serial: None,
+    };
+    let start = Instant::now();
+
+    match flash_command {
+        FlashCommands::Write {
+            offset,
+            file,
+            compression,
+            verify,
+            serial,
+        } => {
+            let provisioning = serial
+                .as_deref()
+                .map(SerialProvisioning::load)
+                .transpose()?;
+            if let Some(provisioning) = &provisioning {
+                reporter.message(&format!("Serial number: {}", provisioning.serial));
+                record.serial = Some(provisioning.serial);
+            }
+
+            result.offset = memory_id.to_offset(offset)?;
+            (result.bytes, result.sha256, result.image_sha256) = write_file(
+                &file,
+                compression,
+                provisioning.as_ref(),
+                &options.verify(verify),
+                &mut session,
+                reporter,
+            )?;
+            result.verified = verify;
+
+            if let Some(provisioning) = provisioning {
+                provisioning.log(record.device_serial.as_deref(), &result.family)?;
+                result.serial = Some(provisioning.serial);
+            }
+        }
+        FlashCommands::Read {
+            offset,
+            size,
+            file,
+            compression,
+        } => {
+            result.command = "flash read";
+            result.offset = memory_id.to_offset(offset)?;
+            result.bytes = size as usize;
+            result.sha256 = read_file(
+                &file,
+                compression,
+                size as usize,
+                &options,
+                &mut session,
+                reporter,
+            )?;
+        }
+    }
+
+    result.duration_ms = start.elapsed().as_millis();
+    Ok(result)
+}
+
+fn run_image(
+    command: ImageCommands,
+    global: &GlobalArgs,
+    reporter: &Reporter,
+) -> Result<(), Box<dyn Error>> {
+    match command {
+        ImageCommands::Info {
+            files,
+            xpi,
+            flash_size,
+            family,
+        } => {
+            let family = match family {
+                Some(family) => Some(family),
+                None => load_config(None, global, reporter)?.family(),
+            };
+            reporter.result(&image_info(&files, xpi, family, flash_size)?);
+        }
+        ImageCommands::Merge {
+            files,
+            merged,
+            format,
+            pad,
+            xpi,
+            memory_config,
+            config,
+            family,
+        } => {
+            let resolved = if memory_config || family.is_none() {
+                Some(load_config(config, global, reporter)?)
+            } else {
+                None
+            };
+            let options = MergeOptions {
+                xpi,
+                pad,
+                memory_config: resolved
+                    .as_ref()
+                    .filter(|_| memory_config)
+                    .map(ResolvedConfig::memory_config),
+                family: family.or_else(|| resolved.as_ref().and_then(ResolvedConfig::family)),
+                format: format.unwrap_or_else(|| ImageFormat::from_path(&merged)),
+            };
+            reporter.result(&image_merge(&files, &merged, &options)?);
+        }
+    }
+
+    Ok(())
+}
+
+/// Ask whether to save the probed memory config, when interactive
+fn confirm_save(reporter: &Reporter) -> Result<bool, Box<dyn Error>> {
+    if reporter.is_json() || !io::stdin().is_terminal() {
+        return Ok(false);
+    }
+    let prompt = if Path::new(DEFAULT_CONFIG_FILE).exists() {
+        format!("Update the memory config of {DEFAULT_CONFIG_FILE} with the best match?")
+    } else {
+        format!("Save the best match to {DEFAULT_CONFIG_FILE}?")
+    };
+    Ok(Confirm::with_theme(&ColorfulTheme::default())
+        .with_prompt(prompt)
+        .interact()?)
+}
+
+/// Merge the config layers, reporting the files used
+fn load_config(
+    config: Option<PathBuf>,
+    global: &GlobalArgs,
+    reporter: &Reporter,
+) -> Result<ResolvedConfig, Box<dyn Error>> {
+    let config = ResolvedConfig::load(
+        config.as_deref(),
+        global.profile.as_deref(),
+        global.board.as_deref(),
+        DEFAULT_CONFIG_FILE,
+    )?;
+    if let Some(profile) = &config.profile {
+        reporter.message(&format!("Using profile: {profile}"));
+    }
+    if let Some(board) = &global.board {
+        reporter.message(&format!("Using board preset: {board}"));
+    }
+    for (_, path) in &config.files {
+        reporter.message(&format!("Reading memory config from: {}", path.display()));
+    }
+    Ok(config)
+}
+
+fn open_device(serial_number: Option<&str>) -> Result<hid::HpmDevice, CodedError> {
+    match serial_number {
+        Some(serial_number) => hid::HpmDevice::open_serial(serial_number),
+        None => hid::HpmDevice::open(),
+    }
+    .map_err(|_| CodedError::new("device_not_found", "can't open HPMicro usb device"))
+}
+
+fn writes_stdout(command: &Commands) -> bool {
+    match command {
+        Commands::Flash {
+            command: FlashCommands::Read { file, .. },
+            ..
+        } => is_stdio(file),
+        Commands::Completions { .. } | Commands::Man | Commands::Complete { .. } => true,
+        _ => false,
+    }
+}
+
+/// Returns the number of bytes written, their SHA-256 and, when serial
+/// patches change what is written, the SHA-256 of the input image
+fn write_file<D>(
+    path: &Path,
+    compression: Option<Compression>,
+    provisioning: Option<&SerialProvisioning>,
+    options: &FlashOptions,
+    session: &mut Session<D>,
+    reporter: &Reporter,
+) -> Result<(usize, String, Option<String>), Box<dyn Error>>
 where
-    P: AsRef<Path>,
     D: IspCommand,
 {
+    let mut input = Input::open(path, compression)?.require_bin(path)?;
+    let mut progress = reporter.progress();
+
     // Write flash
-    let pb = new_progress_bar(0);
-    device.write_file(path, memory_id, offset, |w, l| {
-        pb.set_length(l as u64);
-        pb.set_position(w as u64);
-    })?;
-    pb.finish();
-    Ok(())
+    let length = if options.verify || provisioning.is_some() {
+        let mut data = Vec::with_capacity(input.length.unwrap_or_default());
+        input.reader.read_to_end(&mut data)?;
+        let image_sha256 = match provisioning {
+            Some(_) => {
+                let mut image = HashWriter::new(io::sink());
+                image.write_all(&data)?;
+                Some(image.finish().1)
+            }
+            None => None,
+        };
+        let writes = match provisioning {
+            Some(provisioning) => provisioning.writes(
+                &data,
+                options.memory_id.to_offset(options.offset)?,
+                |offset, length| Ok(session.dump(length, &options.offset(offset), NoProgress)?),
+            )?,
+            None => vec![FlashWrite {
+                offset: options.offset,
+                data,
+            }],
+        };
+        let mut length = 0;
+        let mut hash = HashWriter::new(io::sink());
+        for write in &writes {
+            session.flash_image(
+                &write.data,
+                &options.offset(write.offset),
+                |event: &ProgressEvent| progress.update(event),
+            )?;
+            length += write.data.len();
+            hash.write_all(&write.data)?;
+        }
+        (length, hash.finish().1, image_sha256)
+    } else {
+        let mut length = input.length.unwrap_or_default();
+        let mut reader = HashReader::new(input.reader);
+        session.flash_from_reader(
+            &mut reader,
+            input.length,
+            options,
+            |event: &ProgressEvent| {
+                length = event.total;
+                progress.update(event)
+            },
+        )?;
+        (length, reader.finish(), None)
+    };
+    progress.finish();
+    Ok(length)
 }
 
-fn read_file<D, P>(
-    path: P,
-    memory_id: MemoryId,
-    offset: u32,
+/// Returns the SHA-256 of the data read
+fn read_file<D>(
+    path: &Path,
+    compression: Option<Compression>,
     length: usize,
-    device: &D,
-) -> Result<(), Box<dyn Error>>
+    options: &FlashOptions,
+    session: &mut Session<D>,
+    reporter: &Reporter,
+) -> Result<String, Box<dyn Error>>
 where
     D: IspCommand,
-    P: AsRef<Path>,
 {
+    let mut output = HashWriter::new(Output::create(path, compression)?);
+    let mut progress = reporter.progress();
+
     // Read flash
-    let pb = new_progress_bar(length as u64);
-    device.read_file(path, memory_id, offset, length, |b, _| {
-        pb.set_position(b as u64)
+    session.dump_to_writer(&mut output, length, options, |event: &ProgressEvent| {
+        progress.update(event)
     })?;
-    pb.finish();
-    Ok(())
+    let (output, sha256) = output.finish();
+    output.finish()?;
+    progress.finish();
+    Ok(sha256)
 }
 
-fn new_progress_bar(len: u64) -> ProgressBar {
-    let pb = ProgressBar::new(len);
-    pb.set_style(ProgressStyle::default_bar()
-        .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-        .unwrap()
-        .progress_chars("#>-"));
-    pb
+#[derive(Serialize)]
+struct FlashResult {
+    command: &'static str,
+    family: String,
+    memory: String,
+    offset: u32,
+    bytes: usize,
+    sha256: String,
+    /// SHA-256 of the input image when `--serial` patches it
+    image_sha256: Option<String>,
+    duration_ms: u128,
+    verified: bool,
+    serial: Option<u64>,
+}
+
+impl fmt::Display for FlashResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self.command {
+            "flash read" => "Read",
+            _ => "Wrote",
+        };
+        write!(
+            f,
+            "{action} {} bytes of {} at {:#x} in {:.2?}",
+            self.bytes,
+            self.memory,
+            self.offset,
+            Duration::from_millis(self.duration_ms as u64)
+        )?;
+        if self.verified {
+            write!(f, ", verified")?;
+        }
+        if let Some(serial) = self.serial {
+            write!(f, ", serial {serial}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct DeviceEntry {
+    family: String,
+    serial_number: Option<String>,
+    path: String,
+}
+
+#[derive(Serialize)]
+struct ListResult {
+    command: &'static str,
+    devices: Vec<DeviceEntry>,
+}
+
+impl fmt::Display for ListResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.devices.is_empty() {
+            return write!(f, "No HPMicro device found");
+        }
+        for (i, device) in self.devices.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(
+                f,
+                "{}: {} (serial: {})",
+                device.path,
+                device.family,
+                device.serial_number.as_deref().unwrap_or("unknown")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct BoardEntry {
+    name: &'static str,
+    family: String,
+    xpi: String,
+    memory_config: MemoryConfig,
+}
+
+#[derive(Serialize)]
+struct BoardsResult {
+    command: &'static str,
+    boards: Vec<BoardEntry>,
+}
+
+impl fmt::Display for BoardsResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, board) in self.boards.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(
+                f,
+                "{:<16} {:<14} {}  {}",
+                board.name,
+                board.family,
+                board.xpi,
+                describe_memory_config(&board.memory_config)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct WizardResult {
+    command: &'static str,
+    path: Option<PathBuf>,
+}
+
+impl fmt::Display for WizardResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.path {
+            Some(path) => write!(
+                f,
+                "Config file was successfully saved to: {}",
+                path.display()
+            ),
+            None => write!(f, "Config file was not saved"),
+        }
+    }
 }
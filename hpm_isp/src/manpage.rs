@@ -0,0 +1,174 @@
+use std::fmt::Write;
+
+use clap::{Arg, Command};
+
+/// Render a man page covering the command and all of its subcommands
+///
+/// clap_mangen needs clap 4, so the roff is written by hand.
+pub(crate) fn render_man_page(command: &Command) -> String {
+    let name = command.get_name();
+    let mut page = String::new();
+
+    let _ = writeln!(
+        page,
+        ".TH {} 1 \"\" \"{} {}\" \"User Commands\"",
+        name.to_uppercase(),
+        name,
+        command.get_version().unwrap_or_default()
+    );
+    let _ = writeln!(page, ".SH NAME");
+    let _ = writeln!(
+        page,
+        "{} \\- {}",
+        escape(name),
+        escape(command.get_about().unwrap_or_default())
+    );
+    let _ = writeln!(page, ".SH SYNOPSIS");
+    let _ = writeln!(page, "{}", synopsis(name, command));
+    render_arguments(&mut page, command, ".SH");
+
+    let _ = writeln!(page, ".SH SUBCOMMANDS");
+    for subcommand in command.get_subcommands().filter(|cmd| !cmd.is_hide_set()) {
+        render_subcommand(&mut page, name, subcommand);
+    }
+    page
+}
+
+fn render_subcommand(page: &mut String, parent: &str, command: &Command) {
+    let path = format!("{parent} {}", command.get_name());
+    let _ = writeln!(page, ".SS \"{}\"", escape(&path));
+    if let Some(about) = command.get_about() {
+        let _ = writeln!(page, "{}", escape(about));
+    }
+    let _ = writeln!(page, ".PP");
+    let _ = writeln!(page, "{}", synopsis(&path, command));
+    render_arguments(page, command, ".PP\n.B");
+
+    for subcommand in command.get_subcommands().filter(|cmd| !cmd.is_hide_set()) {
+        render_subcommand(page, &path, subcommand);
+    }
+}
+
+/// List positional arguments and options, headed by `heading` and the title
+fn render_arguments(page: &mut String, command: &Command, heading: &str) {
+    let (positionals, options): (Vec<&Arg>, Vec<&Arg>) = command
+        .get_arguments()
+        .filter(|arg| !arg.is_hide_set())
+        .partition(|arg| arg.is_positional());
+
+    for (title, args) in [("ARGUMENTS", positionals), ("OPTIONS", options)] {
+        if args.is_empty() {
+            continue;
+        }
+        let _ = writeln!(page, "{heading} {title}");
+        for arg in args {
+            let _ = writeln!(page, ".TP");
+            let _ = writeln!(page, "{}", arg_label(arg));
+            let mut help = arg.get_help().unwrap_or_default().to_string();
+            let values: Vec<_> = arg
+                .get_possible_values()
+                .unwrap_or_default()
+                .iter()
+                .filter(|value| !value.is_hide_set())
+                .map(|value| value.get_name())
+                .collect();
+            if !values.is_empty() {
+                let _ = write!(help, " [possible values: {}]", values.join(", "));
+            }
+            let defaults: Vec<_> = arg
+                .get_default_values()
+                .iter()
+                .map(|value| value.to_string_lossy())
+                .collect();
+            if !defaults.is_empty() {
+                let _ = write!(help, " [default: {}]", defaults.join(", "));
+            }
+            let _ = writeln!(page, "{}", escape(help.trim_start()));
+        }
+    }
+}
+
+fn synopsis(path: &str, command: &Command) -> String {
+    let mut synopsis = format!("\\fB{}\\fR", escape(path));
+    if command.get_arguments().any(|arg| !arg.is_positional()) {
+        synopsis.push_str(" [OPTIONS]");
+    }
+    for arg in command.get_positionals() {
+        let name = value_name(arg);
+        if arg.is_required_set() {
+            let _ = write!(synopsis, " <{name}>");
+        } else {
+            let _ = write!(synopsis, " [{name}]");
+        }
+    }
+    if command.has_subcommands() {
+        synopsis.push_str(" <SUBCOMMAND>");
+    }
+    escape_dashes(&synopsis)
+}
+
+fn arg_label(arg: &Arg) -> String {
+    if arg.is_positional() {
+        return format!("<{}>", value_name(arg));
+    }
+
+    let mut label = String::new();
+    if let Some(short) = arg.get_short() {
+        let _ = write!(label, "\\fB\\-{short}\\fR");
+    }
+    if let Some(long) = arg.get_long() {
+        if !label.is_empty() {
+            label.push_str(", ");
+        }
+        let _ = write!(label, "\\fB\\-\\-{}\\fR", escape_dashes(long));
+    }
+    if arg.is_takes_value_set() {
+        let _ = write!(label, " <{}>", value_name(arg));
+    }
+    label
+}
+
+fn value_name(arg: &Arg) -> String {
+    match arg.get_value_names() {
+        Some(names) => names.join(" "),
+        None => arg.get_id().to_uppercase(),
+    }
+}
+
+/// Escape text for roff
+fn escape(text: &str) -> String {
+    let text = escape_dashes(&text.replace('\\', "\\e"));
+    match text.chars().next() {
+        Some('.') | Some('\'') => format!("\\&{text}"),
+        _ => text,
+    }
+}
+
+fn escape_dashes(text: &str) -> String {
+    text.replace("\\-", "-").replace('-', "\\-")
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+    use crate::Cli;
+
+    #[test]
+    fn covers_nested_subcommands() {
+        let page = render_man_page(&Cli::command());
+        assert!(page.starts_with(".TH HPM_ISP 1"));
+        assert!(page.contains(".SS \"hpm_isp flash write\""));
+        assert!(page.contains(".SS \"hpm_isp flash read\""));
+        assert!(page.contains(".SS \"hpm_isp image info\""));
+        assert!(page.contains("\\fB\\-\\-verify\\fR"));
+        assert!(!page.contains("complete-values"));
+    }
+
+    #[test]
+    fn escapes_roff() {
+        assert_eq!(escape(".hidden"), "\\&.hidden");
+        assert_eq!(escape("a-b \\n"), "a\\-b \\en");
+    }
+}
@@ -0,0 +1,558 @@
+//! Peek and poke of device memory by absolute address
+
+use std::error::Error;
+use std::fmt;
+
+use serde::Serialize;
+
+use hpm_isp::hid::Family;
+use hpm_isp::isp_command::{IspCommand, MemoryId};
+use hpm_isp::memory_config::MemoryConfig;
+use hpm_isp::progress::{NoProgress, Progress};
+use hpm_isp::session::{FlashOptions, Session, SECTOR_SIZE};
+
+use crate::output::CodedError;
+
+/// Bytes per line of a hex dump
+pub(crate) const LINE_BYTES: usize = 16;
+
+/// Width of the values of the `mem` commands
+///
+/// It sets how values are encoded, grouped and aligned, the BootROM transfers
+/// bytes either way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Width {
+    Byte,
+    Half,
+    Word,
+}
+
+impl Width {
+    pub(crate) fn bytes(self) -> usize {
+        match self {
+            Width::Byte => 1,
+            Width::Half => 2,
+            Width::Word => 4,
+        }
+    }
+
+    fn bits(self) -> u32 {
+        self.bytes() as u32 * 8
+    }
+
+    fn max(self) -> u32 {
+        u32::MAX >> (32 - self.bits())
+    }
+}
+
+/// Parse a value width in bits, 8, 16 or 32
+pub(crate) fn parse_width(s: &str) -> Result<Width, String> {
+    match s {
+        "8" => Ok(Width::Byte),
+        "16" => Ok(Width::Half),
+        "32" => Ok(Width::Word),
+        _ => Err("width must be 8, 16 or 32".to_string()),
+    }
+}
+
+/// Memory of `family` holding the `length` bytes at `address`
+///
+/// The access has to be aligned to the width and stay inside one memory, which
+/// may be smaller than its window, see `info` for the memory map.
+pub(crate) fn locate(
+    family: Family,
+    address: u32,
+    length: u32,
+    width: Width,
+) -> Result<MemoryId, CodedError> {
+    let memory_id = MemoryId::from_address(address).ok_or_else(|| {
+        CodedError::new(
+            "out_of_range",
+            format!("{address:#010x} is not in ILM, DLM, XRAM, XPI0 or XPI1"),
+        )
+    })?;
+    let width_bytes = width.bytes() as u32;
+    if !address.is_multiple_of(width_bytes) || !length.is_multiple_of(width_bytes) {
+        return Err(CodedError::new(
+            "misaligned",
+            format!(
+                "{}-bit values need an address and length aligned to {width_bytes} bytes",
+                width.bits()
+            ),
+        ));
+    }
+    if length == 0 {
+        return Err(CodedError::new("out_of_range", "length must not be 0"));
+    }
+    let size = family
+        .memory_size(memory_id)
+        .ok_or_else(|| CodedError::new("out_of_range", format!("{family} has no {memory_id}")))?;
+    let end = u64::from(address - memory_id.base_address()) + u64::from(length);
+    if end > u64::from(size) {
+        return Err(CodedError::new(
+            "out_of_range",
+            format!(
+                "{length:#x} bytes at {address:#010x} run past the end of {memory_id} of {family} at {:#010x}",
+                memory_id.base_address() + (size - 1)
+            ),
+        ));
+    }
+    Ok(memory_id)
+}
+
+/// Little-endian values of `width` in `data`
+fn decode(data: &[u8], width: Width) -> Vec<u32> {
+    data.chunks(width.bytes())
+        .map(|chunk| {
+            chunk
+                .iter()
+                .rev()
+                .fold(0, |value, byte| value << 8 | u32::from(*byte))
+        })
+        .collect()
+}
+
+/// Little-endian bytes of the values, each has to fit in `width`
+fn encode(values: &[u32], width: Width) -> Result<Vec<u8>, CodedError> {
+    let mut data = Vec::with_capacity(values.len() * width.bytes());
+    for value in values {
+        if *value > width.max() {
+            return Err(CodedError::new(
+                "out_of_range",
+                format!("{value:#x} doesn't fit in {} bits", width.bits()),
+            ));
+        }
+        data.extend_from_slice(&value.to_le_bytes()[..width.bytes()]);
+    }
+    Ok(data)
+}
+
+/// Hex dump of `data` read from `address`, grouped in little-endian values
+/// of `width` so words read like in a disassembler
+///
+/// # Example
+///
+/// ```ignore
+/// assert_eq!(
+///     hexdump(0x8000_0000, b"\x13\x05\x00\x00", Width::Word),
+///     ["80000000  00000513  |....|"]
+/// );
+/// ```
+pub(crate) fn hexdump(address: u32, data: &[u8], width: Width) -> Vec<String> {
+    let digits = width.bytes() * 2;
+    let columns = LINE_BYTES / width.bytes() * (digits + 1) - 1;
+    data.chunks(LINE_BYTES)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let values: Vec<String> = decode(chunk, width)
+                .iter()
+                .map(|value| format!("{value:0digits$x}"))
+                .collect();
+            let ascii: String = chunk
+                .iter()
+                .map(|&byte| {
+                    if byte.is_ascii_graphic() || byte == b' ' {
+                        byte as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            format!(
+                "{:08x}  {:<columns$}  |{ascii}|",
+                address + (i * LINE_BYTES) as u32,
+                values.join(" ")
+            )
+        })
+        .collect()
+}
+
+#[derive(Serialize)]
+pub(crate) struct MemReadResult {
+    command: &'static str,
+    memory: String,
+    address: u32,
+    width: u32,
+    values: Vec<u32>,
+    #[serde(skip)]
+    data: Vec<u8>,
+    #[serde(skip)]
+    access: Width,
+}
+
+impl fmt::Display for MemReadResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            hexdump(self.address, &self.data, self.access).join("\n")
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct MemWriteResult {
+    command: &'static str,
+    memory: String,
+    address: u32,
+    width: u32,
+    values: Vec<u32>,
+}
+
+impl fmt::Display for MemWriteResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Wrote {} {}-bit value(s) to {} at {:#010x}",
+            self.values.len(),
+            self.width,
+            self.memory,
+            self.address
+        )
+    }
+}
+
+/// Read `length` bytes at an absolute address of a `family` device,
+/// `memory_config` is used if it lies in an XPI flash
+pub(crate) fn mem_read<D>(
+    session: &mut Session<D>,
+    family: Family,
+    memory_config: MemoryConfig,
+    address: u32,
+    length: u32,
+    width: Width,
+) -> Result<MemReadResult, Box<dyn Error>>
+where
+    D: IspCommand,
+{
+    let memory_id = locate(family, address, length, width)?;
+    let options = FlashOptions::new(memory_id)
+        .offset(address)
+        .memory_config(memory_config);
+    let data = session.dump(length as usize, &options, NoProgress)?;
+    Ok(MemReadResult {
+        command: "mem read",
+        memory: memory_id.to_string(),
+        address,
+        width: width.bits(),
+        values: decode(&data, width),
+        data,
+        access: width,
+    })
+}
+
+/// Write `data` at an absolute address of `memory_id`
+///
+/// The BootROM erases the XPI flash sectors it writes to, so the rest of the
+/// sectors is read first and written back along with `data`.
+fn write_preserving<D, P>(
+    session: &mut Session<D>,
+    memory_config: MemoryConfig,
+    memory_id: MemoryId,
+    address: u32,
+    data: &[u8],
+    progress: P,
+) -> Result<(), Box<dyn Error>>
+where
+    D: IspCommand,
+    P: Progress,
+{
+    let options = FlashOptions::new(memory_id).memory_config(memory_config);
+    if !matches!(memory_id, MemoryId::XPI0 | MemoryId::XPI1) {
+        session.flash_image(data, &options.offset(address), progress)?;
+        return Ok(());
+    }
+    // `locate` keeps the data inside the flash, which is made of whole sectors
+    let offset = address - memory_id.base_address();
+    let start = offset & !(SECTOR_SIZE - 1);
+    let end = (offset + data.len() as u32).next_multiple_of(SECTOR_SIZE);
+    let mut sectors = session.dump((end - start) as usize, &options.offset(start), NoProgress)?;
+    let patch = (offset - start) as usize;
+    sectors[patch..patch + data.len()].copy_from_slice(data);
+    session.flash_image(&sectors, &options.offset(start), progress)?;
+    Ok(())
+}
+
+/// Write values of `width` to consecutive addresses
+pub(crate) fn mem_write<D>(
+    session: &mut Session<D>,
+    family: Family,
+    memory_config: MemoryConfig,
+    address: u32,
+    values: Vec<u32>,
+    width: Width,
+) -> Result<MemWriteResult, Box<dyn Error>>
+where
+    D: IspCommand,
+{
+    let data = encode(&values, width)?;
+    let memory_id = locate(family, address, data.len() as u32, width)?;
+    write_preserving(
+        session,
+        memory_config,
+        memory_id,
+        address,
+        &data,
+        NoProgress,
+    )?;
+    Ok(MemWriteResult {
+        command: "mem write",
+        memory: memory_id.to_string(),
+        address,
+        width: width.bits(),
+        values,
+    })
+}
+
+#[derive(Serialize)]
+pub(crate) struct MemFillResult {
+    command: &'static str,
+    memory: String,
+    address: u32,
+    length: u32,
+    width: u32,
+    pattern: u32,
+}
+
+impl fmt::Display for MemFillResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Filled {:#x} bytes of {} at {:#010x} with {:#0digits$x}",
+            self.length,
+            self.memory,
+            self.address,
+            self.pattern,
+            digits = self.width as usize / 4 + 2
+        )
+    }
+}
+
+/// Fill a region with a value of `width` repeated
+pub(crate) fn mem_fill<D, P>(
+    session: &mut Session<D>,
+    family: Family,
+    memory_config: MemoryConfig,
+    (address, length): (u32, u32),
+    pattern: u32,
+    width: Width,
+    progress: P,
+) -> Result<MemFillResult, Box<dyn Error>>
+where
+    D: IspCommand,
+    P: Progress,
+{
+    let memory_id = locate(family, address, length, width)?;
+    let data = encode(&[pattern], width)?.repeat(length as usize / width.bytes());
+    write_preserving(session, memory_config, memory_id, address, &data, progress)?;
+    Ok(MemFillResult {
+        command: "mem fill",
+        memory: memory_id.to_string(),
+        address,
+        length,
+        width: width.bits(),
+        pattern,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use hpm_isp::hid::Family;
+    use hpm_isp::sim::SimulatedDevice;
+
+    use super::*;
+
+    #[test]
+    fn validates_against_memory_map() {
+        assert_eq!(
+            locate(Family::HPM6700_6400, 0x0108_0000, 16, Width::Word).unwrap(),
+            MemoryId::XRAM
+        );
+        assert_eq!(
+            locate(Family::HPM6700_6400, 0x8FFF_FFFE, 2, Width::Half).unwrap(),
+            MemoryId::XPI0
+        );
+        assert_eq!(
+            locate(Family::HPM6700_6400, 0x0010_0000, 4, Width::Byte)
+                .unwrap_err()
+                .code,
+            "out_of_range"
+        );
+        assert_eq!(
+            locate(Family::HPM6700_6400, 0x0007_FFFC, 8, Width::Word)
+                .unwrap_err()
+                .code,
+            "out_of_range"
+        );
+        assert_eq!(
+            locate(Family::HPM6700_6400, 0x0008_0002, 4, Width::Word)
+                .unwrap_err()
+                .code,
+            "misaligned"
+        );
+        assert_eq!(
+            locate(Family::HPM6700_6400, 0x0008_0000, 3, Width::Half)
+                .unwrap_err()
+                .code,
+            "misaligned"
+        );
+        assert_eq!(
+            locate(Family::HPM5300, 0x0108_0000, 4, Width::Word)
+                .unwrap_err()
+                .to_string(),
+            "HPM5300 has no XRAM"
+        );
+        assert_eq!(
+            locate(Family::HPM5300, 0x0001_FFFC, 8, Width::Word)
+                .unwrap_err()
+                .code,
+            "out_of_range"
+        );
+        assert_eq!(
+            locate(Family::HPM6700_6400, 0x0001_FFFC, 8, Width::Word).unwrap(),
+            MemoryId::ILM
+        );
+    }
+
+    #[test]
+    fn formats_hexdump_by_width() {
+        let data: Vec<u8> = (0x40..0x54).collect();
+
+        assert_eq!(
+            hexdump(0x8000_0000, &data, Width::Byte),
+            [
+                "80000000  40 41 42 43 44 45 46 47 48 49 4a 4b 4c 4d 4e 4f  |@ABCDEFGHIJKLMNO|"
+                    .to_string(),
+                format!("80000010  50 51 52 53{}  |PQRS|", " ".repeat(36)),
+            ]
+        );
+        assert_eq!(
+            hexdump(0x8000_0000, &data[..8], Width::Word),
+            [format!(
+                "80000000  43424140 47464544{}  |@ABCDEFG|",
+                " ".repeat(18)
+            )]
+        );
+        assert_eq!(
+            hexdump(0, &data[..4], Width::Half),
+            [format!("00000000  4140 4342{}  |@ABC|", " ".repeat(30))]
+        );
+    }
+
+    #[test]
+    fn writes_and_reads_values() {
+        let mut session = Session::new(SimulatedDevice::new(Family::HPM5300));
+
+        let result = mem_write(
+            &mut session,
+            Family::HPM5300,
+            MemoryConfig::new(),
+            0x0008_0010,
+            vec![0x1234, 0xABCD],
+            Width::Half,
+        )
+        .unwrap();
+        assert_eq!(result.memory, "DLM");
+
+        let result = mem_read(
+            &mut session,
+            Family::HPM5300,
+            MemoryConfig::new(),
+            0x0008_0010,
+            8,
+            Width::Word,
+        )
+        .unwrap();
+        assert_eq!(result.values, [0xABCD_1234, 0]);
+
+        let result = mem_read(
+            &mut session,
+            Family::HPM5300,
+            MemoryConfig::new(),
+            0x8000_0000,
+            2,
+            Width::Byte,
+        )
+        .unwrap();
+        assert_eq!(result.values, [0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn fills_region_with_pattern() {
+        let mut session = Session::new(SimulatedDevice::new(Family::HPM6300));
+
+        mem_fill(
+            &mut session,
+            Family::HPM6300,
+            MemoryConfig::new(),
+            (0x0108_0002, 6),
+            0xA55A,
+            Width::Half,
+            NoProgress,
+        )
+        .unwrap();
+
+        let device = session.into_inner();
+        assert_eq!(
+            device.memory(MemoryId::XRAM, 0, 10),
+            [0, 0, 0x5A, 0xA5, 0x5A, 0xA5, 0x5A, 0xA5, 0, 0]
+        );
+    }
+
+    #[test]
+    fn keeps_rest_of_xpi_sectors() {
+        let mut session = Session::new(SimulatedDevice::new(Family::HPM5300));
+        let options = FlashOptions::new(MemoryId::XPI0);
+        session
+            .flash_image(&[0x11; 0x2000], &options, NoProgress)
+            .unwrap();
+
+        mem_write(
+            &mut session,
+            Family::HPM5300,
+            MemoryConfig::new(),
+            0x8000_0FFE,
+            vec![0xABCD],
+            Width::Half,
+        )
+        .unwrap();
+        mem_fill(
+            &mut session,
+            Family::HPM5300,
+            MemoryConfig::new(),
+            (0x8000_1800, 4),
+            0,
+            Width::Word,
+            NoProgress,
+        )
+        .unwrap();
+
+        let device = session.into_inner();
+        assert_eq!(device.memory(MemoryId::XPI0, 0, 1), [0x11]);
+        assert_eq!(
+            device.memory(MemoryId::XPI0, 0x0FFD, 4),
+            [0x11, 0xCD, 0xAB, 0x11]
+        );
+        assert_eq!(
+            device.memory(MemoryId::XPI0, 0x17FF, 6),
+            [0x11, 0, 0, 0, 0, 0x11]
+        );
+        assert_eq!(device.memory(MemoryId::XPI0, 0x1FFF, 1), [0x11]);
+    }
+
+    #[test]
+    fn rejects_values_wider_than_access() {
+        let mut session = Session::new(SimulatedDevice::new(Family::HPM5300));
+
+        let result = mem_write(
+            &mut session,
+            Family::HPM5300,
+            MemoryConfig::new(),
+            0,
+            vec![0x100],
+            Width::Byte,
+        );
+
+        assert!(matches!(result, Err(e) if e.to_string().contains("doesn't fit in 8 bits")));
+    }
+}
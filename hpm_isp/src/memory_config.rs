@@ -5,6 +5,9 @@ use hpm_rt::{
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+use crate::hid::Family;
+use crate::isp_command::MemoryId;
+
 const MEMORY_CONFIG_LEN: usize = 12;
 
 #[derive(Debug, Error)]
@@ -114,18 +117,23 @@ impl From<QuadIOEnableSequence> for HpmQuadIOEnableSequence {
     }
 }
 
-#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq, Serialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct MemoryConfig {
-    flash_type: FlashType,
-    port_connection: PortConnection,
-    pin_group: PinGroup,
-    quad_io_enable_sequence: QuadIOEnableSequence,
+    pub flash_type: FlashType,
+    pub port_connection: PortConnection,
+    pub pin_group: PinGroup,
+    pub quad_io_enable_sequence: QuadIOEnableSequence,
 }
 
 impl MemoryConfig {
-    pub fn new() -> Self {
-        Self::default()
+    pub const fn new() -> Self {
+        Self {
+            flash_type: FlashType::SfdpSdr,
+            port_connection: PortConnection::PortACs0,
+            pin_group: PinGroup::Group1,
+            quad_io_enable_sequence: QuadIOEnableSequence::None,
+        }
     }
 
     pub fn from_toml_str(config: &str) -> Result<Self, ConfigError> {
@@ -136,22 +144,22 @@ impl MemoryConfig {
         Ok(toml::to_string_pretty(self)?)
     }
 
-    pub fn flash_type(mut self, flash_type: FlashType) -> Self {
+    pub const fn flash_type(mut self, flash_type: FlashType) -> Self {
         self.flash_type = flash_type;
         self
     }
 
-    pub fn port_connection(mut self, port_connection: PortConnection) -> Self {
+    pub const fn port_connection(mut self, port_connection: PortConnection) -> Self {
         self.port_connection = port_connection;
         self
     }
 
-    pub fn pin_group(mut self, pin_group: PinGroup) -> Self {
+    pub const fn pin_group(mut self, pin_group: PinGroup) -> Self {
         self.pin_group = pin_group;
         self
     }
 
-    pub fn quad_io_enable_sequence(
+    pub const fn quad_io_enable_sequence(
         mut self,
         quad_io_enable_sequence: QuadIOEnableSequence,
     ) -> Self {
@@ -176,10 +184,104 @@ impl MemoryConfig {
     }
 }
 
+/// Flash wiring of an HPMicro evaluation board
+#[derive(Debug, Clone, Copy)]
+pub struct BoardPreset {
+    /// Name used to select the preset, e.g. `hpm6750evk2`
+    pub name: &'static str,
+    pub family: Family,
+    /// XPI the board boots from
+    pub xpi: MemoryId,
+    pub memory_config: MemoryConfig,
+}
+
+/// Presets of the official evaluation boards, following the `nor_cfg_option`
+/// of their board support files in the HPM SDK
+pub const BOARD_PRESETS: &[BoardPreset] = &[
+    BoardPreset {
+        name: "hpm6750evk",
+        family: Family::HPM6700_6400,
+        xpi: MemoryId::XPI0,
+        memory_config: MemoryConfig::new(),
+    },
+    BoardPreset {
+        name: "hpm6750evk2",
+        family: Family::HPM6700_6400,
+        xpi: MemoryId::XPI0,
+        memory_config: MemoryConfig::new(),
+    },
+    BoardPreset {
+        name: "hpm6750evkmini",
+        family: Family::HPM6700_6400,
+        xpi: MemoryId::XPI0,
+        memory_config: MemoryConfig::new(),
+    },
+    BoardPreset {
+        name: "hpm6300evk",
+        family: Family::HPM6300,
+        xpi: MemoryId::XPI0,
+        memory_config: MemoryConfig::new(),
+    },
+    BoardPreset {
+        name: "hpm6200evk",
+        family: Family::HPM6200,
+        xpi: MemoryId::XPI0,
+        memory_config: MemoryConfig::new(),
+    },
+    BoardPreset {
+        name: "hpm5300evk",
+        family: Family::HPM5300,
+        xpi: MemoryId::XPI0,
+        memory_config: MemoryConfig::new().pin_group(PinGroup::Group2),
+    },
+    BoardPreset {
+        name: "hpm6800evk",
+        family: Family::HPM6800,
+        xpi: MemoryId::XPI0,
+        memory_config: MemoryConfig::new().pin_group(PinGroup::Group2),
+    },
+    BoardPreset {
+        name: "hpm6e00evk",
+        family: Family::HPM6E00,
+        xpi: MemoryId::XPI0,
+        memory_config: MemoryConfig::new().pin_group(PinGroup::Group2),
+    },
+];
+
+/// Memory config of a config file, or of the `[memory_config]` table's keys
+/// alone
+pub fn parse_memory_config(toml: &str) -> Result<MemoryConfig, toml::de::Error> {
+    let mut table: toml::Table = toml::from_str(toml)?;
+    match table.remove("memory_config") {
+        Some(memory_config) => memory_config.try_into(),
+        None => toml::Value::Table(table).try_into(),
+    }
+}
+
+/// Find a board preset by name, ignoring case
+pub fn board_preset(name: &str) -> Option<&'static BoardPreset> {
+    BOARD_PRESETS
+        .iter()
+        .find(|preset| preset.name.eq_ignore_ascii_case(name))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn parses_config_file_or_table() {
+        let expected = MemoryConfig::new().pin_group(PinGroup::Group2);
+        let file =
+            parse_memory_config("[memory_config]\npin_group = \"group2\"\n[profiles.evk]\nxpi = 1");
+        assert_eq!(file.unwrap(), expected);
+        assert_eq!(
+            parse_memory_config("pin_group = \"group2\"").unwrap(),
+            expected
+        );
+        assert!(parse_memory_config("pin_group = \"group3\"").is_err());
+    }
+
     #[test]
     fn parses_toml_config_values() {
         let config = MemoryConfig::from_toml_str(
@@ -219,6 +321,14 @@ quad_io_enable_sequence = "status2_bit1_programmed_by_0x31"
         assert!(config.contains("flash_type = \"sfdp_sdr\""));
     }
 
+    #[test]
+    fn finds_board_presets() {
+        let preset = board_preset("HPM5300EVK").unwrap();
+        assert_eq!(preset.family, Family::HPM5300);
+        assert_eq!(preset.memory_config.pin_group, PinGroup::Group2);
+        assert!(board_preset("hpm9999evk").is_none());
+    }
+
     #[test]
     fn writes_bootrom_config_magic() {
         let config = MemoryConfig::default().to_bootrom_config();
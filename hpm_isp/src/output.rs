@@ -0,0 +1,178 @@
+use std::error::Error;
+use std::fmt::Display;
+use std::ops::ControlFlow;
+use std::time::{Duration, Instant};
+
+use clap::ArgEnum;
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::Serialize;
+use serde_json::json;
+
+use hpm_isp::isp_command;
+use hpm_isp::progress::ProgressEvent;
+
+/// Minimum interval between two JSON progress events
+const JSON_PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum OutputFormat {
+    /// Human readable text and progress bars
+    Text,
+    /// JSON lines on stdout, one object per event
+    Json,
+}
+
+/// Reports messages, progress and results in the selected output format
+///
+/// Every JSON line carries an `event` field: `message`, `progress`, `result`
+/// or `error`. Text output goes to stderr, except results which go to stdout
+/// unless stdout carries data.
+pub(crate) struct Reporter {
+    format: OutputFormat,
+    stdout_is_data: bool,
+}
+
+impl Reporter {
+    pub(crate) fn new(format: OutputFormat, stdout_is_data: bool) -> Self {
+        Self {
+            format,
+            stdout_is_data,
+        }
+    }
+
+    pub(crate) fn is_json(&self) -> bool {
+        self.format == OutputFormat::Json
+    }
+
+    pub(crate) fn message(&self, message: &str) {
+        match self.format {
+            OutputFormat::Text => eprintln!("{message}"),
+            OutputFormat::Json => print_json(json!({ "event": "message", "message": message })),
+        }
+    }
+
+    pub(crate) fn progress(&self) -> ProgressReporter {
+        match self.format {
+            OutputFormat::Text => ProgressReporter::Bar(new_progress_bar()),
+            OutputFormat::Json => ProgressReporter::Json { last: None },
+        }
+    }
+
+    /// Report the final result of a command
+    pub(crate) fn result<T>(&self, result: &T)
+    where
+        T: Serialize + Display,
+    {
+        match self.format {
+            OutputFormat::Text if self.stdout_is_data => eprintln!("{result}"),
+            OutputFormat::Text => println!("{result}"),
+            OutputFormat::Json => {
+                let mut value = serde_json::to_value(result).unwrap();
+                value["event"] = json!("result");
+                print_json(value);
+            }
+        }
+    }
+
+    pub(crate) fn error(&self, error: &(dyn Error + 'static)) {
+        match self.format {
+            OutputFormat::Text => eprintln!("Error: {error}"),
+            OutputFormat::Json => {
+                let mut value = json!({
+                    "event": "error",
+                    "code": error_code(error),
+                    "message": error.to_string(),
+                });
+                if let Some(isp_command::Error::Other(status)) = error.downcast_ref() {
+                    value["status"] = json!(status);
+                }
+                print_json(value);
+            }
+        }
+    }
+}
+
+/// Error of the command line tool with a stable code
+#[derive(Debug)]
+pub(crate) struct CodedError {
+    pub(crate) code: &'static str,
+    pub(crate) message: String,
+}
+
+impl CodedError {
+    pub(crate) fn new(code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for CodedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for CodedError {}
+
+/// Stable identifier of an error, for machine-readable output
+pub(crate) fn error_code(error: &(dyn Error + 'static)) -> &'static str {
+    if let Some(e) = error.downcast_ref::<isp_command::Error>() {
+        e.code()
+    } else if let Some(e) = error.downcast_ref::<CodedError>() {
+        e.code
+    } else {
+        "error"
+    }
+}
+
+fn print_json(value: serde_json::Value) {
+    println!("{value}");
+}
+
+pub(crate) enum ProgressReporter {
+    Bar(ProgressBar),
+    Json { last: Option<Instant> },
+}
+
+impl ProgressReporter {
+    pub(crate) fn update(&mut self, event: &ProgressEvent) -> ControlFlow<()> {
+        match self {
+            ProgressReporter::Bar(pb) => {
+                pb.set_message(event.phase.as_str());
+                pb.set_length(event.total as u64);
+                pb.set_position(event.bytes as u64);
+            }
+            ProgressReporter::Json { last } => {
+                let due = last.is_none_or(|last| last.elapsed() >= JSON_PROGRESS_INTERVAL);
+                if due || event.bytes == event.total {
+                    *last = Some(Instant::now());
+                    print_json(json!({
+                        "event": "progress",
+                        "phase": event.phase.as_str(),
+                        "bytes": event.bytes,
+                        "total": event.total,
+                        "throughput": event.throughput(),
+                    }));
+                }
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    pub(crate) fn finish(self) {
+        if let ProgressReporter::Bar(pb) = self {
+            pb.finish();
+        }
+    }
+}
+
+fn new_progress_bar() -> ProgressBar {
+    let pb = ProgressBar::new(0);
+    pb.set_style(ProgressStyle::default_bar()
+        .template("{spinner:.green} {msg} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})")
+        .unwrap()
+        .progress_chars("#>-"));
+    pb
+}
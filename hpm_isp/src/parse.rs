@@ -0,0 +1,152 @@
+use strum::IntoEnumIterator;
+
+use hpm_isp::hid::Family;
+
+/// Parse an address or a size
+///
+/// Accepts decimal, `0x`/`0X` hex and `0b`/`0B` binary numbers with optional
+/// `_` separators, `K`/`M`/`G` size suffixes (with optional `B` or `iB`, all
+/// powers of 1024) and sums or differences of these terms.
+///
+/// # Example
+///
+/// ```ignore
+/// assert_eq!(parse_number("0x8000_0000+0x400"), Ok(0x8000_0400));
+/// assert_eq!(parse_number("64K"), Ok(0x10000));
+/// ```
+pub(crate) fn parse_number(s: &str) -> Result<u32, String> {
+    let s: String = s.chars().filter(|c| !c.is_whitespace()).collect();
+    if s.is_empty() {
+        return Err("empty number".to_string());
+    }
+
+    let mut result: u32 = 0;
+    let mut negative = false;
+    let mut term_start = 0;
+    for (i, c) in s.char_indices().chain([(s.len(), '+')]) {
+        if c != '+' && c != '-' {
+            continue;
+        }
+
+        let term = parse_term(&s[term_start..i])?;
+        result = if negative {
+            result.checked_sub(term)
+        } else {
+            result.checked_add(term)
+        }
+        .ok_or_else(|| format!("{s} is out of range"))?;
+        negative = c == '-';
+        term_start = i + 1;
+    }
+
+    Ok(result)
+}
+
+/// Parse a region given as `START:LENGTH`, e.g. `0x80100000:64K`
+pub(crate) fn parse_region(s: &str) -> Result<(u32, u32), String> {
+    let (start, length) = s
+        .split_once(':')
+        .ok_or_else(|| "region must be START:LENGTH".to_string())?;
+    Ok((parse_number(start)?, parse_number(length)?))
+}
+
+/// Parse a chip family as named in config files, ignoring case, e.g. `hpm5300`
+pub(crate) fn parse_family(s: &str) -> Result<Family, String> {
+    toml::Value::String(s.to_ascii_uppercase())
+        .try_into()
+        .map_err(|_| {
+            let families: Vec<String> = Family::iter().map(|family| family.to_string()).collect();
+            format!("unknown family {s}, expected {}", families.join(", "))
+        })
+}
+
+fn parse_term(term: &str) -> Result<u32, String> {
+    let lower = term.to_ascii_lowercase();
+    let (number, multiplier) = split_suffix(&lower);
+
+    let digits = |s: &str| s.replace('_', "");
+    let value = if let Some(hex) = number.strip_prefix("0x") {
+        u32::from_str_radix(&digits(hex), 16)
+    } else if let Some(bin) = number.strip_prefix("0b") {
+        u32::from_str_radix(&digits(bin), 2)
+    } else {
+        digits(number).parse::<u32>()
+    }
+    .map_err(|e| format!("invalid number {term}: {e}"))?;
+
+    value
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("{term} is out of range"))
+}
+
+fn split_suffix(term: &str) -> (&str, u32) {
+    // Hex digits may end with `b`, only decimal numbers take size suffixes
+    if term.starts_with("0x") || term.starts_with("0b") {
+        return (term, 1);
+    }
+
+    let unit = term.trim_end_matches("ib").trim_end_matches('b');
+    match unit.chars().last() {
+        Some('k') => (&unit[..unit.len() - 1], 1 << 10),
+        Some('m') => (&unit[..unit.len() - 1], 1 << 20),
+        Some('g') => (&unit[..unit.len() - 1], 1 << 30),
+        _ => (term, 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_plain_numbers() {
+        assert_eq!(parse_number("1024"), Ok(1024));
+        assert_eq!(parse_number("0x400"), Ok(0x400));
+        assert_eq!(parse_number("0X4aB"), Ok(0x4ab));
+        assert_eq!(parse_number("0b101"), Ok(5));
+        assert_eq!(parse_number("0x8000_0000"), Ok(0x8000_0000));
+        assert_eq!(parse_number("1_000"), Ok(1000));
+    }
+
+    #[test]
+    fn parses_size_suffixes() {
+        assert_eq!(parse_number("64K"), Ok(0x10000));
+        assert_eq!(parse_number("64KiB"), Ok(0x10000));
+        assert_eq!(parse_number("1M"), Ok(0x10_0000));
+        assert_eq!(parse_number("2mb"), Ok(0x20_0000));
+        assert_eq!(parse_number("1G"), Ok(0x4000_0000));
+    }
+
+    #[test]
+    fn parses_expressions() {
+        assert_eq!(parse_number("0x80000000+0x400"), Ok(0x8000_0400));
+        assert_eq!(parse_number("1M - 4K"), Ok(0xF_F000));
+        assert_eq!(parse_number("0x1000+16+0x10"), Ok(0x1020));
+    }
+
+    #[test]
+    fn rejects_invalid_numbers() {
+        assert!(parse_number("").is_err());
+        assert!(parse_number("0x").is_err());
+        assert!(parse_number("12q").is_err());
+        assert!(parse_number("4G").is_err());
+        assert!(parse_number("0xFFFFFFFF+1").is_err());
+        assert!(parse_number("1-2").is_err());
+        assert!(parse_number("1++2").is_err());
+    }
+
+    #[test]
+    fn parses_regions() {
+        assert_eq!(parse_region("0x3000:64K"), Ok((0x3000, 0x10000)));
+        assert_eq!(parse_region("0x80003000:0x1000"), Ok((0x8000_3000, 0x1000)));
+        assert!(parse_region("0x3000").is_err());
+        assert!(parse_region("0x3000:").is_err());
+    }
+
+    #[test]
+    fn parses_families() {
+        assert_eq!(parse_family("hpm5300"), Ok(Family::HPM5300));
+        assert_eq!(parse_family("HPM6400"), Ok(Family::HPM6700_6400));
+        assert!(parse_family("HPM9999").is_err());
+    }
+}
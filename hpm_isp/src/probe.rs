@@ -0,0 +1,315 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+use hpm_isp::boot_image::{NOR_CFG_OPTION_OFFSET, NOR_CFG_OPTION_TAG};
+use hpm_isp::isp_command::{Error, IspCommand, MemoryId};
+use hpm_isp::memory_config::{
+    FlashType, MemoryConfig, PinGroup, PortConnection, QuadIOEnableSequence,
+};
+use hpm_isp::progress::NoProgress;
+use hpm_isp::session::MEMORY_CONFIG_OFFSET;
+
+use crate::config::describe_memory_config;
+
+/// Flash types to try, SPI NOR first
+pub(crate) const FLASH_TYPES: [FlashType; 9] = [
+    FlashType::SfdpSdr,
+    FlashType::SfdpDdr,
+    FlashType::Read144,
+    FlashType::Read122,
+    FlashType::OctaBusDdr,
+    FlashType::XccelaDdr,
+    FlashType::EcoXipDdr,
+    FlashType::HyperBus3v3,
+    FlashType::HyperBus1v8,
+];
+
+pub(crate) const PORT_CONNECTIONS: [PortConnection; 5] = [
+    PortConnection::PortACs0,
+    PortConnection::PortBCs0,
+    PortConnection::PortACs0PortBCs0,
+    PortConnection::PortACs0PortACs1,
+    PortConnection::PortBCs0PortBCs1,
+];
+
+pub(crate) const PIN_GROUPS: [PinGroup; 2] = [PinGroup::Group1, PinGroup::Group2];
+
+pub(crate) struct ProbeOptions {
+    pub(crate) memory_id: MemoryId,
+    /// Region read back after configuring the memory
+    pub(crate) offset: u32,
+    pub(crate) size: u32,
+    /// Data expected in the region
+    pub(crate) expected: Option<Vec<u8>>,
+}
+
+/// Memory configs to try, the most common wiring first
+pub(crate) fn candidates() -> Vec<MemoryConfig> {
+    let mut candidates = Vec::new();
+    for flash_type in FLASH_TYPES {
+        for port_connection in PORT_CONNECTIONS {
+            for pin_group in PIN_GROUPS {
+                candidates.push(
+                    MemoryConfig::new()
+                        .flash_type(flash_type)
+                        .port_connection(port_connection)
+                        .pin_group(pin_group)
+                        .quad_io_enable_sequence(QuadIOEnableSequence::None),
+                );
+            }
+        }
+    }
+    candidates
+}
+
+/// A memory config which could be configured and read back
+#[derive(Serialize)]
+pub(crate) struct ProbeMatch {
+    memory_config: MemoryConfig,
+    /// A NOR config option, as written at the start of a boot image, was read
+    /// back
+    boot_image: bool,
+    /// Whether the region read back matches the expected data
+    matches: Option<bool>,
+}
+
+impl ProbeMatch {
+    /// Higher is a better match
+    fn score(&self) -> u8 {
+        match self.matches {
+            Some(false) => 0,
+            Some(true) => 2 + self.boot_image as u8,
+            None => 1 + self.boot_image as u8,
+        }
+    }
+}
+
+/// Try every candidate memory config, writing it to ILM, configuring the
+/// memory with it and reading back a region
+///
+/// `on_match` is called for each working config as it's found.
+pub(crate) fn probe<D, F>(
+    device: &D,
+    options: &ProbeOptions,
+    mut on_match: F,
+) -> Result<ProbeResult, Error>
+where
+    D: IspCommand,
+    F: FnMut(&ProbeMatch),
+{
+    let candidates = candidates();
+    let mut matches = Vec::new();
+    let mut data = vec![0u8; options.size as usize];
+    let mut header = [0u8; 4];
+
+    for memory_config in &candidates {
+        // Writing to ILM doesn't depend on the candidate, so it must work
+        device.write_memory(
+            MemoryId::ILM,
+            MEMORY_CONFIG_OFFSET,
+            &memory_config.to_bootrom_config(),
+            NoProgress,
+        )?;
+        let configured = device
+            .configure_memory(
+                options.memory_id,
+                MemoryId::ILM.base_address() + MEMORY_CONFIG_OFFSET,
+            )
+            .and_then(|()| {
+                device.read_memory(options.memory_id, options.offset, &mut data, NoProgress)
+            });
+        match configured {
+            Ok(()) => (),
+            // The BootROM answers a status if the config doesn't suit the flash
+            Err(Error::Other(_)) => continue,
+            Err(e) => return Err(e),
+        }
+
+        let boot_image = device
+            .read_memory(
+                options.memory_id,
+                NOR_CFG_OPTION_OFFSET as u32,
+                &mut header,
+                NoProgress,
+            )
+            .is_ok_and(|()| has_nor_cfg_option(&header));
+        let found = ProbeMatch {
+            memory_config: *memory_config,
+            boot_image,
+            matches: options
+                .expected
+                .as_ref()
+                .map(|expected| data.starts_with(expected) || expected.starts_with(&data)),
+        };
+        on_match(&found);
+        matches.push(found);
+    }
+
+    let best = best_match(&matches).map(|found| found.memory_config);
+    Ok(ProbeResult {
+        command: "probe",
+        memory: options.memory_id.to_string(),
+        tried: candidates.len(),
+        matches,
+        best,
+        saved: None,
+    })
+}
+
+fn has_nor_cfg_option(header: &[u8; 4]) -> bool {
+    u16::from_le_bytes([header[2], header[3]]) == NOR_CFG_OPTION_TAG
+}
+
+/// The first of the highest scoring matches, or none if the expected data
+/// wasn't read with any config
+fn best_match(matches: &[ProbeMatch]) -> Option<&ProbeMatch> {
+    let best = matches.iter().map(ProbeMatch::score).max()?;
+    matches
+        .iter()
+        .find(|found| found.score() == best)
+        .filter(|found| found.score() > 0)
+}
+
+impl fmt::Display for ProbeMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", describe_memory_config(&self.memory_config))?;
+        if self.boot_image {
+            write!(f, " (boot image found)")?;
+        }
+        match self.matches {
+            Some(true) => write!(f, " (expected data read)"),
+            Some(false) => write!(f, " (unexpected data read)"),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct ProbeResult {
+    command: &'static str,
+    memory: String,
+    tried: usize,
+    matches: Vec<ProbeMatch>,
+    pub(crate) best: Option<MemoryConfig>,
+    pub(crate) saved: Option<PathBuf>,
+}
+
+impl fmt::Display for ProbeResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} memory configs work with {}",
+            self.matches.len(),
+            self.tried,
+            self.memory
+        )?;
+        for found in &self.matches {
+            write!(f, "\n  {found}")?;
+        }
+        if let Some(best) = &self.best {
+            write!(f, "\nBest match: {}", describe_memory_config(best))?;
+        }
+        if let Some(path) = &self.saved {
+            write!(f, "\nMemory config saved to: {}", path.display())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hpm_isp::hid::Family;
+    use hpm_isp::isp_command::{Interface, Packet};
+    use hpm_isp::sim::SimulatedDevice;
+
+    use super::*;
+
+    fn found(pin_group: PinGroup, boot_image: bool, matches: Option<bool>) -> ProbeMatch {
+        ProbeMatch {
+            memory_config: MemoryConfig::new().pin_group(pin_group),
+            boot_image,
+            matches,
+        }
+    }
+
+    #[test]
+    fn tries_common_wiring_first() {
+        let candidates = candidates();
+        assert_eq!(candidates.len(), 90);
+        assert_eq!(
+            describe_memory_config(&candidates[0]),
+            "sfdp_sdr, port_a_cs0, group1, none"
+        );
+    }
+
+    #[test]
+    fn ranks_matches() {
+        let matches = [
+            found(PinGroup::Group1, false, None),
+            found(PinGroup::Group2, true, None),
+        ];
+        let best = best_match(&matches).unwrap();
+        assert!(best.boot_image);
+
+        let matches = [
+            found(PinGroup::Group1, true, Some(false)),
+            found(PinGroup::Group2, false, Some(true)),
+        ];
+        assert_eq!(best_match(&matches).unwrap().matches, Some(true));
+
+        let matches = [found(PinGroup::Group1, true, Some(false))];
+        assert!(best_match(&matches).is_none());
+    }
+
+    /// Simulated device whose memory configuration fails
+    struct FailingConfigure {
+        device: SimulatedDevice,
+        error: fn() -> Error,
+    }
+
+    impl Interface for FailingConfigure {
+        fn write(&self, packet: &Packet, length: u16) -> Result<(), Error> {
+            self.device.write(packet, length)
+        }
+
+        fn read(&self, packet: &mut Packet) -> Result<u16, Error> {
+            self.device.read(packet)
+        }
+    }
+
+    impl IspCommand for FailingConfigure {
+        fn configure_memory(&self, _: MemoryId, _: u32) -> Result<(), Error> {
+            Err((self.error)())
+        }
+    }
+
+    #[test]
+    fn skips_only_rejected_configs() {
+        let options = ProbeOptions {
+            memory_id: MemoryId::XPI0,
+            offset: 0,
+            size: 0x100,
+            expected: None,
+        };
+        let device = |error| FailingConfigure {
+            device: SimulatedDevice::new(Family::HPM5300),
+            error,
+        };
+
+        let result = probe(&device(|| Error::Other(1)), &options, |_| ()).unwrap();
+        assert!(result.matches.is_empty());
+        assert!(matches!(
+            probe(&device(|| Error::Timeout), &options, |_| ()),
+            Err(Error::Timeout)
+        ));
+    }
+
+    #[test]
+    fn detects_nor_cfg_option() {
+        let header = MemoryConfig::new().to_bootrom_config();
+        assert!(has_nor_cfg_option(&header[..4].try_into().unwrap()));
+        assert!(!has_nor_cfg_option(&[0xFF; 4]));
+    }
+}
@@ -0,0 +1,132 @@
+use std::ops::ControlFlow;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+
+/// Stage of an ISP operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Configuring memory with a configuration block
+    Configure,
+    /// Erasing memory
+    Erase,
+    /// Writing memory
+    Write,
+    /// Reading memory
+    Read,
+    /// Reading back memory and comparing it with the written data
+    Verify,
+}
+
+impl Phase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Phase::Configure => "configure",
+            Phase::Erase => "erase",
+            Phase::Write => "write",
+            Phase::Read => "read",
+            Phase::Verify => "verify",
+        }
+    }
+}
+
+/// Progress of a running operation
+#[derive(Debug, Clone, Copy)]
+pub struct ProgressEvent {
+    pub phase: Phase,
+    /// Bytes transferred so far
+    pub bytes: usize,
+    /// Total bytes of this phase
+    pub total: usize,
+    /// Time elapsed since the phase started
+    pub elapsed: Duration,
+}
+
+impl ProgressEvent {
+    /// Average throughput in bytes per second
+    pub fn throughput(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.bytes as f64 / secs
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Receiver of progress events
+///
+/// Returning [`ControlFlow::Break`] aborts the running operation, the device is
+/// notified with an abort packet and the operation fails with
+/// [`Error::Aborted`](crate::isp_command::Error::Aborted).
+///
+/// It is implemented for closures taking a [`ProgressEvent`], and for
+/// [`Sender`] so events can be forwarded to another thread. A sender aborts
+/// the operation once its receiver is dropped.
+///
+/// # Example
+///
+/// ```ignore
+/// device.write_memory(MemoryId::XPI0, 0x400, &data, |event: &ProgressEvent| {
+///     println!("{}: {}/{}", event.phase.as_str(), event.bytes, event.total);
+///     ControlFlow::Continue(())
+/// })?;
+/// ```
+pub trait Progress {
+    fn update(&mut self, event: &ProgressEvent) -> ControlFlow<()>;
+}
+
+impl<F> Progress for F
+where
+    F: FnMut(&ProgressEvent) -> ControlFlow<()>,
+{
+    fn update(&mut self, event: &ProgressEvent) -> ControlFlow<()> {
+        self(event)
+    }
+}
+
+impl Progress for Sender<ProgressEvent> {
+    fn update(&mut self, event: &ProgressEvent) -> ControlFlow<()> {
+        match self.send(*event) {
+            Ok(()) => ControlFlow::Continue(()),
+            Err(_) => ControlFlow::Break(()),
+        }
+    }
+}
+
+/// Progress receiver that ignores all events
+pub struct NoProgress;
+
+impl Progress for NoProgress {
+    fn update(&mut self, _: &ProgressEvent) -> ControlFlow<()> {
+        ControlFlow::Continue(())
+    }
+}
+
+/// Tracks the elapsed time of one phase and builds its events
+pub(crate) struct PhaseTracker {
+    phase: Phase,
+    total: usize,
+    start: Instant,
+}
+
+impl PhaseTracker {
+    pub(crate) fn new(phase: Phase, total: usize) -> Self {
+        Self {
+            phase,
+            total,
+            start: Instant::now(),
+        }
+    }
+
+    pub(crate) fn report<P>(&self, progress: &mut P, bytes: usize) -> ControlFlow<()>
+    where
+        P: Progress + ?Sized,
+    {
+        progress.update(&ProgressEvent {
+            phase: self.phase,
+            bytes,
+            total: self.total,
+            elapsed: self.start.elapsed(),
+        })
+    }
+}
@@ -0,0 +1,269 @@
+//! Memory self-test with pseudo-random patterns
+//!
+//! Each pass writes a pattern to the region, reads it back and counts the
+//! flipped bits. Odd passes write the complement of the pass before, so with
+//! the default two passes every bit is written both as 0 and as 1. The
+//! patterns only depend on the seed, so a failing run can be repeated with
+//! `--seed`.
+
+use std::error::Error;
+use std::fmt;
+use std::ops::ControlFlow;
+use std::time::Instant;
+
+use serde::Serialize;
+
+use hpm_isp::hid::Family;
+use hpm_isp::isp_command::IspCommand;
+use hpm_isp::memory_config::MemoryConfig;
+use hpm_isp::progress::ProgressEvent;
+use hpm_isp::session::{FlashOptions, Session};
+
+use crate::mem::{locate, Width};
+
+/// Errors listed in the result, the counts cover all of them
+const MAX_REPORTED_ERRORS: usize = 64;
+
+/// SplitMix64, small and good enough to not repeat within a flash
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+/// Data written by a pass
+fn pattern(seed: u32, pass: u32, length: usize) -> Vec<u8> {
+    let mut rng = SplitMix64(u64::from(seed) << 32 | u64::from(pass / 2));
+    let mut data: Vec<u8> = (0..length.div_ceil(8))
+        .flat_map(|_| rng.next().to_le_bytes())
+        .take(length)
+        .collect();
+    if pass % 2 == 1 {
+        data.iter_mut().for_each(|byte| *byte = !*byte);
+    }
+    data
+}
+
+/// A byte read back with flipped bits
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct ByteError {
+    pass: u32,
+    address: u32,
+    expected: u8,
+    actual: u8,
+}
+
+#[derive(Serialize)]
+pub(crate) struct SelftestResult {
+    command: &'static str,
+    memory: String,
+    address: u32,
+    length: u32,
+    seed: u32,
+    passes: u32,
+    bit_errors: u64,
+    /// Bit errors by bit of the byte, 0 is the LSB, a data line with a bad
+    /// joint shows up as one bit failing much more often
+    bit_errors_by_bit: [u64; 8],
+    /// First bytes with errors, by pass and address
+    errors: Vec<ByteError>,
+    duration_ms: u128,
+}
+
+impl SelftestResult {
+    pub(crate) fn bit_errors(&self) -> u64 {
+        self.bit_errors
+    }
+
+    /// Count the bits of `actual` differing from `expected`, read at `address`
+    fn compare(&mut self, pass: u32, address: u32, expected: &[u8], actual: &[u8]) {
+        for (i, (&expected, &actual)) in expected.iter().zip(actual).enumerate() {
+            let flipped = expected ^ actual;
+            if flipped == 0 {
+                continue;
+            }
+            self.bit_errors += u64::from(flipped.count_ones());
+            for (bit, count) in self.bit_errors_by_bit.iter_mut().enumerate() {
+                *count += u64::from(flipped >> bit & 1);
+            }
+            if self.errors.len() < MAX_REPORTED_ERRORS {
+                self.errors.push(ByteError {
+                    pass,
+                    address: address + i as u32,
+                    expected,
+                    actual,
+                });
+            }
+        }
+    }
+}
+
+impl fmt::Display for SelftestResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Tested {:#x} bytes of {} at {:#010x} in {} pass(es) with seed {}: {} bit error(s)",
+            self.length, self.memory, self.address, self.passes, self.seed, self.bit_errors
+        )?;
+        if self.bit_errors == 0 {
+            return Ok(());
+        }
+        for error in &self.errors {
+            write!(
+                f,
+                "\n  pass {} {:#010x}: wrote {:#04x}, read {:#04x}",
+                error.pass, error.address, error.expected, error.actual
+            )?;
+        }
+        let bits: Vec<String> = self
+            .bit_errors_by_bit
+            .iter()
+            .enumerate()
+            .map(|(bit, count)| format!("{bit}:{count}"))
+            .collect();
+        write!(f, "\nBit errors by bit: {}", bits.join(" "))
+    }
+}
+
+/// Run `passes` write and read back passes over the region
+pub(crate) fn selftest<D, P>(
+    session: &mut Session<D>,
+    family: Family,
+    memory_config: MemoryConfig,
+    (address, length): (u32, u32),
+    seed: u32,
+    passes: u32,
+    mut progress: P,
+) -> Result<SelftestResult, Box<dyn Error>>
+where
+    D: IspCommand,
+    P: FnMut(&ProgressEvent) -> ControlFlow<()>,
+{
+    let memory_id = locate(family, address, length, Width::Byte)?;
+    let options = FlashOptions::new(memory_id)
+        .offset(address)
+        .memory_config(memory_config);
+    let mut result = SelftestResult {
+        command: "selftest",
+        memory: memory_id.to_string(),
+        address,
+        length,
+        seed,
+        passes,
+        bit_errors: 0,
+        bit_errors_by_bit: [0; 8],
+        errors: Vec::new(),
+        duration_ms: 0,
+    };
+    let start = Instant::now();
+
+    for pass in 0..passes {
+        let expected = pattern(seed, pass, length as usize);
+        session.flash_image(&expected, &options, &mut progress)?;
+        let actual = session.dump(expected.len(), &options, &mut progress)?;
+        result.compare(pass, address, &expected, &actual);
+    }
+
+    result.duration_ms = start.elapsed().as_millis();
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use hpm_isp::hid::Family;
+    use hpm_isp::isp_command::MemoryId;
+    use hpm_isp::sim::SimulatedDevice;
+
+    use super::*;
+
+    #[test]
+    fn patterns_depend_on_seed_only() {
+        assert_eq!(pattern(7, 0, 13), pattern(7, 0, 13));
+        assert_ne!(pattern(7, 0, 13), pattern(8, 0, 13));
+        assert_ne!(pattern(7, 0, 13), pattern(7, 2, 13));
+
+        let inverted: Vec<u8> = pattern(7, 0, 13).iter().map(|byte| !byte).collect();
+        assert_eq!(pattern(7, 1, 13), inverted);
+    }
+
+    #[test]
+    fn counts_bit_errors_by_bit() {
+        let mut session = Session::new(SimulatedDevice::new(Family::HPM5300));
+        let mut result = selftest(
+            &mut session,
+            Family::HPM5300,
+            MemoryConfig::new(),
+            (0x0008_0000, 0x40),
+            1,
+            1,
+            |_: &ProgressEvent| ControlFlow::Continue(()),
+        )
+        .unwrap();
+
+        result.compare(1, 0x100, &[0xA5, 0x0F, 0x00], &[0xA1, 0x0F, 0x05]);
+
+        assert_eq!(result.bit_errors(), 3);
+        assert_eq!(result.bit_errors_by_bit, [1, 0, 2, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            result.errors[1],
+            ByteError {
+                pass: 1,
+                address: 0x102,
+                expected: 0x00,
+                actual: 0x05,
+            }
+        );
+    }
+
+    #[test]
+    fn passes_on_working_memory() {
+        let mut session = Session::new(SimulatedDevice::new(Family::HPM5300));
+
+        let result = selftest(
+            &mut session,
+            Family::HPM5300,
+            MemoryConfig::new(),
+            (0x8001_0000, 0x1000),
+            42,
+            2,
+            |_: &ProgressEvent| ControlFlow::Continue(()),
+        )
+        .unwrap();
+
+        assert_eq!(result.bit_errors(), 0);
+        assert!(result.errors.is_empty());
+        let device = session.into_inner();
+        assert_eq!(
+            device.memory(MemoryId::XPI0, 0x1_0000, 0x1000),
+            pattern(42, 1, 0x1000)
+        );
+    }
+
+    #[test]
+    fn refuses_memory_the_family_lacks() {
+        let mut session = Session::new(SimulatedDevice::new(Family::HPM5300));
+        let mut run = |region| {
+            selftest(
+                &mut session,
+                Family::HPM5300,
+                MemoryConfig::new(),
+                region,
+                1,
+                1,
+                |_: &ProgressEvent| ControlFlow::Continue(()),
+            )
+            .map(|_| ())
+            .unwrap_err()
+            .to_string()
+        };
+
+        assert_eq!(run((0x0108_0000, 0x100)), "HPM5300 has no XRAM");
+        assert!(run((0x0001_F000, 0x2000)).contains("run past the end of ILM"));
+    }
+}
@@ -0,0 +1,570 @@
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use serde::Deserialize;
+
+use hpm_isp::session::SECTOR_SIZE;
+
+use crate::hex::decode_hex;
+use crate::output::CodedError;
+
+/// Per-unit data written into the image before programming
+///
+/// ```toml
+/// counter_file = "serial.counter"
+/// start = 1
+/// log_file = "serial_log.csv"
+///
+/// [[patch]]
+/// type = "counter"
+/// offset = 0x7F000
+/// format = "u32le"
+///
+/// [[patch]]
+/// type = "mac"
+/// offset = 0x7F010
+/// base = "02:00:00:00:00:00"
+/// ```
+///
+/// Relative paths are resolved against the directory of the rules file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SerialRules {
+    /// File holding the next serial number
+    counter_file: PathBuf,
+    /// First serial number, if the counter file doesn't exist yet
+    #[serde(default)]
+    start: u64,
+    /// CSV file recording which serial went to which device
+    log_file: Option<PathBuf>,
+    #[serde(default)]
+    patch: Vec<PatchRule>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub(crate) enum PatchRule {
+    /// Serial number as an integer
+    Counter {
+        offset: u32,
+        #[serde(default)]
+        format: IntFormat,
+    },
+    /// Serial number as text, `{serial}` in the template is replaced by the
+    /// zero padded serial number
+    Text {
+        offset: u32,
+        template: String,
+        #[serde(default)]
+        digits: usize,
+    },
+    /// MAC address, the base address plus the serial number
+    Mac { offset: u32, base: String },
+    /// Hex encoded bytes from the row of a CSV file whose `serial` column
+    /// matches the serial number
+    Csv {
+        offset: u32,
+        file: PathBuf,
+        column: String,
+    },
+}
+
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum IntFormat {
+    U16le,
+    U16be,
+    #[default]
+    U32le,
+    U32be,
+    U64le,
+    U64be,
+}
+
+impl IntFormat {
+    fn encode(&self, value: u64) -> Option<Vec<u8>> {
+        Some(match self {
+            IntFormat::U16le => u16::try_from(value).ok()?.to_le_bytes().to_vec(),
+            IntFormat::U16be => u16::try_from(value).ok()?.to_be_bytes().to_vec(),
+            IntFormat::U32le => u32::try_from(value).ok()?.to_le_bytes().to_vec(),
+            IntFormat::U32be => u32::try_from(value).ok()?.to_be_bytes().to_vec(),
+            IntFormat::U64le => value.to_le_bytes().to_vec(),
+            IntFormat::U64be => value.to_be_bytes().to_vec(),
+        })
+    }
+}
+
+/// How long to wait for another station to release the counter file
+const LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Data written to the flash in one go
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct FlashWrite {
+    pub(crate) offset: u32,
+    pub(crate) data: Vec<u8>,
+}
+
+/// Serial number of the unit being programmed, with its rules
+pub(crate) struct SerialProvisioning {
+    rules: SerialRules,
+    base_dir: PathBuf,
+    pub(crate) serial: u64,
+}
+
+impl SerialProvisioning {
+    /// Load the rules and take the next serial number from the counter file
+    ///
+    /// The counter is advanced right away, under a lock file, so stations
+    /// sharing it never get the same serial. Serials of failed units are
+    /// skipped.
+    pub(crate) fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let rules: SerialRules = toml::from_str(&fs::read_to_string(path)?)?;
+        let base_dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+
+        let counter_file = base_dir.join(&rules.counter_file);
+        let _lock = CounterLock::acquire(counter_file.with_extension("lock"), LOCK_TIMEOUT)?;
+        let serial: u64 = match fs::read_to_string(&counter_file) {
+            Ok(counter) => counter.trim().parse().map_err(|_| {
+                CodedError::new(
+                    "invalid_counter",
+                    format!("invalid serial counter in {}", counter_file.display()),
+                )
+            })?,
+            Err(e) if e.kind() == ErrorKind::NotFound => rules.start,
+            Err(e) => return Err(e.into()),
+        };
+        let next = serial.checked_add(1).ok_or_else(|| {
+            CodedError::new(
+                "invalid_counter",
+                format!("serial counter in {} overflows", counter_file.display()),
+            )
+        })?;
+        let temp_file = counter_file.with_extension("tmp");
+        fs::write(&temp_file, format!("{next}\n"))?;
+        fs::rename(&temp_file, &counter_file)?;
+
+        Ok(Self {
+            rules,
+            base_dir,
+            serial,
+        })
+    }
+
+    /// Combine the image written at `image_offset` with the per-unit data
+    ///
+    /// Patch offsets are flash offsets. Patches inside the image replace its
+    /// bytes, the others are written on their own. The BootROM erases every
+    /// sector it programs, so data sharing a sector goes into one write, with
+    /// the gaps filled with 0xFF. Sectors the image doesn't touch keep their
+    /// other bytes, `read` is called with the offset and length of such
+    /// sectors to read them back first.
+    pub(crate) fn writes<F>(
+        &self,
+        image: &[u8],
+        image_offset: u32,
+        mut read: F,
+    ) -> Result<Vec<FlashWrite>, Box<dyn Error>>
+    where
+        F: FnMut(u32, usize) -> Result<Vec<u8>, Box<dyn Error>>,
+    {
+        let mut patches = self
+            .rules
+            .patch
+            .iter()
+            .map(|rule| self.render(rule))
+            .collect::<Result<Vec<_>, _>>()?;
+        patches.sort_by_key(|(offset, _)| *offset);
+        for pair in patches.windows(2) {
+            let (offset, bytes) = &pair[0];
+            if u64::from(pair[1].0) < u64::from(*offset) + bytes.len() as u64 {
+                return Err(CodedError::new(
+                    "invalid_patch",
+                    format!("patches at {offset:#x} and {:#x} overlap", pair[1].0),
+                )
+                .into());
+            }
+        }
+
+        let mut ranges: Vec<_> = patches
+            .iter()
+            .map(|(offset, bytes)| (u64::from(*offset), bytes.len()))
+            .chain([(u64::from(image_offset), image.len())])
+            .map(|(start, length)| (start, start + length as u64))
+            .collect();
+        ranges.sort_unstable();
+        let sector = u64::from(SECTOR_SIZE);
+        let mut writes: Vec<(u64, u64)> = Vec::new();
+        for (start, end) in ranges {
+            match writes.last_mut() {
+                Some(last) if start < last.1.div_ceil(sector) * sector => last.1 = last.1.max(end),
+                _ => writes.push((start, end)),
+            }
+        }
+        let image_start = u64::from(image_offset);
+        let image_end = image_start + image.len() as u64;
+        let mut writes: Vec<_> = writes
+            .into_iter()
+            .map(|(start, end)| {
+                if start < image_end && image_start < end {
+                    return Ok(FlashWrite {
+                        offset: start as u32,
+                        data: vec![0xFF; (end - start) as usize],
+                    });
+                }
+                let start = start / sector * sector;
+                let end = end.div_ceil(sector) * sector;
+                Ok(FlashWrite {
+                    offset: start as u32,
+                    data: read(start as u32, (end - start) as usize)?,
+                })
+            })
+            .collect::<Result<_, Box<dyn Error>>>()?;
+
+        // Patches are copied last to replace the bytes of the image
+        for (offset, bytes) in [(image_offset, image)].into_iter().chain(
+            patches
+                .iter()
+                .map(|(offset, bytes)| (*offset, bytes.as_slice())),
+        ) {
+            let write = writes
+                .iter_mut()
+                .rev()
+                .find(|write| write.offset <= offset)
+                .expect("every range starts a write or lies in one");
+            let start = (offset - write.offset) as usize;
+            write.data[start..start + bytes.len()].copy_from_slice(bytes);
+        }
+        Ok(writes)
+    }
+
+    fn render(&self, rule: &PatchRule) -> Result<(u32, Vec<u8>), Box<dyn Error>> {
+        let serial = self.serial;
+        Ok(match rule {
+            PatchRule::Counter { offset, format } => {
+                let bytes = format.encode(serial).ok_or_else(|| {
+                    CodedError::new(
+                        "patch_out_of_range",
+                        format!("serial {serial} doesn't fit in {format:?}"),
+                    )
+                })?;
+                (*offset, bytes)
+            }
+            PatchRule::Text {
+                offset,
+                template,
+                digits,
+            } => {
+                let text = template.replace("{serial}", &format!("{serial:0digits$}"));
+                (*offset, text.into_bytes())
+            }
+            PatchRule::Mac { offset, base } => (*offset, mac_address(base, serial)?.to_vec()),
+            PatchRule::Csv {
+                offset,
+                file,
+                column,
+            } => (
+                *offset,
+                csv_bytes(&self.base_dir.join(file), column, serial)?,
+            ),
+        })
+    }
+
+    /// Log the serial, once the unit is programmed
+    pub(crate) fn log(
+        &self,
+        device_serial: Option<&str>,
+        family: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        if let Some(log_file) = &self.rules.log_file {
+            let log_file = self.base_dir.join(log_file);
+            let new = !log_file.exists();
+            let log = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&log_file)?;
+            let mut log = csv::Writer::from_writer(log);
+            if new {
+                log.write_record(["timestamp", "serial", "device_serial", "family"])?;
+            }
+            log.write_record([
+                &humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+                &self.serial.to_string(),
+                device_serial.unwrap_or(""),
+                family,
+            ])?;
+            log.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Lock file next to the counter file, removed when dropped
+struct CounterLock {
+    path: PathBuf,
+}
+
+impl CounterLock {
+    /// Create the lock file, waiting up to `timeout` for another station to
+    /// remove it
+    fn acquire(path: PathBuf, timeout: Duration) -> Result<Self, Box<dyn Error>> {
+        let start = Instant::now();
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(_) => return Ok(Self { path }),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists && start.elapsed() < timeout => {
+                    thread::sleep(Duration::from_millis(50));
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    return Err(CodedError::new(
+                        "counter_locked",
+                        format!(
+                            "{} is held by another station, delete it if none is running",
+                            path.display()
+                        ),
+                    )
+                    .into())
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+impl Drop for CounterLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn mac_address(base: &str, serial: u64) -> Result<[u8; 6], Box<dyn Error>> {
+    let invalid = || CodedError::new("invalid_patch", format!("invalid MAC address {base}"));
+    let octets = base
+        .split([':', '-'])
+        .map(|octet| u8::from_str_radix(octet, 16).map_err(|_| invalid()))
+        .collect::<Result<Vec<_>, _>>()?;
+    if octets.len() != 6 {
+        return Err(invalid().into());
+    }
+
+    let mut value = [0u8; 8];
+    value[2..].copy_from_slice(&octets);
+    let mac = u64::from_be_bytes(value)
+        .checked_add(serial)
+        .filter(|mac| *mac < 1 << 48)
+        .ok_or_else(|| {
+            CodedError::new(
+                "patch_out_of_range",
+                format!("MAC address {base} + {serial} overflows"),
+            )
+        })?;
+
+    let mut bytes = [0u8; 6];
+    bytes.copy_from_slice(&mac.to_be_bytes()[2..]);
+    Ok(bytes)
+}
+
+fn csv_bytes(path: &Path, column: &str, serial: u64) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut reader = csv::Reader::from_path(path)?;
+    let headers = reader.headers()?.clone();
+    let position = |name: &str| {
+        headers.iter().position(|h| h == name).ok_or_else(|| {
+            CodedError::new(
+                "invalid_patch",
+                format!("{} has no {name} column", path.display()),
+            )
+        })
+    };
+    let serial_column = position("serial")?;
+    let data_column = position(column)?;
+
+    for record in reader.records() {
+        let record = record?;
+        if record.get(serial_column).map(str::trim) == Some(&serial.to_string()) {
+            let hex: String = record[data_column]
+                .chars()
+                .filter(|c| !c.is_whitespace())
+                .collect();
+            return decode_hex(&hex).ok_or_else(|| {
+                CodedError::new(
+                    "invalid_patch",
+                    format!("{column} of serial {serial} is not hex"),
+                )
+                .into()
+            });
+        }
+    }
+
+    Err(CodedError::new(
+        "serial_not_found",
+        format!("serial {serial} not found in {}", path.display()),
+    )
+    .into())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn patches_image() {
+        let dir = tempdir().unwrap();
+        fs::write(
+            dir.path().join("calibration.csv"),
+            "serial,blob\n41,00112233\n42,deadbeef\n",
+        )
+        .unwrap();
+        fs::write(dir.path().join("serial.counter"), "42\n").unwrap();
+        fs::write(
+            dir.path().join("rules.toml"),
+            r#"
+counter_file = "serial.counter"
+
+[[patch]]
+type = "counter"
+offset = 0x1000
+format = "u16be"
+
+[[patch]]
+type = "text"
+offset = 0x1004
+template = "SN{serial}"
+digits = 4
+
+[[patch]]
+type = "mac"
+offset = 0x1010
+base = "02:00:00:00:00:ff"
+
+[[patch]]
+type = "csv"
+offset = 0x1020
+file = "calibration.csv"
+column = "blob"
+"#,
+        )
+        .unwrap();
+
+        let provisioning = SerialProvisioning::load(&dir.path().join("rules.toml")).unwrap();
+        assert_eq!(provisioning.serial, 42);
+
+        let writes = provisioning
+            .writes(&[0u8; 0x10], 0x1000, |_, _| panic!("nothing to read"))
+            .unwrap();
+        assert_eq!(writes.len(), 1);
+        let image = &writes[0].data;
+        assert_eq!(writes[0].offset, 0x1000);
+        assert_eq!(image.len(), 0x24);
+        assert_eq!(&image[..2], &[0x00, 42]);
+        assert_eq!(&image[4..10], b"SN0042");
+        assert_eq!(&image[0x10..0x16], &[0x02, 0, 0, 0, 0x01, 0x29]);
+        assert_eq!(&image[0x16..0x20], &[0xFF; 10]);
+        assert_eq!(&image[0x20..], &[0xDE, 0xAD, 0xBE, 0xEF]);
+
+        // Patches in other sectors are written on their own, keeping the rest
+        // of their sectors
+        let mut reads = Vec::new();
+        let writes = provisioning
+            .writes(&[0u8; 0x10], 0x3000, |offset, length| {
+                reads.push((offset, length));
+                Ok(vec![0xAB; length])
+            })
+            .unwrap();
+        assert_eq!(reads, [(0x1000, 0x1000)]);
+        assert_eq!(writes.len(), 2);
+        assert_eq!((writes[0].offset, writes[0].data.len()), (0x1000, 0x1000));
+        assert_eq!(&writes[0].data[..2], &[0x00, 42]);
+        assert_eq!(&writes[0].data[0x16..0x20], &[0xAB; 10]);
+        assert_eq!(&writes[0].data[0x24..], &[0xAB; 0x1000 - 0x24]);
+        assert_eq!(
+            writes[1],
+            FlashWrite {
+                offset: 0x3000,
+                data: vec![0; 0x10],
+            }
+        );
+
+        // The counter is taken when loading
+        assert_eq!(
+            fs::read_to_string(dir.path().join("serial.counter")).unwrap(),
+            "43\n"
+        );
+    }
+
+    #[test]
+    fn rejects_overlapping_patches() {
+        let dir = tempdir().unwrap();
+        fs::write(
+            dir.path().join("rules.toml"),
+            r#"
+counter_file = "serial.counter"
+
+[[patch]]
+type = "counter"
+offset = 0x1000
+
+[[patch]]
+type = "text"
+offset = 0x1002
+template = "SN{serial}"
+"#,
+        )
+        .unwrap();
+
+        let provisioning = SerialProvisioning::load(&dir.path().join("rules.toml")).unwrap();
+        let error = provisioning
+            .writes(&[], 0, |_, length| Ok(vec![0xFF; length]))
+            .unwrap_err();
+        assert_eq!(error.to_string(), "patches at 0x1000 and 0x1002 overlap");
+    }
+
+    #[test]
+    fn locks_counter_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("serial.lock");
+
+        let lock = CounterLock::acquire(path.clone(), Duration::ZERO).unwrap();
+        assert!(CounterLock::acquire(path.clone(), Duration::from_millis(100)).is_err());
+        drop(lock);
+        assert!(!path.exists());
+        CounterLock::acquire(path, Duration::ZERO).unwrap();
+    }
+
+    #[test]
+    fn takes_counter_and_logs() {
+        let dir = tempdir().unwrap();
+        fs::write(
+            dir.path().join("rules.toml"),
+            "counter_file = \"serial.counter\"\nstart = 7\nlog_file = \"log.csv\"\n",
+        )
+        .unwrap();
+
+        for serial in [7, 8] {
+            let provisioning = SerialProvisioning::load(&dir.path().join("rules.toml")).unwrap();
+            assert_eq!(provisioning.serial, serial);
+            provisioning.log(Some("ABC"), "HPM6700/6400").unwrap();
+        }
+
+        assert_eq!(
+            fs::read_to_string(dir.path().join("serial.counter")).unwrap(),
+            "9\n"
+        );
+        let log = fs::read_to_string(dir.path().join("log.csv")).unwrap();
+        let lines: Vec<_> = log.lines().collect();
+        assert_eq!(lines[0], "timestamp,serial,device_serial,family");
+        assert!(lines[1].ends_with(",7,ABC,HPM6700/6400"));
+        assert!(lines[2].ends_with(",8,ABC,HPM6700/6400"));
+    }
+
+    #[test]
+    fn rejects_overflowing_values() {
+        assert!(IntFormat::U16le.encode(0x1_0000).is_none());
+        assert!(mac_address("ff:ff:ff:ff:ff:ff", 1).is_err());
+        assert!(mac_address("02:00:00", 1).is_err());
+    }
+}
@@ -0,0 +1,527 @@
+//! High-level flash workflow on top of [`IspCommand`]
+//!
+//! A [`Session`] owns the transport and configures each XPI instance before its
+//! first use, so embedding tools don't have to repeat the staging of the memory
+//! config in ILM.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let mut session = Session::new(HpmDevice::open()?);
+//! let options = FlashOptions::new(MemoryId::XPI0)
+//!     .offset(0x400)
+//!     .memory_config(MemoryConfig::new().pin_group(PinGroup::Group2))
+//!     .verify(true);
+//! session.flash_image(&image, &options, NoProgress)?;
+//! ```
+
+use std::io::{Read, Write};
+
+use strum::IntoEnumIterator;
+
+use crate::isp_command::{Error, IspCommand, MemoryId, RuntimeEnvironment};
+use crate::memory_config::MemoryConfig;
+use crate::progress::{NoProgress, Phase, Progress, ProgressEvent};
+
+/// Offset in ILM the memory config is staged at before configuring an XPI
+pub const MEMORY_CONFIG_OFFSET: u32 = 0x200;
+/// Size of the XPI flash sectors the BootROM erases before programming them
+pub const SECTOR_SIZE: u32 = 0x1000;
+
+/// Target and behaviour of a flash operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlashOptions {
+    pub memory_id: MemoryId,
+    /// Offset in the memory, or an absolute address inside its window
+    pub offset: u32,
+    /// Config the XPI is configured with, none keeps the config it's
+    /// configured with already, or uses the default one for its first use
+    pub memory_config: Option<MemoryConfig>,
+    /// Read back and compare after writing
+    pub verify: bool,
+}
+
+impl FlashOptions {
+    pub const fn new(memory_id: MemoryId) -> Self {
+        Self {
+            memory_id,
+            offset: 0,
+            memory_config: None,
+            verify: false,
+        }
+    }
+
+    pub const fn offset(mut self, offset: u32) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    pub const fn memory_config(mut self, memory_config: MemoryConfig) -> Self {
+        self.memory_config = Some(memory_config);
+        self
+    }
+
+    pub const fn verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    fn start(&self) -> Result<u32, Error> {
+        self.memory_id.to_offset(self.offset)
+    }
+}
+
+/// Answers of the BootROM to the runtime environment queries
+#[derive(Debug)]
+pub struct Info {
+    pub runtime_environment: Vec<(RuntimeEnvironment, Result<Vec<u32>, Error>)>,
+}
+
+/// Config an XPI instance was configured with
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Configured {
+    /// XPI NOR configuration option passed as is
+    Raw(Vec<u8>),
+    Config(MemoryConfig),
+}
+
+/// A device in ISP mode with the XPI instances configured so far
+pub struct Session<D> {
+    device: D,
+    configured: Vec<(MemoryId, Configured)>,
+}
+
+impl<D> Session<D>
+where
+    D: IspCommand,
+{
+    pub fn new(device: D) -> Self {
+        Self {
+            device,
+            configured: Vec::new(),
+        }
+    }
+
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+
+    /// Config the memory was last configured with, none for RAM, XPI
+    /// instances not configured yet and ones configured with raw bytes
+    pub fn memory_config(&self, memory_id: MemoryId) -> Option<MemoryConfig> {
+        match self.configured(memory_id) {
+            Some(Configured::Config(memory_config)) => Some(*memory_config),
+            _ => None,
+        }
+    }
+
+    fn configured(&self, memory_id: MemoryId) -> Option<&Configured> {
+        self.configured
+            .iter()
+            .find(|(id, _)| *id == memory_id)
+            .map(|(_, configured)| configured)
+    }
+
+    /// Configure an XPI instance, unless it's configured with the same config
+    /// already
+    ///
+    /// RAM needs no configuration, so it's left alone.
+    pub fn configure(
+        &mut self,
+        memory_id: MemoryId,
+        memory_config: MemoryConfig,
+    ) -> Result<(), Error> {
+        self.configure_with_progress(memory_id, Some(memory_config), NoProgress)
+    }
+
+    /// Configure an XPI instance with a raw XPI NOR configuration option,
+    /// unless it's configured with the same bytes already
+    ///
+    /// Later operations keep it, unless their options pass a config.
+    pub fn configure_bytes(
+        &mut self,
+        memory_id: MemoryId,
+        nor_cfg_option: &[u8],
+    ) -> Result<(), Error> {
+        let configured = Configured::Raw(nor_cfg_option.to_vec());
+        if self.configured(memory_id) == Some(&configured) {
+            return Ok(());
+        }
+
+        self.configured.retain(|(id, _)| *id != memory_id);
+        self.stage_config(memory_id, nor_cfg_option, NoProgress)?;
+        self.configured.push((memory_id, configured));
+        Ok(())
+    }
+
+    /// [`Session::configure`], reporting the staging of the config in the
+    /// configure phase
+    ///
+    /// Without a config, an XPI instance configured already is left alone.
+    fn configure_with_progress<P>(
+        &mut self,
+        memory_id: MemoryId,
+        memory_config: Option<MemoryConfig>,
+        progress: P,
+    ) -> Result<(), Error>
+    where
+        P: Progress,
+    {
+        if !matches!(memory_id, MemoryId::XPI0 | MemoryId::XPI1) {
+            return Ok(());
+        }
+        let memory_config = match (self.configured(memory_id), memory_config) {
+            (Some(_), None) => return Ok(()),
+            (Some(Configured::Config(configured)), Some(memory_config))
+                if *configured == memory_config =>
+            {
+                return Ok(())
+            }
+            (_, memory_config) => memory_config.unwrap_or(MemoryConfig::new()),
+        };
+
+        self.configured.retain(|(id, _)| *id != memory_id);
+        self.stage_config(memory_id, &memory_config.to_bootrom_config(), progress)?;
+        self.configured
+            .push((memory_id, Configured::Config(memory_config)));
+        Ok(())
+    }
+
+    /// Write the config to ILM and configure the memory with it
+    fn stage_config<P>(
+        &self,
+        memory_id: MemoryId,
+        nor_cfg_option: &[u8],
+        mut progress: P,
+    ) -> Result<(), Error>
+    where
+        P: Progress,
+    {
+        self.device.write_memory(
+            MemoryId::ILM,
+            MEMORY_CONFIG_OFFSET,
+            nor_cfg_option,
+            |event: &ProgressEvent| {
+                progress.update(&ProgressEvent {
+                    phase: Phase::Configure,
+                    ..*event
+                })
+            },
+        )?;
+        self.device.configure_memory(
+            memory_id,
+            MemoryId::ILM.base_address() + MEMORY_CONFIG_OFFSET,
+        )
+    }
+
+    /// Write an image, and read it back when the options ask to verify
+    pub fn flash_image<P>(
+        &mut self,
+        data: &[u8],
+        options: &FlashOptions,
+        mut progress: P,
+    ) -> Result<(), Error>
+    where
+        P: Progress,
+    {
+        let start = options.start()?;
+        self.configure_with_progress(
+            options.memory_id,
+            options.memory_config,
+            |event: &ProgressEvent| progress.update(event),
+        )?;
+        self.device
+            .write_memory(options.memory_id, start, data, |event: &ProgressEvent| {
+                progress.update(event)
+            })?;
+        if options.verify {
+            self.device
+                .verify_memory(options.memory_id, start, data, progress)?;
+        }
+        Ok(())
+    }
+
+    /// Write an image from a reader, see [`IspCommand::write_from_reader`]
+    ///
+    /// The data isn't kept, so `verify` of the options is ignored.
+    pub fn flash_from_reader<R, P>(
+        &mut self,
+        reader: R,
+        length: Option<usize>,
+        options: &FlashOptions,
+        mut progress: P,
+    ) -> Result<(), Error>
+    where
+        R: Read,
+        P: Progress,
+    {
+        let start = options.start()?;
+        self.configure_with_progress(
+            options.memory_id,
+            options.memory_config,
+            |event: &ProgressEvent| progress.update(event),
+        )?;
+        self.device
+            .write_from_reader(reader, length, options.memory_id, start, progress)
+    }
+
+    /// Compare the memory with `data`
+    pub fn verify<P>(
+        &mut self,
+        data: &[u8],
+        options: &FlashOptions,
+        mut progress: P,
+    ) -> Result<(), Error>
+    where
+        P: Progress,
+    {
+        let start = options.start()?;
+        self.configure_with_progress(
+            options.memory_id,
+            options.memory_config,
+            |event: &ProgressEvent| progress.update(event),
+        )?;
+        self.device
+            .verify_memory(options.memory_id, start, data, progress)
+    }
+
+    /// Erase `length` bytes
+    ///
+    /// The ISP protocol has no erase command, but the BootROM erases the
+    /// sectors it programs, so the range is written with `0xFF`. Whether the
+    /// rest of a partly covered sector survives is up to the BootROM, so the
+    /// range should be aligned to sectors.
+    pub fn erase<P>(
+        &mut self,
+        length: usize,
+        options: &FlashOptions,
+        mut progress: P,
+    ) -> Result<(), Error>
+    where
+        P: Progress,
+    {
+        let start = options.start()?;
+        self.configure_with_progress(
+            options.memory_id,
+            options.memory_config,
+            |event: &ProgressEvent| progress.update(event),
+        )?;
+        self.device.write_memory(
+            options.memory_id,
+            start,
+            &vec![0xFF; length],
+            |event: &ProgressEvent| {
+                progress.update(&ProgressEvent {
+                    phase: Phase::Erase,
+                    ..*event
+                })
+            },
+        )
+    }
+
+    /// Read `length` bytes
+    pub fn dump<P>(
+        &mut self,
+        length: usize,
+        options: &FlashOptions,
+        mut progress: P,
+    ) -> Result<Vec<u8>, Error>
+    where
+        P: Progress,
+    {
+        let mut data = vec![0u8; length];
+        let start = options.start()?;
+        self.configure_with_progress(
+            options.memory_id,
+            options.memory_config,
+            |event: &ProgressEvent| progress.update(event),
+        )?;
+        self.device
+            .read_memory(options.memory_id, start, &mut data, progress)?;
+        Ok(data)
+    }
+
+    /// Read `length` bytes into a writer
+    pub fn dump_to_writer<W, P>(
+        &mut self,
+        writer: W,
+        length: usize,
+        options: &FlashOptions,
+        mut progress: P,
+    ) -> Result<(), Error>
+    where
+        W: Write,
+        P: Progress,
+    {
+        let start = options.start()?;
+        self.configure_with_progress(
+            options.memory_id,
+            options.memory_config,
+            |event: &ProgressEvent| progress.update(event),
+        )?;
+        self.device
+            .read_to_writer(writer, options.memory_id, start, length, progress)
+    }
+
+    /// Query every runtime environment item
+    ///
+    /// Items the BootROM refuses keep their error, so one failing query
+    /// doesn't hide the others.
+    pub fn info(&self) -> Info {
+        Info {
+            runtime_environment: RuntimeEnvironment::iter()
+                .map(|id| (id, self.device.query_runtime_environment(id)))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::mem;
+    use std::ops::ControlFlow;
+
+    use zerocopy::FromZeroes;
+
+    use super::*;
+    use crate::isp_command::{Interface, Packet};
+    use crate::memory_config::PinGroup;
+
+    /// Records the packets written and answers everything with success
+    #[derive(Default)]
+    struct MockDevice {
+        packets: RefCell<Vec<(u8, Vec<u8>)>>,
+    }
+
+    impl MockDevice {
+        fn commands(&self, cmd: u8) -> usize {
+            self.packets
+                .borrow()
+                .iter()
+                .filter(|(packet_cmd, _)| *packet_cmd == cmd)
+                .count()
+        }
+    }
+
+    impl Interface for MockDevice {
+        fn write(&self, packet: &Packet, length: u16) -> Result<(), Error> {
+            self.packets
+                .borrow_mut()
+                .push((packet.cmd, packet.payload[..length as usize].to_vec()));
+            Ok(())
+        }
+
+        fn read(&self, packet: &mut Packet) -> Result<u16, Error> {
+            *packet = Packet::new_zeroed();
+            Ok(mem::size_of::<u32>() as u16)
+        }
+    }
+
+    impl IspCommand for MockDevice {}
+
+    const CONFIGURE_MEMORY: u8 = 0x03;
+    const WRITE_MEMORY: u8 = 0x04;
+
+    #[test]
+    fn configures_each_xpi_once() {
+        let mut session = Session::new(MockDevice::default());
+        let options = FlashOptions::new(MemoryId::XPI0).offset(0x8000_0400);
+
+        session
+            .flash_image(&[0xA5; 16], &options, NoProgress)
+            .unwrap();
+        session.verify(&[0; 16], &options, NoProgress).unwrap();
+        assert_eq!(session.device().commands(CONFIGURE_MEMORY), 1);
+        assert_eq!(
+            session.memory_config(MemoryId::XPI0),
+            Some(MemoryConfig::new())
+        );
+
+        let options = options.memory_config(MemoryConfig::new().pin_group(PinGroup::Group2));
+        session.dump(16, &options, NoProgress).unwrap();
+        session
+            .dump(16, &FlashOptions::new(MemoryId::XPI1), NoProgress)
+            .unwrap();
+        session
+            .dump(16, &FlashOptions::new(MemoryId::ILM), NoProgress)
+            .unwrap();
+        assert_eq!(session.device().commands(CONFIGURE_MEMORY), 3);
+        assert_eq!(session.memory_config(MemoryId::ILM), None);
+    }
+
+    #[test]
+    fn stages_memory_config_in_ilm() {
+        let mut session = Session::new(MockDevice::default());
+        let options = FlashOptions::new(MemoryId::XPI0).offset(0x8000_0400);
+
+        session
+            .flash_image(&[0xA5; 16], &options, NoProgress)
+            .unwrap();
+
+        let packets = session.into_inner().packets.into_inner();
+        let (cmd, payload) = &packets[0];
+        assert_eq!(*cmd, WRITE_MEMORY);
+        assert_eq!(&payload[..4], &MEMORY_CONFIG_OFFSET.to_le_bytes());
+        assert_eq!(&payload[12..], &MemoryConfig::new().to_bootrom_config());
+        let (cmd, payload) = &packets[2];
+        assert_eq!(*cmd, WRITE_MEMORY);
+        assert_eq!(&payload[..4], &0x8000_0400u32.to_le_bytes());
+    }
+
+    #[test]
+    fn configures_with_raw_config() {
+        let mut session = Session::new(MockDevice::default());
+        let options = FlashOptions::new(MemoryId::XPI0);
+        session
+            .configure(MemoryId::XPI0, MemoryConfig::new())
+            .unwrap();
+
+        session
+            .configure_bytes(MemoryId::XPI0, &[0xAA; 12])
+            .unwrap();
+        assert_eq!(session.memory_config(MemoryId::XPI0), None);
+        session
+            .configure_bytes(MemoryId::XPI0, &[0xAA; 12])
+            .unwrap();
+        session.dump(16, &options, NoProgress).unwrap();
+        assert_eq!(session.device().commands(CONFIGURE_MEMORY), 2);
+
+        let options = options.memory_config(MemoryConfig::new());
+        session.dump(16, &options, NoProgress).unwrap();
+        assert_eq!(session.device().commands(CONFIGURE_MEMORY), 3);
+        assert_eq!(
+            session.memory_config(MemoryId::XPI0),
+            Some(MemoryConfig::new())
+        );
+
+        let packets = session.into_inner().packets.into_inner();
+        let (_, payload) = &packets[2];
+        assert_eq!(&payload[12..], &[0xAA; 12]);
+    }
+
+    #[test]
+    fn erases_with_ones() {
+        let mut session = Session::new(MockDevice::default());
+        let mut phases = Vec::new();
+
+        session
+            .erase(
+                0x20,
+                &FlashOptions::new(MemoryId::XPI0),
+                |event: &ProgressEvent| {
+                    phases.push(event.phase);
+                    ControlFlow::Continue(())
+                },
+            )
+            .unwrap();
+
+        let packets = session.into_inner().packets.into_inner();
+        assert_eq!(&packets.last().unwrap().1[12..], &[0xFF; 0x20]);
+        assert_eq!(phases, vec![Phase::Configure, Phase::Erase]);
+    }
+}
@@ -0,0 +1,425 @@
+//! Simulated device for testing tools without hardware
+//!
+//! [`SimulatedDevice`] speaks the ISP protocol on the packet level and keeps
+//! the memories on the host. XPI flash reads as erased (`0xFF`) until written,
+//! has to be configured with a valid XPI NOR configuration option first, and
+//! writes erase the sectors they touch, like on the chip.
+//!
+//! [`Transport`] is either an attached or a simulated device, for tools and
+//! bindings offering both.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let device = SimulatedDevice::new(Family::HPM5300);
+//! let mut session = Session::new(device);
+//! session.flash_image(&image, &FlashOptions::new(MemoryId::XPI0), NoProgress)?;
+//! ```
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::mem;
+
+use strum::IntoEnumIterator;
+
+use crate::boot_image::NOR_CFG_OPTION_TAG;
+use crate::hid::{Family, HpmDevice};
+use crate::isp_command::{
+    CommandType, Commands, Error, Interface, IspCommand, MemoryId, Packet, RuntimeEnvironment,
+};
+use crate::session::SECTOR_SIZE;
+
+/// Host page of the memories, the size of an XPI flash sector
+const PAGE_SIZE: usize = SECTOR_SIZE as usize;
+/// Payload of a packet, the most data one response carries
+const PAYLOAD_SIZE: u32 = 508;
+const STATUS_FAIL: u32 = 1;
+const STATUS_INVALID_ARGUMENT: u32 = 2;
+
+/// Host-side stand-in for a device in ISP mode
+pub struct SimulatedDevice {
+    family: Family,
+    state: RefCell<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Pages written so far, by absolute address
+    pages: HashMap<u32, Box<[u8; PAGE_SIZE]>>,
+    configured: Vec<MemoryId>,
+    write: Option<PendingWrite>,
+    responses: VecDeque<Vec<u8>>,
+}
+
+/// Write command waiting for its data packets
+struct PendingWrite {
+    address: u32,
+    remaining: usize,
+    /// Status answered once all data arrived, data of failed writes is dropped
+    status: u32,
+}
+
+impl SimulatedDevice {
+    pub fn new(family: Family) -> Self {
+        Self {
+            family,
+            state: RefCell::default(),
+        }
+    }
+
+    pub fn family(&self) -> Family {
+        self.family
+    }
+
+    /// Contents of the memory, bypassing the protocol
+    pub fn memory(&self, memory_id: MemoryId, offset: u32, length: usize) -> Vec<u8> {
+        let state = self.state.borrow();
+        let address = memory_id.base_address() + offset;
+        (0..length as u32)
+            .map(|i| state.read_byte(address + i))
+            .collect()
+    }
+
+    /// Whether an XPI instance has been configured
+    pub fn is_configured(&self, memory_id: MemoryId) -> bool {
+        self.state.borrow().configured.contains(&memory_id)
+    }
+}
+
+fn argument(payload: &[u8], index: usize) -> u32 {
+    let start = index * mem::size_of::<u32>();
+    u32::from_le_bytes(payload[start..start + 4].try_into().unwrap())
+}
+
+fn status_response(status: u32) -> Vec<u8> {
+    status.to_le_bytes().to_vec()
+}
+
+impl State {
+    /// Value of memory never written, flash reads as erased
+    fn blank_value(address: u32) -> u8 {
+        match MemoryId::from_address(address) {
+            Some(MemoryId::XPI0 | MemoryId::XPI1) => 0xFF,
+            _ => 0x00,
+        }
+    }
+
+    fn read_byte(&self, address: u32) -> u8 {
+        let page = address & !(PAGE_SIZE as u32 - 1);
+        match self.pages.get(&page) {
+            Some(data) => data[(address - page) as usize],
+            None => Self::blank_value(address),
+        }
+    }
+
+    fn write_bytes(&mut self, address: u32, data: &[u8]) {
+        for (i, byte) in data.iter().enumerate() {
+            let address = address + i as u32;
+            let page = address & !(PAGE_SIZE as u32 - 1);
+            self.pages
+                .entry(page)
+                .or_insert_with(|| Box::new([Self::blank_value(page); PAGE_SIZE]))
+                [(address - page) as usize] = *byte;
+        }
+    }
+
+    /// Erase the XPI flash sectors holding `length` bytes at `address`
+    fn erase_sectors(&mut self, address: u32, length: u32) {
+        if length == 0 || Self::blank_value(address) != 0xFF {
+            return;
+        }
+        let first = address & !(PAGE_SIZE as u32 - 1);
+        let last = (address + (length - 1)) & !(PAGE_SIZE as u32 - 1);
+        self.pages.retain(|page, _| !(first..=last).contains(page));
+    }
+
+    /// Status of an access to `length` bytes at `address` of the memory
+    fn check_access(&self, memory_id: u32, address: u32, length: u32) -> u32 {
+        let Some(memory_id) = MemoryId::iter().find(|id| *id as u32 == memory_id) else {
+            return STATUS_INVALID_ARGUMENT;
+        };
+        let in_window = address
+            .checked_sub(memory_id.base_address())
+            .and_then(|offset| offset.checked_add(length))
+            .is_some_and(|end| end <= memory_id.window_size());
+        if !in_window {
+            STATUS_INVALID_ARGUMENT
+        } else if matches!(memory_id, MemoryId::XPI0 | MemoryId::XPI1)
+            && !self.configured.contains(&memory_id)
+        {
+            STATUS_FAIL
+        } else {
+            0
+        }
+    }
+
+    fn query_runtime_environment(&mut self, id: u32) {
+        let response = match RuntimeEnvironment::iter().find(|env| *env as u32 == id) {
+            // Last boot succeeded
+            Some(RuntimeEnvironment::LastBootStatus) => vec![0, 0],
+            Some(_) => vec![0],
+            None => vec![STATUS_INVALID_ARGUMENT],
+        };
+        self.responses.push_back(
+            response
+                .iter()
+                .flat_map(|word| word.to_le_bytes())
+                .collect(),
+        );
+    }
+
+    fn configure_memory(&mut self, memory_id: u32, cfg_addr: u32) {
+        let memory_id = MemoryId::iter()
+            .find(|id| *id as u32 == memory_id)
+            .filter(|id| matches!(id, MemoryId::XPI0 | MemoryId::XPI1));
+        let tag = u16::from_le_bytes([self.read_byte(cfg_addr + 2), self.read_byte(cfg_addr + 3)]);
+        let status = match memory_id {
+            Some(memory_id) if tag == NOR_CFG_OPTION_TAG => {
+                if !self.configured.contains(&memory_id) {
+                    self.configured.push(memory_id);
+                }
+                0
+            }
+            _ => STATUS_INVALID_ARGUMENT,
+        };
+        self.responses.push_back(status_response(status));
+    }
+
+    fn start_write(&mut self, address: u32, length: u32, memory_id: u32, data: &[u8]) {
+        self.write = Some(PendingWrite {
+            address,
+            remaining: length as usize,
+            status: self.check_access(memory_id, address, length),
+        });
+        if self.write.as_ref().is_some_and(|write| write.status == 0) {
+            self.erase_sectors(address, length);
+        }
+        self.continue_write(data);
+    }
+
+    fn continue_write(&mut self, data: &[u8]) {
+        let Some(mut write) = self.write.take() else {
+            return;
+        };
+        let data = &data[..data.len().min(write.remaining)];
+        if write.status == 0 {
+            self.write_bytes(write.address, data);
+        }
+        write.address += data.len() as u32;
+        write.remaining -= data.len();
+
+        if write.remaining == 0 {
+            self.responses.push_back(status_response(write.status));
+        } else {
+            self.write = Some(write);
+        }
+    }
+
+    fn read_memory(&mut self, address: u32, length: u32, memory_id: u32) {
+        let status = self.check_access(memory_id, address, length);
+        self.responses.push_back(status_response(status));
+        if status != 0 {
+            return;
+        }
+
+        for chunk in (0..length).step_by(PAYLOAD_SIZE as usize) {
+            let chunk_length = PAYLOAD_SIZE.min(length - chunk);
+            let data = (0..chunk_length)
+                .map(|i| self.read_byte(address + chunk + i))
+                .collect();
+            self.responses.push_back(data);
+        }
+    }
+}
+
+impl Interface for SimulatedDevice {
+    fn write(&self, packet: &Packet, length: u16) -> Result<(), Error> {
+        let mut state = self.state.borrow_mut();
+        let payload = &packet.payload[..length as usize];
+
+        if packet.cmd_type == CommandType::DataOnly as u8 {
+            state.continue_write(payload);
+            return Ok(());
+        }
+
+        let arguments = packet.arg_num as usize * mem::size_of::<u32>();
+        if payload.len() < arguments {
+            return Err(Error::Nak);
+        }
+        let arg = |index| argument(payload, index);
+        match packet.cmd {
+            cmd if cmd == Commands::QueryRuntimeEnv as u8 => {
+                state.query_runtime_environment(arg(0))
+            }
+            cmd if cmd == Commands::ConfigureMemory as u8 => state.configure_memory(arg(0), arg(1)),
+            cmd if cmd == Commands::WriteMemory as u8 => {
+                state.start_write(arg(0), arg(1), arg(2), &payload[arguments..])
+            }
+            cmd if cmd == Commands::ReadMemory as u8 => state.read_memory(arg(0), arg(1), arg(2)),
+            _ => return Err(Error::Nak),
+        }
+        Ok(())
+    }
+
+    fn read(&self, packet: &mut Packet) -> Result<u16, Error> {
+        let response = self
+            .state
+            .borrow_mut()
+            .responses
+            .pop_front()
+            .ok_or(Error::Timeout)?;
+        packet.payload[..response.len()].copy_from_slice(&response);
+        Ok(response.len() as u16)
+    }
+
+    fn abort(&self) -> Result<(), Error> {
+        let mut state = self.state.borrow_mut();
+        state.write = None;
+        state.responses.clear();
+        Ok(())
+    }
+}
+
+impl IspCommand for SimulatedDevice {}
+
+/// An attached or a simulated device
+pub enum Transport {
+    Hid(HpmDevice),
+    Simulated(SimulatedDevice),
+}
+
+impl Transport {
+    pub fn family(&self) -> Family {
+        match self {
+            Transport::Hid(device) => device.family(),
+            Transport::Simulated(device) => device.family(),
+        }
+    }
+}
+
+impl Interface for Transport {
+    fn write(&self, packet: &Packet, length: u16) -> Result<(), Error> {
+        match self {
+            Transport::Hid(device) => device.write(packet, length),
+            Transport::Simulated(device) => device.write(packet, length),
+        }
+    }
+
+    fn read(&self, packet: &mut Packet) -> Result<u16, Error> {
+        match self {
+            Transport::Hid(device) => device.read(packet),
+            Transport::Simulated(device) => device.read(packet),
+        }
+    }
+
+    fn abort(&self) -> Result<(), Error> {
+        match self {
+            Transport::Hid(device) => device.abort(),
+            Transport::Simulated(device) => device.abort(),
+        }
+    }
+}
+
+impl IspCommand for Transport {}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::ControlFlow;
+
+    use super::*;
+    use crate::isp_command::ReadMemory;
+    use crate::memory_config::MemoryConfig;
+    use crate::progress::{NoProgress, ProgressEvent};
+    use crate::session::{FlashOptions, Session};
+
+    #[test]
+    fn round_trips_memory() {
+        let mut session = Session::new(SimulatedDevice::new(Family::HPM5300));
+        let data: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+        let options = FlashOptions::new(MemoryId::XPI0)
+            .offset(0x3000)
+            .verify(true);
+
+        session.flash_image(&data, &options, NoProgress).unwrap();
+
+        assert_eq!(
+            session.dump(data.len(), &options, NoProgress).unwrap(),
+            data
+        );
+        let device = session.into_inner();
+        assert!(device.is_configured(MemoryId::XPI0));
+        assert_eq!(device.memory(MemoryId::XPI0, 0x2FFF, 2), vec![0xFF, 0]);
+        assert_eq!(device.memory(MemoryId::DLM, 0, 1), vec![0]);
+    }
+
+    #[test]
+    fn erases_written_sectors() {
+        let mut session = Session::new(SimulatedDevice::new(Family::HPM5300));
+        let options = FlashOptions::new(MemoryId::XPI0);
+        session
+            .flash_image(&[0; 0x2000], &options, NoProgress)
+            .unwrap();
+
+        session
+            .flash_image(&[1; 2], &options.offset(0x0FFF), NoProgress)
+            .unwrap();
+
+        let device = session.into_inner();
+        assert_eq!(device.memory(MemoryId::XPI0, 0x0FFE, 3), [0xFF, 1, 1]);
+        assert_eq!(device.memory(MemoryId::XPI0, 0x1001, 1), [0xFF]);
+    }
+
+    #[test]
+    fn requires_configured_xpi() {
+        let device = SimulatedDevice::new(Family::HPM5300);
+        let mut data = [0u8; 4];
+
+        let result = device.read_memory(MemoryId::XPI0, 0, &mut data, NoProgress);
+        assert!(matches!(result, Err(Error::Other(STATUS_FAIL))));
+
+        // ILM holds no configuration option yet
+        let result = device.configure_memory(MemoryId::XPI0, 0x200);
+        assert!(matches!(result, Err(Error::Other(STATUS_INVALID_ARGUMENT))));
+
+        device
+            .write_memory(
+                MemoryId::ILM,
+                0x200,
+                &MemoryConfig::new().to_bootrom_config(),
+                NoProgress,
+            )
+            .unwrap();
+        device.configure_memory(MemoryId::XPI0, 0x200).unwrap();
+        device
+            .read_memory(MemoryId::XPI0, 0, &mut data, NoProgress)
+            .unwrap();
+        assert_eq!(data, [0xFF; 4]);
+    }
+
+    #[test]
+    fn rejects_out_of_window_access() {
+        let device = SimulatedDevice::new(Family::HPM5300);
+        // The host refuses such accesses already, so send the command itself
+        let mut packet: Packet = ReadMemory::new(0x7_FFFF, 2, MemoryId::ILM).into();
+
+        device
+            .write(&packet, mem::size_of::<ReadMemory>() as u16)
+            .unwrap();
+        device.read(&mut packet).unwrap();
+
+        assert_eq!(packet.payload[..4], STATUS_INVALID_ARGUMENT.to_le_bytes());
+    }
+
+    #[test]
+    fn drops_transfer_on_abort() {
+        let device = SimulatedDevice::new(Family::HPM5300);
+
+        let result = device.write_memory(MemoryId::ILM, 0, &[0xA5; 1200], |_: &ProgressEvent| {
+            ControlFlow::Break(())
+        });
+
+        assert!(matches!(result, Err(Error::Aborted)));
+        assert_eq!(device.memory(MemoryId::ILM, 0, 2), vec![0xA5, 0xA5]);
+        assert_eq!(device.memory(MemoryId::ILM, 600, 1), vec![0]);
+    }
+}
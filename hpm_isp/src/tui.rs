@@ -0,0 +1,811 @@
+//! Full-screen terminal UI
+//!
+//! Four panes: the attached devices, the memory config, the images to flash
+//! and a hex view of the device memory. Flashing runs on a worker thread that
+//! owns the session until it is done, so the screen keeps redrawing and the
+//! job can be cancelled.
+
+use std::error::Error;
+use std::fs;
+use std::ops::ControlFlow;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use serde_json::Value;
+
+use hpm_isp::hid::{DeviceInfo, HpmDevice};
+use hpm_isp::isp_command::MemoryId;
+use hpm_isp::memory_config::{MemoryConfig, QuadIOEnableSequence};
+use hpm_isp::progress::{NoProgress, Phase, ProgressEvent};
+use hpm_isp::session::{FlashOptions, Session};
+
+use crate::config::ResolvedConfig;
+use crate::mem::{hexdump, Width, LINE_BYTES};
+use crate::open_device;
+use crate::parse::parse_number;
+use crate::probe::{FLASH_TYPES, PIN_GROUPS, PORT_CONNECTIONS};
+
+/// How long to wait for a key before polling the flash job again
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+const QUAD_IO_ENABLE_SEQUENCES: [QuadIOEnableSequence; 5] = [
+    QuadIOEnableSequence::None,
+    QuadIOEnableSequence::Status1Bit6,
+    QuadIOEnableSequence::Status2Bit1,
+    QuadIOEnableSequence::Status2Bit7,
+    QuadIOEnableSequence::Status2Bit1ProgrammedBy0x31,
+];
+
+/// Memory config keys shown in the config pane, below the XPI
+const CONFIG_KEYS: [&str; 4] = [
+    "flash_type",
+    "port_connection",
+    "pin_group",
+    "quad_io_enable_sequence",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Pane {
+    Devices,
+    Config,
+    Images,
+    Memory,
+}
+
+impl Pane {
+    fn next(self) -> Self {
+        match self {
+            Pane::Devices => Pane::Config,
+            Pane::Config => Pane::Images,
+            Pane::Images => Pane::Memory,
+            Pane::Memory => Pane::Devices,
+        }
+    }
+
+    fn help(self) -> &'static str {
+        match self {
+            Pane::Devices => "↑↓ select  r refresh",
+            Pane::Config => "↑↓ field  ←→ change",
+            Pane::Images => "↑↓ select  a add  d remove  v verify  f flash  Esc cancel",
+            Pane::Memory => "↑↓ scroll  PgUp/PgDn page  g go to  r reload",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum ImageState {
+    Pending,
+    Running(ProgressEvent),
+    Done(Duration),
+    Failed(String),
+}
+
+/// Image queued to be written at an offset of the selected XPI
+#[derive(Debug)]
+struct ImageEntry {
+    path: PathBuf,
+    offset: u32,
+    state: ImageState,
+}
+
+/// What the text input line is asking for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Prompt {
+    AddImage,
+    Goto,
+}
+
+/// Progress of the flash job, tagged with the index of the image
+enum JobEvent {
+    Progress(usize, ProgressEvent),
+    Done(usize, Duration),
+    Failed(usize, String),
+}
+
+struct Job {
+    events: Receiver<JobEvent>,
+    cancel: Arc<AtomicBool>,
+    handle: JoinHandle<Session<HpmDevice>>,
+}
+
+struct MemoryView {
+    offset: u32,
+    data: Vec<u8>,
+    error: Option<String>,
+}
+
+struct App {
+    focus: Pane,
+    devices: Vec<DeviceInfo>,
+    device: ListState,
+    xpi: MemoryId,
+    memory_config: MemoryConfig,
+    /// Selected row of the config pane, 0 is the XPI
+    field: usize,
+    images: Vec<ImageEntry>,
+    image: ListState,
+    verify: bool,
+    memory: MemoryView,
+    /// Lines of the memory view at the last draw
+    memory_lines: usize,
+    prompt: Option<(Prompt, String)>,
+    status: String,
+    /// Open device, `None` while a job owns it or before the first use
+    session: Option<Session<HpmDevice>>,
+    job: Option<Job>,
+    quit: bool,
+}
+
+/// Run the terminal UI until the user quits
+pub(crate) fn run_tui(config: &ResolvedConfig, serial: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let devices = HpmDevice::list()?;
+    let mut app = App::new(
+        devices,
+        config.xpi().unwrap_or(MemoryId::XPI0),
+        config.memory_config(),
+    );
+    if let Some(serial) = serial {
+        let index = app
+            .devices
+            .iter()
+            .position(|device| device.serial_number.as_deref() == Some(serial));
+        app.device.select(index);
+    }
+
+    let mut terminal = ratatui::init();
+    let result = app.run(&mut terminal);
+    ratatui::restore();
+    result
+}
+
+/// Read a `PATH@OFFSET` image spec
+fn parse_image_spec(spec: &str) -> Result<(PathBuf, u32), String> {
+    let (path, offset) = spec
+        .rsplit_once('@')
+        .filter(|(path, _)| !path.trim().is_empty())
+        .ok_or("expected PATH@OFFSET, e.g. app.bin@0x3000")?;
+    Ok((PathBuf::from(path.trim()), parse_number(offset.trim())?))
+}
+
+fn config_options(key: &str) -> Vec<Value> {
+    fn values<T: serde::Serialize>(values: &[T]) -> Vec<Value> {
+        values
+            .iter()
+            .map(|value| serde_json::to_value(value).unwrap())
+            .collect()
+    }
+    match key {
+        "flash_type" => values(&FLASH_TYPES),
+        "port_connection" => values(&PORT_CONNECTIONS),
+        "pin_group" => values(&PIN_GROUPS),
+        _ => values(&QUAD_IO_ENABLE_SEQUENCES),
+    }
+}
+
+/// Value of a memory config key, as written in config files
+fn config_value(memory_config: MemoryConfig, key: &str) -> String {
+    let values = serde_json::to_value(memory_config).unwrap();
+    values[key].as_str().unwrap_or_default().to_string()
+}
+
+/// Step a memory config key through its values, wrapping around
+fn cycle_config(memory_config: MemoryConfig, key: &str, step: isize) -> MemoryConfig {
+    let mut values = serde_json::to_value(memory_config).unwrap();
+    let options = config_options(key);
+    let current = options
+        .iter()
+        .position(|option| *option == values[key])
+        .unwrap_or(0);
+    let next = (current as isize + step).rem_euclid(options.len() as isize) as usize;
+    values[key] = options[next].clone();
+    serde_json::from_value(values).unwrap_or(memory_config)
+}
+
+fn progress_bar(bytes: usize, total: usize, width: usize) -> String {
+    let (filled, percent) = match total {
+        0 => (width, 100),
+        _ => (bytes * width / total, bytes * 100 / total),
+    };
+    format!(
+        "[{}{}] {percent:>3}%",
+        "#".repeat(filled),
+        " ".repeat(width - filled)
+    )
+}
+
+fn phase_name(phase: Phase) -> &'static str {
+    match phase {
+        Phase::Configure => "configuring",
+        Phase::Erase => "erasing",
+        Phase::Write => "writing",
+        Phase::Read => "reading",
+        Phase::Verify => "verifying",
+    }
+}
+
+/// Write the images one after the other, stopping at the first failure
+fn flash_images(
+    mut session: Session<HpmDevice>,
+    images: Vec<(usize, PathBuf, FlashOptions)>,
+    events: Sender<JobEvent>,
+    cancel: Arc<AtomicBool>,
+) -> Session<HpmDevice> {
+    for (index, path, options) in images {
+        let start = Instant::now();
+        let result = fs::read(&path)
+            .map_err(|e| format!("{}: {e}", path.display()))
+            .and_then(|data| {
+                let events = events.clone();
+                let progress = |event: &ProgressEvent| {
+                    if cancel.load(Ordering::Relaxed) {
+                        return ControlFlow::Break(());
+                    }
+                    let _ = events.send(JobEvent::Progress(index, *event));
+                    ControlFlow::Continue(())
+                };
+                session
+                    .flash_image(&data, &options, progress)
+                    .map_err(|e| e.to_string())
+            });
+        let failed = result.is_err();
+        let _ = events.send(match result {
+            Ok(()) => JobEvent::Done(index, start.elapsed()),
+            Err(e) => JobEvent::Failed(index, e),
+        });
+        if failed {
+            break;
+        }
+    }
+    session
+}
+
+impl App {
+    fn new(devices: Vec<DeviceInfo>, xpi: MemoryId, memory_config: MemoryConfig) -> Self {
+        let mut device = ListState::default();
+        device.select((!devices.is_empty()).then_some(0));
+        Self {
+            focus: Pane::Devices,
+            devices,
+            device,
+            xpi,
+            memory_config,
+            field: 0,
+            images: Vec::new(),
+            image: ListState::default(),
+            verify: true,
+            memory: MemoryView {
+                offset: 0,
+                data: Vec::new(),
+                error: None,
+            },
+            memory_lines: 16,
+            prompt: None,
+            status: String::new(),
+            session: None,
+            job: None,
+            quit: false,
+        }
+    }
+
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> Result<(), Box<dyn Error>> {
+        while !self.quit {
+            self.poll_job();
+            terminal.draw(|frame| self.draw(frame))?;
+            if event::poll(POLL_INTERVAL)? {
+                if let Event::Key(key) = event::read()? {
+                    if key.kind == KeyEventKind::Press {
+                        self.handle_key(key);
+                    }
+                }
+            }
+        }
+        if let Some(job) = self.job.take() {
+            job.cancel.store(true, Ordering::Relaxed);
+            let _ = job.handle.join();
+        }
+        Ok(())
+    }
+
+    fn session(&mut self) -> Result<&mut Session<HpmDevice>, String> {
+        if self.job.is_some() {
+            return Err("busy flashing".to_string());
+        }
+        if self.session.is_none() {
+            let serial = self
+                .device
+                .selected()
+                .and_then(|index| self.devices.get(index))
+                .and_then(|device| device.serial_number.clone());
+            let device = open_device(serial.as_deref()).map_err(|e| e.to_string())?;
+            self.session = Some(Session::new(device));
+        }
+        Ok(self.session.as_mut().unwrap())
+    }
+
+    fn options(&self) -> FlashOptions {
+        FlashOptions::new(self.xpi).memory_config(self.memory_config)
+    }
+
+    fn refresh_devices(&mut self) {
+        if self.job.is_some() {
+            self.status = "busy flashing".to_string();
+            return;
+        }
+        match HpmDevice::list() {
+            Ok(devices) => {
+                self.status = format!("{} device(s) attached", devices.len());
+                self.device.select((!devices.is_empty()).then_some(0));
+                self.devices = devices;
+                self.session = None;
+            }
+            Err(e) => self.status = e.to_string(),
+        }
+    }
+
+    fn load_memory(&mut self) {
+        let length = self.memory_lines * LINE_BYTES;
+        let options = self.options().offset(self.memory.offset);
+        let result = self.session().and_then(|session| {
+            session
+                .dump(length, &options, NoProgress)
+                .map_err(|e| e.to_string())
+        });
+        match result {
+            Ok(data) => {
+                self.memory.data = data;
+                self.memory.error = None;
+            }
+            Err(e) => {
+                self.memory.data.clear();
+                self.memory.error = Some(e);
+            }
+        }
+    }
+
+    /// Offset of the last page of the memory view, keeping the page within
+    /// the XPI window
+    fn last_memory_offset(&self) -> u32 {
+        self.xpi
+            .window_size()
+            .saturating_sub((self.memory_lines * LINE_BYTES) as u32)
+    }
+
+    fn scroll_memory(&mut self, lines: isize) {
+        let step = (lines.unsigned_abs() * LINE_BYTES) as u32;
+        self.memory.offset = if lines < 0 {
+            self.memory.offset.saturating_sub(step)
+        } else {
+            self.memory
+                .offset
+                .saturating_add(step)
+                .min(self.last_memory_offset())
+        };
+        self.load_memory();
+    }
+
+    fn start_flash(&mut self) {
+        if self.images.is_empty() {
+            self.status = "no images, press a to add one".to_string();
+            return;
+        }
+        let session = match self.session() {
+            Ok(_) => self.session.take().unwrap(),
+            Err(e) => {
+                self.status = e;
+                return;
+            }
+        };
+        let options = self.options().verify(self.verify);
+        let images = self
+            .images
+            .iter_mut()
+            .enumerate()
+            .map(|(index, image)| {
+                image.state = ImageState::Pending;
+                (index, image.path.clone(), options.offset(image.offset))
+            })
+            .collect();
+        let (sender, events) = mpsc::channel();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let cancel = cancel.clone();
+            thread::spawn(move || flash_images(session, images, sender, cancel))
+        };
+        self.job = Some(Job {
+            events,
+            cancel,
+            handle,
+        });
+        self.status = "flashing".to_string();
+    }
+
+    /// Apply the job's progress, and take the session back once it finished
+    fn poll_job(&mut self) {
+        let Some(job) = &self.job else {
+            return;
+        };
+        for event in job.events.try_iter() {
+            match event {
+                JobEvent::Progress(index, event) => {
+                    self.images[index].state = ImageState::Running(event);
+                }
+                JobEvent::Done(index, elapsed) => {
+                    self.images[index].state = ImageState::Done(elapsed);
+                }
+                JobEvent::Failed(index, e) => {
+                    self.status = format!("{}: {e}", self.images[index].path.display());
+                    self.images[index].state = ImageState::Failed(e);
+                }
+            }
+        }
+        if job.handle.is_finished() {
+            let job = self.job.take().unwrap();
+            match job.handle.join() {
+                Ok(session) => self.session = Some(session),
+                Err(_) => self.status = "flash job panicked".to_string(),
+            }
+            if self
+                .images
+                .iter()
+                .all(|image| matches!(image.state, ImageState::Done(_)))
+            {
+                self.status = "all images written".to_string();
+            }
+        }
+    }
+
+    fn submit_prompt(&mut self, prompt: Prompt, text: &str) {
+        match prompt {
+            Prompt::AddImage => match parse_image_spec(text) {
+                Ok((path, offset)) => {
+                    self.images.push(ImageEntry {
+                        path,
+                        offset,
+                        state: ImageState::Pending,
+                    });
+                    self.image.select(Some(self.images.len() - 1));
+                }
+                Err(e) => self.status = e,
+            },
+            Prompt::Goto => match parse_number(text.trim()) {
+                Ok(address) => match self.xpi.to_offset(address) {
+                    Ok(offset) => {
+                        self.memory.offset =
+                            (offset & !(LINE_BYTES as u32 - 1)).min(self.last_memory_offset());
+                        self.load_memory();
+                    }
+                    Err(e) => self.status = e.to_string(),
+                },
+                Err(e) => self.status = e,
+            },
+        }
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) {
+        if let Some((_, text)) = &mut self.prompt {
+            match key.code {
+                KeyCode::Char(c) => text.push(c),
+                KeyCode::Backspace => {
+                    text.pop();
+                }
+                KeyCode::Enter => {
+                    let (prompt, text) = self.prompt.take().unwrap();
+                    self.submit_prompt(prompt, &text);
+                }
+                KeyCode::Esc => self.prompt = None,
+                _ => {}
+            }
+            return;
+        }
+
+        match key.code {
+            KeyCode::Char('q') => self.quit = true,
+            KeyCode::Tab => {
+                self.focus = self.focus.next();
+                if self.focus == Pane::Memory && self.memory.data.is_empty() {
+                    self.load_memory();
+                }
+            }
+            KeyCode::Esc => {
+                if let Some(job) = &self.job {
+                    job.cancel.store(true, Ordering::Relaxed);
+                    self.status = "cancelling".to_string();
+                }
+            }
+            _ => match self.focus {
+                Pane::Devices => self.devices_key(key.code),
+                Pane::Config => self.config_key(key.code),
+                Pane::Images => self.images_key(key.code),
+                Pane::Memory => self.memory_key(key.code),
+            },
+        }
+    }
+
+    fn devices_key(&mut self, code: KeyCode) {
+        let selected = self.device.selected();
+        match code {
+            KeyCode::Up => self.device.select_previous(),
+            KeyCode::Down if selected.is_some_and(|i| i + 1 < self.devices.len()) => {
+                self.device.select_next()
+            }
+            KeyCode::Char('r') => self.refresh_devices(),
+            _ => {}
+        }
+        if self.device.selected() != selected && self.job.is_none() {
+            self.session = None;
+        }
+    }
+
+    fn config_key(&mut self, code: KeyCode) {
+        let step = match code {
+            KeyCode::Up => {
+                self.field = self.field.saturating_sub(1);
+                return;
+            }
+            KeyCode::Down => {
+                self.field = (self.field + 1).min(CONFIG_KEYS.len());
+                return;
+            }
+            KeyCode::Left => -1,
+            KeyCode::Right | KeyCode::Enter => 1,
+            _ => return,
+        };
+        match self.field {
+            0 => {
+                self.xpi = match self.xpi {
+                    MemoryId::XPI0 => MemoryId::XPI1,
+                    _ => MemoryId::XPI0,
+                }
+            }
+            field => {
+                self.memory_config = cycle_config(self.memory_config, CONFIG_KEYS[field - 1], step)
+            }
+        }
+        self.memory.data.clear();
+    }
+
+    fn images_key(&mut self, code: KeyCode) {
+        match code {
+            KeyCode::Up => self.image.select_previous(),
+            KeyCode::Down => self.image.select_next(),
+            KeyCode::Char('a') => self.prompt = Some((Prompt::AddImage, String::new())),
+            KeyCode::Char('d') if self.job.is_none() => {
+                if let Some(index) = self.image.selected().filter(|&i| i < self.images.len()) {
+                    self.images.remove(index);
+                    if self.images.is_empty() {
+                        self.image.select(None);
+                    }
+                }
+            }
+            KeyCode::Char('v') => self.verify = !self.verify,
+            KeyCode::Char('f') if self.job.is_none() => self.start_flash(),
+            _ => {}
+        }
+    }
+
+    fn memory_key(&mut self, code: KeyCode) {
+        let page = self.memory_lines as isize;
+        match code {
+            KeyCode::Up => self.scroll_memory(-1),
+            KeyCode::Down => self.scroll_memory(1),
+            KeyCode::PageUp => self.scroll_memory(-page),
+            KeyCode::PageDown => self.scroll_memory(page),
+            KeyCode::Char('g') => self.prompt = Some((Prompt::Goto, String::new())),
+            KeyCode::Char('r') => self.load_memory(),
+            _ => {}
+        }
+    }
+
+    fn block(&self, pane: Pane, title: String) -> Block<'static> {
+        let block = Block::bordered().title(title);
+        if self.focus == pane {
+            block.border_style(Style::new().fg(Color::Yellow))
+        } else {
+            block
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let images_height = self.images.len().clamp(1, 8) as u16 + 2;
+        let [top, images, memory, status] = Layout::vertical([
+            Constraint::Length(CONFIG_KEYS.len() as u16 + 3),
+            Constraint::Length(images_height),
+            Constraint::Min(3),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [devices, config] =
+            Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)]).areas(top);
+
+        self.draw_devices(frame, devices);
+        self.draw_config(frame, config);
+        self.draw_images(frame, images);
+        self.draw_memory(frame, memory);
+
+        let status_line = match &self.prompt {
+            Some((Prompt::AddImage, text)) => format!("Add image (PATH@OFFSET): {text}_"),
+            Some((Prompt::Goto, text)) => format!("Go to address: {text}_"),
+            None if self.status.is_empty() => format!("{}  Tab pane  q quit", self.focus.help()),
+            None => format!(
+                "{}  |  {}  Tab pane  q quit",
+                self.status,
+                self.focus.help()
+            ),
+        };
+        frame.render_widget(Paragraph::new(status_line), status);
+    }
+
+    fn draw_devices(&mut self, frame: &mut Frame, area: Rect) {
+        let items: Vec<ListItem> = self
+            .devices
+            .iter()
+            .map(|device| {
+                ListItem::new(format!(
+                    "{}  {}",
+                    device.family,
+                    device.serial_number.as_deref().unwrap_or("-")
+                ))
+            })
+            .collect();
+        let title = match items.len() {
+            0 => "Devices (none, r to refresh)".to_string(),
+            _ => "Devices".to_string(),
+        };
+        let list = List::new(items)
+            .block(self.block(Pane::Devices, title))
+            .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, area, &mut self.device);
+    }
+
+    fn draw_config(&self, frame: &mut Frame, area: Rect) {
+        let rows = std::iter::once(("xpi", format!("{:?}", self.xpi))).chain(
+            CONFIG_KEYS
+                .iter()
+                .map(|key| (*key, config_value(self.memory_config, key))),
+        );
+        let lines: Vec<Line> = rows
+            .enumerate()
+            .map(|(i, (key, value))| {
+                let line = Line::from(format!("{key:<24}{value}"));
+                if i == self.field && self.focus == Pane::Config {
+                    line.style(Style::new().add_modifier(Modifier::REVERSED))
+                } else {
+                    line
+                }
+            })
+            .collect();
+        frame.render_widget(
+            Paragraph::new(lines).block(self.block(Pane::Config, "Memory config".to_string())),
+            area,
+        );
+    }
+
+    fn draw_images(&mut self, frame: &mut Frame, area: Rect) {
+        let bar_width = (area.width as usize).saturating_sub(60).clamp(10, 30);
+        let items: Vec<ListItem> = self
+            .images
+            .iter()
+            .map(|image| {
+                let state = match &image.state {
+                    ImageState::Pending => "pending".to_string(),
+                    ImageState::Running(event) => format!(
+                        "{:<12}{}",
+                        phase_name(event.phase),
+                        progress_bar(event.bytes, event.total, bar_width)
+                    ),
+                    ImageState::Done(elapsed) => format!("done in {:.1}s", elapsed.as_secs_f32()),
+                    ImageState::Failed(e) => format!("failed: {e}"),
+                };
+                ListItem::new(format!(
+                    "{:#010x}  {:<30}  {state}",
+                    image.offset,
+                    image.path.display()
+                ))
+            })
+            .collect();
+        let title = format!("Images (verify {})", if self.verify { "on" } else { "off" });
+        let list = List::new(items)
+            .block(self.block(Pane::Images, title))
+            .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, area, &mut self.image);
+    }
+
+    fn draw_memory(&mut self, frame: &mut Frame, area: Rect) {
+        self.memory_lines = (area.height as usize).saturating_sub(2).max(1);
+        let address = self.xpi.base_address() + self.memory.offset;
+        let lines: Vec<Line> = match &self.memory.error {
+            Some(e) => vec![Line::from(e.clone())],
+            None if self.memory.data.is_empty() => vec![Line::from("r to read")],
+            None => hexdump(address, &self.memory.data, Width::Byte)
+                .into_iter()
+                .map(Line::from)
+                .collect(),
+        };
+        let title = format!("Memory {:?} @ {address:#010x}", self.xpi);
+        frame.render_widget(
+            Paragraph::new(lines).block(self.block(Pane::Memory, title)),
+            area,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+
+    use super::*;
+
+    #[test]
+    fn parses_image_spec() {
+        assert_eq!(
+            parse_image_spec("out/app.bin@0x3000"),
+            Ok((PathBuf::from("out/app.bin"), 0x3000))
+        );
+        assert!(parse_image_spec("app.bin").is_err());
+        assert!(parse_image_spec("@0x3000").is_err());
+        assert!(parse_image_spec("app.bin@zz").is_err());
+    }
+
+    #[test]
+    fn cycles_config_values() {
+        let memory_config = cycle_config(MemoryConfig::new(), "flash_type", 1);
+        assert_eq!(config_value(memory_config, "flash_type"), "sfdp_ddr");
+
+        let memory_config = cycle_config(MemoryConfig::new(), "pin_group", -1);
+        assert_eq!(config_value(memory_config, "pin_group"), "group2");
+        let memory_config = cycle_config(memory_config, "pin_group", 1);
+        assert_eq!(memory_config, MemoryConfig::new());
+    }
+
+    #[test]
+    fn draws_progress_bar() {
+        assert_eq!(progress_bar(1, 4, 8), "[##      ]  25%");
+        assert_eq!(progress_bar(0, 0, 4), "[####] 100%");
+    }
+
+    #[test]
+    fn clamps_goto_to_window() {
+        let mut app = App::new(Vec::new(), MemoryId::XPI0, MemoryConfig::new());
+        app.memory_lines = 4;
+
+        app.submit_prompt(Prompt::Goto, "0x8fffffff");
+        assert_eq!(
+            app.memory.offset,
+            MemoryId::XPI0.window_size() - 4 * LINE_BYTES as u32
+        );
+        app.submit_prompt(Prompt::Goto, "0x80000020");
+        assert_eq!(app.memory.offset, 0x20);
+    }
+
+    #[test]
+    fn renders_panes() {
+        let mut app = App::new(Vec::new(), MemoryId::XPI0, MemoryConfig::new());
+        app.submit_prompt(Prompt::AddImage, "app.bin@0x3000");
+        let mut terminal = Terminal::new(TestBackend::new(100, 24)).unwrap();
+
+        terminal.draw(|frame| app.draw(frame)).unwrap();
+
+        let screen: String = terminal
+            .backend()
+            .buffer()
+            .content()
+            .iter()
+            .map(|cell| cell.symbol())
+            .collect();
+        assert!(screen.contains("Devices (none, r to refresh)"));
+        assert!(screen.contains("flash_type              sfdp_sdr"));
+        assert!(screen.contains("0x00003000  app.bin"));
+        assert!(screen.contains("Memory XPI0 @ 0x80000000"));
+    }
+}
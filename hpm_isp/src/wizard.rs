@@ -1,11 +1,13 @@
 use std::error::Error;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use dialoguer::{theme::ColorfulTheme, Confirm, Select};
 
 use crate::config::Config;
-use hpm_isp::memory_config::{MemoryConfig, PinGroup, PortConnection, QuadIOEnableSequence};
+use hpm_isp::memory_config::{
+    MemoryConfig, PinGroup, PortConnection, QuadIOEnableSequence, BOARD_PRESETS,
+};
 
 trait SelectPromptModel: Sized {
     const COUNT: u32;
@@ -97,19 +99,46 @@ impl SelectPromptModel for QuadIOEnableSequence {
     }
 }
 
-pub fn config_wizard<P>(path: P) -> Result<(), Box<dyn Error>>
+/// Returns the memory config of the selected evaluation board, or `None` for a
+/// custom board
+fn select_board_preset() -> std::io::Result<Option<MemoryConfig>> {
+    let items: Vec<String> = std::iter::once("Custom board".to_string())
+        .chain(
+            BOARD_PRESETS
+                .iter()
+                .map(|preset| format!("{} ({})", preset.name, preset.family)),
+        )
+        .collect();
+    let selection = Select::with_theme(&ColorfulTheme::default())
+        .items(&items)
+        .with_prompt("Select board")
+        .default(0)
+        .interact()?;
+    Ok(selection
+        .checked_sub(1)
+        .map(|i| BOARD_PRESETS[i].memory_config))
+}
+
+/// Returns the path of the saved config file, or `None` if the user declined to
+/// overwrite an existing one
+pub fn config_wizard<P>(path: P) -> Result<Option<PathBuf>, Box<dyn Error>>
 where
     P: AsRef<Path>,
 {
-    let port_connection = PortConnection::show_select_prompt("Select port connection")?;
-    let pin_group = PinGroup::show_select_prompt("Select pin group")?;
-    let quad_io_enable_sequence =
-        QuadIOEnableSequence::show_select_prompt("Select Quad Enable sequence")?;
-
-    let config = MemoryConfig::new()
-        .port_connection(port_connection)
-        .pin_group(pin_group)
-        .quad_io_enable_sequence(quad_io_enable_sequence);
+    let config = match select_board_preset()? {
+        Some(config) => config,
+        None => {
+            let port_connection = PortConnection::show_select_prompt("Select port connection")?;
+            let pin_group = PinGroup::show_select_prompt("Select pin group")?;
+            let quad_io_enable_sequence =
+                QuadIOEnableSequence::show_select_prompt("Select Quad Enable sequence")?;
+
+            MemoryConfig::new()
+                .port_connection(port_connection)
+                .pin_group(pin_group)
+                .quad_io_enable_sequence(quad_io_enable_sequence)
+        }
+    };
 
     if path.as_ref().exists() {
         let replace = Confirm::with_theme(&ColorfulTheme::default())
@@ -119,17 +148,12 @@ where
             ))
             .interact()?;
         if !replace {
-            return Ok(());
+            return Ok(None);
         }
     }
 
     let output = Config::new(config).to_toml_string()?;
     fs::write(&path, output)?;
 
-    println!(
-        "Config file was successfully saved to: {}",
-        fs::canonicalize(path)?.display()
-    );
-
-    Ok(())
+    Ok(Some(fs::canonicalize(path)?))
 }
@@ -0,0 +1,164 @@
+/* Generated by cbindgen from hpm_isp_capi/src/lib.rs, do not edit */
+
+#ifndef HPM_ISP_H
+#define HPM_ISP_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+#define HPM_ISP_MEMORY_ILM 0
+
+#define HPM_ISP_MEMORY_DLM 1
+
+#define HPM_ISP_MEMORY_XRAM 2
+
+#define HPM_ISP_MEMORY_XPI0 65536
+
+#define HPM_ISP_MEMORY_XPI1 65537
+
+// Result of every call, mapped from the errors of the library
+typedef enum HpmIspStatus {
+  HPM_ISP_STATUS_OK = 0,
+  HPM_ISP_STATUS_NAK = 1,
+  HPM_ISP_STATUS_TRANSFER_ERROR = 2,
+  HPM_ISP_STATUS_TIMEOUT = 3,
+  // The progress callback asked to stop
+  HPM_ISP_STATUS_ABORTED = 4,
+  HPM_ISP_STATUS_VERIFY_FAILED = 5,
+  HPM_ISP_STATUS_IO_ERROR = 6,
+  // The BootROM answered with a failure status
+  HPM_ISP_STATUS_DEVICE_STATUS = 7,
+  HPM_ISP_STATUS_DEVICE_NOT_FOUND = 8,
+  HPM_ISP_STATUS_INVALID_ARGUMENT = 9,
+  HPM_ISP_STATUS_INVALID_CONFIG = 10,
+  // A bug in the library, the device should be closed
+  HPM_ISP_STATUS_PANIC = 11,
+} HpmIspStatus;
+
+typedef enum HpmIspPhase {
+  HPM_ISP_PHASE_CONFIGURE = 0,
+  HPM_ISP_PHASE_ERASE = 1,
+  HPM_ISP_PHASE_WRITE = 2,
+  HPM_ISP_PHASE_READ = 3,
+  HPM_ISP_PHASE_VERIFY = 4,
+} HpmIspPhase;
+
+// Open device, released with [`hpm_isp_close`]
+typedef struct HpmIspDevice HpmIspDevice;
+
+typedef struct HpmIspProgress {
+  enum HpmIspPhase phase;
+  // Bytes transferred so far
+  size_t bytes;
+  // Total bytes of this phase
+  size_t total;
+  // Time elapsed since the phase started
+  uint64_t elapsed_ms;
+} HpmIspProgress;
+
+// Called as data is transferred, returning non-zero aborts the transfer
+typedef int32_t (*HpmIspProgressCallback)(const struct HpmIspProgress *progress, void *user_data);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Message of the last failed call on this thread
+//
+// The string stays valid until the next call failing on this thread.
+const char *hpm_isp_last_error(void);
+
+// Open an attached device, the first one found if `serial_number` is NULL
+//
+// # Safety
+//
+// `serial_number` is NULL or a NUL-terminated string, `device` is valid for
+// writes.
+enum HpmIspStatus hpm_isp_open(const char *serial_number, struct HpmIspDevice **device);
+
+// Open a simulated device of the family with the given USB product ID, e.g.
+// `0x0005` for HPM5300
+//
+// # Safety
+//
+// `device` is valid for writes.
+enum HpmIspStatus hpm_isp_open_simulated(uint16_t product_id, struct HpmIspDevice **device);
+
+// Close a device, NULL is ignored
+//
+// # Safety
+//
+// `device` is NULL or returned by one of the open functions, and not used
+// afterwards.
+void hpm_isp_close(struct HpmIspDevice *device);
+
+// Configure an XPI instance with a raw XPI NOR configuration option
+//
+// # Safety
+//
+// `device` is open, `config` is valid for `length` bytes.
+enum HpmIspStatus hpm_isp_configure_memory_bytes(struct HpmIspDevice *device,
+                                                 uint32_t memory,
+                                                 const uint8_t *config,
+                                                 size_t length);
+
+// Configure an XPI instance with a memory config in TOML, either a config
+// file with a `[memory_config]` table or the table's keys alone
+//
+// # Safety
+//
+// `device` is open, `toml` is a NUL-terminated string.
+enum HpmIspStatus hpm_isp_configure_memory_toml(struct HpmIspDevice *device,
+                                                uint32_t memory,
+                                                const char *toml);
+
+// Write `length` bytes at `offset`, which may be an absolute address
+//
+// # Safety
+//
+// `device` is open, `data` is valid for `length` bytes.
+enum HpmIspStatus hpm_isp_write(struct HpmIspDevice *device,
+                                uint32_t memory,
+                                uint32_t offset,
+                                const uint8_t *data,
+                                size_t length,
+                                HpmIspProgressCallback callback,
+                                void *user_data);
+
+// Read `length` bytes at `offset`, which may be an absolute address
+//
+// # Safety
+//
+// `device` is open, `data` is valid for writes of `length` bytes.
+enum HpmIspStatus hpm_isp_read(struct HpmIspDevice *device,
+                               uint32_t memory,
+                               uint32_t offset,
+                               uint8_t *data,
+                               size_t length,
+                               HpmIspProgressCallback callback,
+                               void *user_data);
+
+// Read back `length` bytes at `offset` and compare them with `data`
+//
+// Fails with [`HpmIspStatus::VerifyFailed`], and the offset of the first
+// mismatching byte in the last error.
+//
+// # Safety
+//
+// `device` is open, `data` is valid for `length` bytes.
+enum HpmIspStatus hpm_isp_verify(struct HpmIspDevice *device,
+                                 uint32_t memory,
+                                 uint32_t offset,
+                                 const uint8_t *data,
+                                 size_t length,
+                                 HpmIspProgressCallback callback,
+                                 void *user_data);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* HPM_ISP_H */
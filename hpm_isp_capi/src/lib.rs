@@ -0,0 +1,437 @@
+//! C API of the HPMicro ISP library
+//!
+//! Functions return an [`HpmIspStatus`], and [`hpm_isp_last_error`] describes
+//! the last failure of the calling thread. A panic inside the library is
+//! caught and reported as [`HpmIspStatus::Panic`]. The header `include/hpm_isp.h` is
+//! generated from this file by cbindgen, the `header_is_up_to_date` test
+//! rewrites it when run with `UPDATE_HEADER=1`.
+
+use std::cell::RefCell;
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::ops::ControlFlow;
+use std::panic::{self, AssertUnwindSafe};
+use std::slice;
+
+use strum::IntoEnumIterator;
+
+use hpm_isp::hid::{Family, HpmDevice};
+use hpm_isp::isp_command::{Error, IspCommand, MemoryId};
+use hpm_isp::memory_config::parse_memory_config;
+use hpm_isp::progress::{Phase, ProgressEvent};
+use hpm_isp::session::Session;
+use hpm_isp::sim::{SimulatedDevice, Transport};
+
+pub const HPM_ISP_MEMORY_ILM: u32 = 0x00;
+pub const HPM_ISP_MEMORY_DLM: u32 = 0x01;
+pub const HPM_ISP_MEMORY_XRAM: u32 = 0x02;
+pub const HPM_ISP_MEMORY_XPI0: u32 = 0x10000;
+pub const HPM_ISP_MEMORY_XPI1: u32 = 0x10001;
+
+/// Result of every call, mapped from the errors of the library
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpmIspStatus {
+    Ok = 0,
+    Nak = 1,
+    TransferError = 2,
+    Timeout = 3,
+    /// The progress callback asked to stop
+    Aborted = 4,
+    VerifyFailed = 5,
+    IoError = 6,
+    /// The BootROM answered with a failure status
+    DeviceStatus = 7,
+    DeviceNotFound = 8,
+    InvalidArgument = 9,
+    InvalidConfig = 10,
+    /// A bug in the library, the device should be closed
+    Panic = 11,
+}
+
+impl From<&Error> for HpmIspStatus {
+    fn from(e: &Error) -> Self {
+        match e {
+            Error::Nak => HpmIspStatus::Nak,
+            Error::TransferError => HpmIspStatus::TransferError,
+            Error::Timeout => HpmIspStatus::Timeout,
+            Error::Aborted => HpmIspStatus::Aborted,
+            Error::OutOfRange(_) => HpmIspStatus::InvalidArgument,
+            Error::VerifyFailed(_) => HpmIspStatus::VerifyFailed,
+            Error::IoError(_) => HpmIspStatus::IoError,
+            Error::Other(_) => HpmIspStatus::DeviceStatus,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpmIspPhase {
+    Configure = 0,
+    Erase = 1,
+    Write = 2,
+    Read = 3,
+    Verify = 4,
+}
+
+impl From<Phase> for HpmIspPhase {
+    fn from(phase: Phase) -> Self {
+        match phase {
+            Phase::Configure => HpmIspPhase::Configure,
+            Phase::Erase => HpmIspPhase::Erase,
+            Phase::Write => HpmIspPhase::Write,
+            Phase::Read => HpmIspPhase::Read,
+            Phase::Verify => HpmIspPhase::Verify,
+        }
+    }
+}
+
+#[repr(C)]
+pub struct HpmIspProgress {
+    pub phase: HpmIspPhase,
+    /// Bytes transferred so far
+    pub bytes: usize,
+    /// Total bytes of this phase
+    pub total: usize,
+    /// Time elapsed since the phase started
+    pub elapsed_ms: u64,
+}
+
+/// Called as data is transferred, returning non-zero aborts the transfer
+pub type HpmIspProgressCallback =
+    Option<extern "C" fn(progress: *const HpmIspProgress, user_data: *mut c_void) -> i32>;
+
+/// Open device, released with [`hpm_isp_close`]
+pub struct HpmIspDevice {
+    session: Session<Transport>,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn fail(status: HpmIspStatus, message: impl ToString) -> HpmIspStatus {
+    let message = CString::new(message.to_string().replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = message);
+    status
+}
+
+/// Run the body of an exported function, as unwinding into C is undefined
+/// behaviour
+fn guard(body: impl FnOnce() -> HpmIspStatus) -> HpmIspStatus {
+    panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown panic");
+        fail(HpmIspStatus::Panic, format!("panic: {message}"))
+    })
+}
+
+fn status(result: Result<(), Error>) -> HpmIspStatus {
+    match result {
+        Ok(()) => HpmIspStatus::Ok,
+        Err(e) => fail((&e).into(), e),
+    }
+}
+
+fn memory_id(memory_id: u32) -> Result<MemoryId, HpmIspStatus> {
+    MemoryId::iter()
+        .find(|id| *id as u32 == memory_id)
+        .ok_or_else(|| {
+            fail(
+                HpmIspStatus::InvalidArgument,
+                format!("unknown memory {memory_id:#x}"),
+            )
+        })
+}
+
+/// Memory and offset of a transfer, `offset` may be an absolute address
+fn target(memory: u32, offset: u32) -> Result<(MemoryId, u32), HpmIspStatus> {
+    let memory_id = memory_id(memory)?;
+    let offset = memory_id
+        .to_offset(offset)
+        .map_err(|e| fail((&e).into(), e))?;
+    Ok((memory_id, offset))
+}
+
+/// Forward progress events to the C callback
+fn progress(
+    callback: HpmIspProgressCallback,
+    user_data: *mut c_void,
+) -> impl FnMut(&ProgressEvent) -> ControlFlow<()> {
+    move |event: &ProgressEvent| {
+        let Some(callback) = callback else {
+            return ControlFlow::Continue(());
+        };
+        let progress = HpmIspProgress {
+            phase: event.phase.into(),
+            bytes: event.bytes,
+            total: event.total,
+            elapsed_ms: event.elapsed.as_millis() as u64,
+        };
+        match callback(&progress, user_data) {
+            0 => ControlFlow::Continue(()),
+            _ => ControlFlow::Break(()),
+        }
+    }
+}
+
+/// Message of the last failed call on this thread
+///
+/// The string stays valid until the next call failing on this thread.
+#[no_mangle]
+pub extern "C" fn hpm_isp_last_error() -> *const c_char {
+    panic::catch_unwind(|| LAST_ERROR.with(|last_error| last_error.borrow().as_ptr()))
+        .unwrap_or(c"".as_ptr())
+}
+
+/// Open an attached device, the first one found if `serial_number` is NULL
+///
+/// # Safety
+///
+/// `serial_number` is NULL or a NUL-terminated string, `device` is valid for
+/// writes.
+#[no_mangle]
+pub unsafe extern "C" fn hpm_isp_open(
+    serial_number: *const c_char,
+    device: *mut *mut HpmIspDevice,
+) -> HpmIspStatus {
+    guard(|| {
+        if device.is_null() {
+            return fail(HpmIspStatus::InvalidArgument, "device is NULL");
+        }
+        let opened = if serial_number.is_null() {
+            HpmDevice::open()
+        } else {
+            match CStr::from_ptr(serial_number).to_str() {
+                Ok(serial_number) => HpmDevice::open_serial(serial_number),
+                Err(e) => return fail(HpmIspStatus::InvalidArgument, e),
+            }
+        };
+        match opened {
+            Ok(opened) => {
+                *device = Box::into_raw(Box::new(HpmIspDevice {
+                    session: Session::new(Transport::Hid(opened)),
+                }));
+                HpmIspStatus::Ok
+            }
+            Err(e) => fail(HpmIspStatus::DeviceNotFound, e),
+        }
+    })
+}
+
+/// Open a simulated device of the family with the given USB product ID, e.g.
+/// `0x0005` for HPM5300
+///
+/// # Safety
+///
+/// `device` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn hpm_isp_open_simulated(
+    product_id: u16,
+    device: *mut *mut HpmIspDevice,
+) -> HpmIspStatus {
+    guard(|| {
+        if device.is_null() {
+            return fail(HpmIspStatus::InvalidArgument, "device is NULL");
+        }
+        let Some(family) = Family::iter().find(|family| family.vid() == product_id) else {
+            return fail(
+                HpmIspStatus::InvalidArgument,
+                format!("unknown product ID {product_id:#06x}"),
+            );
+        };
+        *device = Box::into_raw(Box::new(HpmIspDevice {
+            session: Session::new(Transport::Simulated(SimulatedDevice::new(family))),
+        }));
+        HpmIspStatus::Ok
+    })
+}
+
+/// Close a device, NULL is ignored
+///
+/// # Safety
+///
+/// `device` is NULL or returned by one of the open functions, and not used
+/// afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn hpm_isp_close(device: *mut HpmIspDevice) {
+    guard(|| {
+        if !device.is_null() {
+            drop(Box::from_raw(device));
+        }
+        HpmIspStatus::Ok
+    });
+}
+
+/// Configure an XPI instance with a raw XPI NOR configuration option
+///
+/// # Safety
+///
+/// `device` is open, `config` is valid for `length` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn hpm_isp_configure_memory_bytes(
+    device: *mut HpmIspDevice,
+    memory: u32,
+    config: *const u8,
+    length: usize,
+) -> HpmIspStatus {
+    guard(|| {
+        if device.is_null() || config.is_null() {
+            return fail(HpmIspStatus::InvalidArgument, "device or config is NULL");
+        }
+        let memory_id = match memory_id(memory) {
+            Ok(memory_id) => memory_id,
+            Err(status) => return status,
+        };
+        status(
+            (*device)
+                .session
+                .configure_bytes(memory_id, slice::from_raw_parts(config, length)),
+        )
+    })
+}
+
+/// Configure an XPI instance with a memory config in TOML, either a config
+/// file with a `[memory_config]` table or the table's keys alone
+///
+/// # Safety
+///
+/// `device` is open, `toml` is a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn hpm_isp_configure_memory_toml(
+    device: *mut HpmIspDevice,
+    memory: u32,
+    toml: *const c_char,
+) -> HpmIspStatus {
+    guard(|| {
+        if device.is_null() || toml.is_null() {
+            return fail(HpmIspStatus::InvalidArgument, "device or toml is NULL");
+        }
+        let memory_id = match memory_id(memory) {
+            Ok(memory_id) => memory_id,
+            Err(status) => return status,
+        };
+        let memory_config = match CStr::from_ptr(toml)
+            .to_str()
+            .map_err(|e| e.to_string())
+            .and_then(|toml| parse_memory_config(toml).map_err(|e| e.to_string()))
+        {
+            Ok(memory_config) => memory_config,
+            Err(e) => return fail(HpmIspStatus::InvalidConfig, e),
+        };
+        status((*device).session.configure(memory_id, memory_config))
+    })
+}
+
+/// Write `length` bytes at `offset`, which may be an absolute address
+///
+/// # Safety
+///
+/// `device` is open, `data` is valid for `length` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn hpm_isp_write(
+    device: *mut HpmIspDevice,
+    memory: u32,
+    offset: u32,
+    data: *const u8,
+    length: usize,
+    callback: HpmIspProgressCallback,
+    user_data: *mut c_void,
+) -> HpmIspStatus {
+    guard(|| {
+        if device.is_null() || data.is_null() {
+            return fail(HpmIspStatus::InvalidArgument, "device or data is NULL");
+        }
+        let (memory_id, offset) = match target(memory, offset) {
+            Ok(target) => target,
+            Err(status) => return status,
+        };
+        status((*device).session.device().write_memory(
+            memory_id,
+            offset,
+            slice::from_raw_parts(data, length),
+            progress(callback, user_data),
+        ))
+    })
+}
+
+/// Read `length` bytes at `offset`, which may be an absolute address
+///
+/// # Safety
+///
+/// `device` is open, `data` is valid for writes of `length` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn hpm_isp_read(
+    device: *mut HpmIspDevice,
+    memory: u32,
+    offset: u32,
+    data: *mut u8,
+    length: usize,
+    callback: HpmIspProgressCallback,
+    user_data: *mut c_void,
+) -> HpmIspStatus {
+    guard(|| {
+        if device.is_null() || data.is_null() {
+            return fail(HpmIspStatus::InvalidArgument, "device or data is NULL");
+        }
+        let (memory_id, offset) = match target(memory, offset) {
+            Ok(target) => target,
+            Err(status) => return status,
+        };
+        status((*device).session.device().read_memory(
+            memory_id,
+            offset,
+            slice::from_raw_parts_mut(data, length),
+            progress(callback, user_data),
+        ))
+    })
+}
+
+/// Read back `length` bytes at `offset` and compare them with `data`
+///
+/// Fails with [`HpmIspStatus::VerifyFailed`], and the offset of the first
+/// mismatching byte in the last error.
+///
+/// # Safety
+///
+/// `device` is open, `data` is valid for `length` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn hpm_isp_verify(
+    device: *mut HpmIspDevice,
+    memory: u32,
+    offset: u32,
+    data: *const u8,
+    length: usize,
+    callback: HpmIspProgressCallback,
+    user_data: *mut c_void,
+) -> HpmIspStatus {
+    guard(|| {
+        if device.is_null() || data.is_null() {
+            return fail(HpmIspStatus::InvalidArgument, "device or data is NULL");
+        }
+        let (memory_id, offset) = match target(memory, offset) {
+            Ok(target) => target,
+            Err(status) => return status,
+        };
+        status((*device).session.device().verify_memory(
+            memory_id,
+            offset,
+            slice::from_raw_parts(data, length),
+            progress(callback, user_data),
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_panics() {
+        assert_eq!(guard(|| panic!("boom")), HpmIspStatus::Panic);
+        let message = unsafe { CStr::from_ptr(hpm_isp_last_error()) };
+        assert_eq!(message.to_str().unwrap(), "panic: boom");
+    }
+}
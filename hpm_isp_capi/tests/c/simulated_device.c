@@ -0,0 +1,93 @@
+/* Exercises the C API against the simulated device */
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "hpm_isp.h"
+
+#define HPM5300_PRODUCT_ID 0x0005
+
+#define CHECK(call, expected)                                                  \
+    do {                                                                       \
+        HpmIspStatus status_ = (call);                                         \
+        if (status_ != (expected)) {                                           \
+            fprintf(stderr, "%s:%d: %s returned %d: %s\n", __FILE__, __LINE__, \
+                    #call, status_, hpm_isp_last_error());                     \
+            exit(1);                                                           \
+        }                                                                      \
+    } while (0)
+
+static const char CONFIG[] = "[memory_config]\n"
+                             "flash_type = \"sfdp_sdr\"\n"
+                             "pin_group = \"group2\"\n";
+
+struct progress {
+    size_t calls;
+    size_t bytes;
+    int abort;
+};
+
+static int on_progress(const HpmIspProgress *event, void *user_data) {
+    struct progress *progress = user_data;
+    progress->calls++;
+    progress->bytes = event->bytes;
+    return progress->abort;
+}
+
+int main(void) {
+    HpmIspDevice *device = NULL;
+    uint8_t image[3000];
+    uint8_t read_back[sizeof(image)];
+    struct progress progress = {0};
+
+    for (size_t i = 0; i < sizeof(image); i++) {
+        image[i] = (uint8_t)i;
+    }
+
+    CHECK(hpm_isp_open_simulated(0xFFFF, &device), HPM_ISP_STATUS_INVALID_ARGUMENT);
+    CHECK(hpm_isp_open_simulated(HPM5300_PRODUCT_ID, &device), HPM_ISP_STATUS_OK);
+
+    /* XPI has to be configured before use */
+    CHECK(hpm_isp_read(device, HPM_ISP_MEMORY_XPI0, 0, read_back, 16, NULL, NULL),
+          HPM_ISP_STATUS_DEVICE_STATUS);
+    CHECK(hpm_isp_configure_memory_toml(device, HPM_ISP_MEMORY_XPI0, "flash_type = 1"),
+          HPM_ISP_STATUS_INVALID_CONFIG);
+    CHECK(hpm_isp_configure_memory_toml(device, HPM_ISP_MEMORY_XPI0, CONFIG),
+          HPM_ISP_STATUS_OK);
+
+    CHECK(hpm_isp_write(device, HPM_ISP_MEMORY_XPI0, 0x80003000, image, sizeof(image),
+                        on_progress, &progress),
+          HPM_ISP_STATUS_OK);
+    if (progress.calls == 0 || progress.bytes != sizeof(image)) {
+        fprintf(stderr, "progress reported %zu of %zu bytes\n", progress.bytes, sizeof(image));
+        return 1;
+    }
+
+    CHECK(hpm_isp_read(device, HPM_ISP_MEMORY_XPI0, 0x3000, read_back, sizeof(read_back),
+                       NULL, NULL),
+          HPM_ISP_STATUS_OK);
+    if (memcmp(image, read_back, sizeof(image)) != 0) {
+        fprintf(stderr, "read back data differs\n");
+        return 1;
+    }
+    CHECK(hpm_isp_verify(device, HPM_ISP_MEMORY_XPI0, 0x3000, image, sizeof(image), NULL,
+                         NULL),
+          HPM_ISP_STATUS_OK);
+    image[100] ^= 0xFF;
+    CHECK(hpm_isp_verify(device, HPM_ISP_MEMORY_XPI0, 0x3000, image, sizeof(image), NULL,
+                         NULL),
+          HPM_ISP_STATUS_VERIFY_FAILED);
+    if (strstr(hpm_isp_last_error(), "0x3064") == NULL) {
+        fprintf(stderr, "unexpected error: %s\n", hpm_isp_last_error());
+        return 1;
+    }
+
+    progress.abort = 1;
+    CHECK(hpm_isp_write(device, HPM_ISP_MEMORY_DLM, 0, image, sizeof(image), on_progress,
+                        &progress),
+          HPM_ISP_STATUS_ABORTED);
+
+    hpm_isp_close(device);
+    return 0;
+}
@@ -0,0 +1,67 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+fn crate_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+}
+
+/// Directory of the shared library built for the tests
+fn library_dir() -> PathBuf {
+    let exe = env::current_exe().unwrap();
+    exe.parent().unwrap().parent().unwrap().to_path_buf()
+}
+
+#[test]
+fn header_is_up_to_date() {
+    let header_path = crate_dir().join("include/hpm_isp.h");
+    let config = cbindgen::Config::from_file(crate_dir().join("cbindgen.toml")).unwrap();
+    let mut header = Vec::new();
+    cbindgen::Builder::new()
+        .with_crate(crate_dir())
+        .with_config(config)
+        .generate()
+        .unwrap()
+        .write(&mut header);
+    let header = String::from_utf8(header).unwrap();
+
+    if env::var_os("UPDATE_HEADER").is_some() {
+        fs::write(&header_path, &header).unwrap();
+    }
+    assert_eq!(
+        fs::read_to_string(&header_path).unwrap(),
+        header,
+        "include/hpm_isp.h is stale, run the test with UPDATE_HEADER=1"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn c_program_runs_against_simulated_device() {
+    let out_dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let program = out_dir.join("simulated_device");
+    let library_dir = library_dir();
+
+    let status = Command::new(env::var("CC").unwrap_or_else(|_| "cc".to_string()))
+        .arg(crate_dir().join("tests/c/simulated_device.c"))
+        .arg("-I")
+        .arg(crate_dir().join("include"))
+        .arg("-L")
+        .arg(&library_dir)
+        .arg(format!("-Wl,-rpath,{}", library_dir.display()))
+        .arg("-lhpm_isp_capi")
+        .arg("-o")
+        .arg(&program)
+        .status()
+        .expect("a C compiler is needed, set CC to use another one than cc");
+    assert!(status.success(), "compiling the C program failed");
+
+    let output = Command::new(&program).output().unwrap();
+    assert!(
+        output.status.success(),
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
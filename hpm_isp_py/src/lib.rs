@@ -0,0 +1,409 @@
+//! Python bindings of the HPMicro ISP library
+//!
+//! Built into the `hpm_isp` Python module with maturin, see `pyproject.toml`.
+
+use std::ops::ControlFlow;
+use std::sync::{Mutex, MutexGuard};
+
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict};
+use strum::IntoEnumIterator;
+
+use hpm_isp::hid::{Family, HpmDevice};
+use hpm_isp::isp_command::{Error, IspCommand, MemoryId};
+use hpm_isp::memory_config::{parse_memory_config, MemoryConfig};
+use hpm_isp::progress::ProgressEvent;
+use hpm_isp::session::Session;
+use hpm_isp::sim::{SimulatedDevice, Transport};
+
+create_exception!(
+    hpm_isp,
+    IspError,
+    PyException,
+    "Failure of an ISP operation"
+);
+create_exception!(hpm_isp, NakError, IspError, "The device refused a packet");
+create_exception!(hpm_isp, TransferError, IspError, "USB transfer failed");
+create_exception!(
+    hpm_isp,
+    IspTimeoutError,
+    IspError,
+    "The device didn't answer"
+);
+create_exception!(
+    hpm_isp,
+    AbortedError,
+    IspError,
+    "The progress callback returned False"
+);
+create_exception!(
+    hpm_isp,
+    VerifyError,
+    IspError,
+    "Read back data differs, `args[1]` is the offset of the first mismatching byte"
+);
+create_exception!(
+    hpm_isp,
+    DeviceStatusError,
+    IspError,
+    "The BootROM answered with a failure status, `args[1]` is the status"
+);
+create_exception!(hpm_isp, DeviceNotFoundError, IspError, "No device to open");
+create_exception!(hpm_isp, ConfigError, PyValueError, "Invalid memory config");
+
+fn isp_error(e: Error) -> PyErr {
+    let message = e.to_string();
+    match e {
+        Error::Nak => NakError::new_err(message),
+        Error::TransferError => TransferError::new_err(message),
+        Error::Timeout => IspTimeoutError::new_err(message),
+        Error::Aborted => AbortedError::new_err(message),
+        Error::OutOfRange(_) => PyValueError::new_err(message),
+        Error::VerifyFailed(offset) => VerifyError::new_err((message, offset)),
+        Error::IoError(_) => IspError::new_err(message),
+        Error::Other(status) => DeviceStatusError::new_err((message, status)),
+    }
+}
+
+fn memory_id(name: &str) -> PyResult<MemoryId> {
+    MemoryId::iter()
+        .find(|memory_id| memory_id.as_str().eq_ignore_ascii_case(name))
+        .ok_or_else(|| {
+            PyValueError::new_err(format!(
+                "unknown memory {name}, expected ILM, DLM, XRAM, XPI0 or XPI1"
+            ))
+        })
+}
+
+/// Memory config of an XPI instance, built from keyword arguments, a dict or
+/// TOML with the keys of the `[memory_config]` table
+#[pyclass(name = "MemoryConfig", eq, module = "hpm_isp")]
+#[derive(Clone, PartialEq)]
+struct PyMemoryConfig(MemoryConfig);
+
+#[pymethods]
+impl PyMemoryConfig {
+    #[new]
+    #[pyo3(signature = (**values))]
+    fn new(values: Option<&Bound<'_, PyDict>>) -> PyResult<Self> {
+        match values {
+            Some(values) => Self::from_dict(values),
+            None => Ok(Self(MemoryConfig::new())),
+        }
+    }
+
+    #[staticmethod]
+    fn from_dict(values: &Bound<'_, PyDict>) -> PyResult<Self> {
+        let mut table = toml::Table::new();
+        for (key, value) in values {
+            let key: String = key
+                .extract()
+                .map_err(|_| ConfigError::new_err("keys must be strings"))?;
+            let value: String = value
+                .extract()
+                .map_err(|_| ConfigError::new_err(format!("value of {key} must be a string")))?;
+            table.insert(key, toml::Value::String(value));
+        }
+        toml::Value::Table(table)
+            .try_into()
+            .map(Self)
+            .map_err(|e| ConfigError::new_err(e.to_string()))
+    }
+
+    #[staticmethod]
+    fn from_toml(toml: &str) -> PyResult<Self> {
+        parse_memory_config(toml)
+            .map(Self)
+            .map_err(|e| ConfigError::new_err(e.to_string()))
+    }
+
+    fn to_dict<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let dict = PyDict::new(py);
+        for (key, value) in self.table()? {
+            dict.set_item(key, value.as_str())?;
+        }
+        Ok(dict)
+    }
+
+    fn to_toml(&self) -> PyResult<String> {
+        self.0
+            .to_toml_string()
+            .map_err(|e| ConfigError::new_err(e.to_string()))
+    }
+
+    /// XPI NOR configuration option as the BootROM reads it
+    fn to_bytes<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.0.to_bootrom_config())
+    }
+
+    fn __repr__(&self) -> PyResult<String> {
+        let values: Vec<_> = self
+            .table()?
+            .iter()
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect();
+        Ok(format!("MemoryConfig({})", values.join(", ")))
+    }
+}
+
+impl PyMemoryConfig {
+    fn table(&self) -> PyResult<toml::Table> {
+        toml::Table::try_from(self.0).map_err(|e| ConfigError::new_err(e.to_string()))
+    }
+}
+
+/// An attached device in ISP mode, see `list_devices`
+#[pyclass(get_all, module = "hpm_isp")]
+struct DeviceInfo {
+    family: String,
+    serial_number: Option<String>,
+    path: String,
+}
+
+#[pymethods]
+impl DeviceInfo {
+    fn __repr__(&self) -> String {
+        format!(
+            "DeviceInfo(family={:?}, serial_number={:?}, path={:?})",
+            self.family, self.serial_number, self.path
+        )
+    }
+}
+
+#[pyfunction]
+fn list_devices() -> PyResult<Vec<DeviceInfo>> {
+    let devices = HpmDevice::list().map_err(|e| IspError::new_err(e.to_string()))?;
+    Ok(devices
+        .into_iter()
+        .map(|info| DeviceInfo {
+            family: info.family.to_string(),
+            serial_number: info.serial_number,
+            path: info.path,
+        })
+        .collect())
+}
+
+/// Calls the Python progress callback, keeping the exception it raised
+///
+/// Transfers run without the GIL, the callback takes it for each update.
+struct Callback {
+    callback: Option<Py<PyAny>>,
+    error: Option<PyErr>,
+}
+
+impl Callback {
+    fn new(callback: Option<Py<PyAny>>) -> Self {
+        Self {
+            callback,
+            error: None,
+        }
+    }
+
+    /// Called with the phase name, bytes transferred and total bytes, returning
+    /// `False` aborts the operation
+    fn update(&mut self, event: &ProgressEvent) -> ControlFlow<()> {
+        let Some(callback) = &self.callback else {
+            return ControlFlow::Continue(());
+        };
+        Python::with_gil(|py| {
+            match callback.call1(py, (event.phase.as_str(), event.bytes, event.total)) {
+                Ok(result) if matches!(result.extract::<bool>(py), Ok(false)) => {
+                    ControlFlow::Break(())
+                }
+                Ok(_) => ControlFlow::Continue(()),
+                Err(e) => {
+                    self.error = Some(e);
+                    ControlFlow::Break(())
+                }
+            }
+        })
+    }
+
+    /// Error of the operation, the callback's exception if it raised one
+    fn finish<T>(self, result: Result<T, Error>) -> PyResult<T> {
+        match (result, self.error) {
+            (Err(_), Some(e)) => Err(e),
+            (result, _) => result.map_err(isp_error),
+        }
+    }
+}
+
+/// Device in ISP mode, attached or simulated
+#[pyclass(module = "hpm_isp")]
+struct Device {
+    session: Mutex<Session<Transport>>,
+    family: Family,
+}
+
+impl Device {
+    fn new(transport: Transport) -> Self {
+        Self {
+            family: transport.family(),
+            session: Mutex::new(Session::new(transport)),
+        }
+    }
+
+    fn session(&self) -> MutexGuard<'_, Session<Transport>> {
+        // A panic can't leave the session half updated, so poisoning is ignored
+        self.session
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[pymethods]
+impl Device {
+    /// Open an attached device, the first one found without a serial number
+    #[staticmethod]
+    #[pyo3(signature = (serial_number=None))]
+    fn open(serial_number: Option<&str>) -> PyResult<Self> {
+        let device = match serial_number {
+            Some(serial_number) => HpmDevice::open_serial(serial_number),
+            None => HpmDevice::open(),
+        }
+        .map_err(|e| DeviceNotFoundError::new_err(e.to_string()))?;
+        Ok(Self::new(Transport::Hid(device)))
+    }
+
+    /// Open a simulated device of the family, e.g. `"HPM5300"`
+    #[staticmethod]
+    #[pyo3(signature = (family="HPM5300"))]
+    fn simulated(family: &str) -> PyResult<Self> {
+        let family: Family = toml::Value::String(family.to_string())
+            .try_into()
+            .map_err(|_| PyValueError::new_err(format!("unknown family {family}")))?;
+        Ok(Self::new(Transport::Simulated(SimulatedDevice::new(
+            family,
+        ))))
+    }
+
+    #[getter]
+    fn family(&self) -> String {
+        self.family.to_string()
+    }
+
+    /// Configure an XPI instance with a `MemoryConfig` or the raw bytes of an
+    /// XPI NOR configuration option
+    fn configure_memory(
+        &self,
+        py: Python<'_>,
+        memory: &str,
+        config: &Bound<'_, PyAny>,
+    ) -> PyResult<()> {
+        let memory_id = memory_id(memory)?;
+        let result = match config.extract::<PyMemoryConfig>() {
+            Ok(config) => py.allow_threads(|| self.session().configure(memory_id, config.0)),
+            Err(_) => {
+                let config = config
+                    .extract::<Vec<u8>>()
+                    .map_err(|_| PyValueError::new_err("config must be a MemoryConfig or bytes"))?;
+                py.allow_threads(|| self.session().configure_bytes(memory_id, &config))
+            }
+        };
+        result.map_err(isp_error)
+    }
+
+    /// Write `data` at `offset`, which may be an absolute address
+    #[pyo3(signature = (memory, offset, data, progress=None))]
+    fn write(
+        &self,
+        py: Python<'_>,
+        memory: &str,
+        offset: u32,
+        data: &[u8],
+        progress: Option<Py<PyAny>>,
+    ) -> PyResult<()> {
+        let memory_id = memory_id(memory)?;
+        let offset = memory_id.to_offset(offset).map_err(isp_error)?;
+        let mut callback = Callback::new(progress);
+        let result = py.allow_threads(|| {
+            self.session().device().write_memory(
+                memory_id,
+                offset,
+                data,
+                |event: &ProgressEvent| callback.update(event),
+            )
+        });
+        callback.finish(result)
+    }
+
+    /// Read `length` bytes at `offset`, which may be an absolute address
+    #[pyo3(signature = (memory, offset, length, progress=None))]
+    fn read<'py>(
+        &self,
+        py: Python<'py>,
+        memory: &str,
+        offset: u32,
+        length: usize,
+        progress: Option<Py<PyAny>>,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        let memory_id = memory_id(memory)?;
+        let offset = memory_id.to_offset(offset).map_err(isp_error)?;
+        let mut callback = Callback::new(progress);
+        let mut data = vec![0u8; length];
+        let result = py.allow_threads(|| {
+            self.session().device().read_memory(
+                memory_id,
+                offset,
+                &mut data,
+                |event: &ProgressEvent| callback.update(event),
+            )
+        });
+        callback.finish(result)?;
+        Ok(PyBytes::new(py, &data))
+    }
+
+    /// Read back memory at `offset` and compare it with `data`
+    #[pyo3(signature = (memory, offset, data, progress=None))]
+    fn verify(
+        &self,
+        py: Python<'_>,
+        memory: &str,
+        offset: u32,
+        data: &[u8],
+        progress: Option<Py<PyAny>>,
+    ) -> PyResult<()> {
+        let memory_id = memory_id(memory)?;
+        let offset = memory_id.to_offset(offset).map_err(isp_error)?;
+        let mut callback = Callback::new(progress);
+        let result = py.allow_threads(|| {
+            self.session().device().verify_memory(
+                memory_id,
+                offset,
+                data,
+                |event: &ProgressEvent| callback.update(event),
+            )
+        });
+        callback.finish(result)
+    }
+
+    fn __repr__(&self) -> String {
+        let kind = match self.session().device() {
+            Transport::Hid(_) => "usb",
+            Transport::Simulated(_) => "simulated",
+        };
+        format!("Device(family={:?}, {kind})", self.family.to_string())
+    }
+}
+
+#[pymodule]
+#[pyo3(name = "hpm_isp")]
+fn hpm_isp_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    let py = m.py();
+    m.add_class::<Device>()?;
+    m.add_class::<DeviceInfo>()?;
+    m.add_class::<PyMemoryConfig>()?;
+    m.add_function(wrap_pyfunction!(list_devices, m)?)?;
+    m.add("IspError", py.get_type::<IspError>())?;
+    m.add("NakError", py.get_type::<NakError>())?;
+    m.add("TransferError", py.get_type::<TransferError>())?;
+    m.add("IspTimeoutError", py.get_type::<IspTimeoutError>())?;
+    m.add("AbortedError", py.get_type::<AbortedError>())?;
+    m.add("VerifyError", py.get_type::<VerifyError>())?;
+    m.add("DeviceStatusError", py.get_type::<DeviceStatusError>())?;
+    m.add("DeviceNotFoundError", py.get_type::<DeviceNotFoundError>())?;
+    m.add("ConfigError", py.get_type::<ConfigError>())?;
+    Ok(())
+}
@@ -0,0 +1,106 @@
+import pytest
+
+import hpm_isp
+
+XPI0_BASE = 0x8000_0000
+
+
+@pytest.fixture
+def device():
+    return hpm_isp.Device.simulated("HPM5300")
+
+
+@pytest.fixture
+def configured(device):
+    device.configure_memory("XPI0", hpm_isp.MemoryConfig())
+    return device
+
+
+def test_memory_config_from_dict_and_toml():
+    config = hpm_isp.MemoryConfig.from_dict({"pin_group": "group2"})
+
+    assert config == hpm_isp.MemoryConfig(pin_group="group2")
+    assert config.to_dict()["pin_group"] == "group2"
+    assert config.to_dict()["flash_type"] == "sfdp_sdr"
+    assert hpm_isp.MemoryConfig.from_toml(config.to_toml()) == config
+    assert (
+        hpm_isp.MemoryConfig.from_toml('[memory_config]\npin_group = "group2"\n')
+        == config
+    )
+    assert len(config.to_bytes()) == 12
+
+
+def test_memory_config_rejects_invalid_values():
+    with pytest.raises(hpm_isp.ConfigError):
+        hpm_isp.MemoryConfig(pin_group="group3")
+    with pytest.raises(hpm_isp.ConfigError):
+        hpm_isp.MemoryConfig(pin_groups="group2")
+    with pytest.raises(ValueError):
+        hpm_isp.MemoryConfig.from_toml("flash_type = 1")
+
+
+def test_simulated_device(device):
+    assert device.family == "HPM5300"
+    with pytest.raises(ValueError):
+        hpm_isp.Device.simulated("HPM9999")
+
+
+def test_xpi_must_be_configured(device):
+    with pytest.raises(hpm_isp.DeviceStatusError) as error:
+        device.read("XPI0", 0, 16)
+    assert error.value.args[1] == 1
+
+
+def test_configure_with_bytes(device):
+    device.configure_memory("xpi0", hpm_isp.MemoryConfig().to_bytes())
+
+    assert device.read("XPI0", 0, 4) == b"\xff" * 4
+
+
+def test_write_and_read_back(configured):
+    data = bytes(i % 256 for i in range(3000))
+    events = []
+
+    configured.write("XPI0", XPI0_BASE + 0x3000, data, lambda *event: events.append(event))
+
+    assert events[-1] == ("write", 3000, 3000)
+    assert configured.read("XPI0", 0x3000, len(data)) == data
+    configured.verify("XPI0", 0x3000, data)
+
+
+def test_verify_reports_offset(configured):
+    configured.write("XPI0", 0x3000, b"\x00" * 64)
+
+    with pytest.raises(hpm_isp.VerifyError) as error:
+        configured.verify("XPI0", 0x3000, b"\x00" * 10 + b"\x01" + b"\x00" * 53)
+    assert error.value.args[1] == 0x300A
+
+
+def test_progress_can_abort(device):
+    with pytest.raises(hpm_isp.AbortedError):
+        device.write("DLM", 0, b"\xa5" * 2000, lambda *event: False)
+
+
+def test_progress_exception_propagates(device):
+    def progress(phase, done, total):
+        raise KeyError(phase)
+
+    with pytest.raises(KeyError):
+        device.read("ILM", 0, 2000, progress)
+
+
+def test_errors_share_base_class():
+    for error in (
+        hpm_isp.NakError,
+        hpm_isp.TransferError,
+        hpm_isp.IspTimeoutError,
+        hpm_isp.AbortedError,
+        hpm_isp.VerifyError,
+        hpm_isp.DeviceStatusError,
+        hpm_isp.DeviceNotFoundError,
+    ):
+        assert issubclass(error, hpm_isp.IspError)
+
+
+def test_list_devices():
+    assert isinstance(hpm_isp.list_devices(), list)